@@ -9,59 +9,264 @@ use sulu_lib::{
     error::Error,
     edge_list::{
         EdgeList,
-    }
+    },
+    node_list::NodeList,
 };
 #[cfg(feature="formats-gdal")]
-use sulu_lib::edge_list::linestring_to_gdal;
+use sulu_lib::{
+    edge_list::{
+        linestring_to_gdal,
+        linestring_z_to_gdal,
+    },
+    node_list::coordinate_to_gdal,
+};
+
 
+/// Concatenate list values with `;`, as OSM does, for drivers without list fields
+#[cfg(feature="formats-gdal")]
+fn join<T: ToString, I: Iterator<Item=T>>(values: I) -> String {
+    values.map(|v| v.to_string()).collect::<Vec<_>>().join(";")
+}
 
 pub enum Format {
-    GeoJson(std::fs::File),
+    /// The edges, and the nodes if they are written
+    GeoJson(std::fs::File, Option<std::fs::File>),
     #[cfg(feature="formats-gdal")]
     Gdal(gdal::Dataset)
 }
 
 impl Format {
-    pub fn write(self, el: EdgeList<f64>) -> Result<(), Error> {
+    /// Write the edges, and the nodes if given
+    pub fn write(self, el: EdgeList<f64>, nl: Option<NodeList>) -> Result<(), Error> {
         match self {
-            Format::GeoJson(mut file) => {
+            Format::GeoJson(mut file, nodes_file) => {
                 let fc: geojson::FeatureCollection = el.into();
                 let g: geojson::GeoJson = fc.into();
                 file.write_all(&g.to_string().into_bytes())
                     .map_err(Error::IoError)?;
+                if let (Some(mut nodes_file), Some(nl)) = (nodes_file, nl) {
+                    let fc: geojson::FeatureCollection = nl.into();
+                    let g: geojson::GeoJson = fc.into();
+                    nodes_file.write_all(&g.to_string().into_bytes())
+                        .map_err(Error::IoError)?;
+                }
             },
             #[cfg(feature="formats-gdal")]
             Format::Gdal(mut ds) => {
-                let srs = gdal::spatial_ref::SpatialRef::from_epsg(4326)
+                let srs = gdal::spatial_ref::SpatialRef::from_epsg(el.crs.epsg)
                     .map_err(Error::GdalError)?;
+                let z = |edge: &sulu_lib::edge_list::Edge<f64>| edge.elevation.as_ref()
+                    .and_then(|e| e.z.clone())
+                    .filter(|z| z.len() == edge.geometry.0.len());
+                let geometry_type = if el.edges.iter().any(|e| z(e).is_some()) {
+                    gdal::vector::OGRwkbGeometryType::wkbLineString25D
+                } else {
+                    gdal::vector::OGRwkbGeometryType::wkbLineString
+                };
                 let mut layer = ds.create_layer("graph",
                                             Some(&srs),
-                                            gdal::vector::OGRwkbGeometryType::wkbLineString)
+                                            geometry_type)
                     .map_err(Error::GdalError)?;
                 layer.create_defn_fields(&[("way_osmid", gdal::vector::OGRFieldType::OFTInteger64),
                                            ("start_node_id", gdal::vector::OGRFieldType::OFTInteger64),
                                            ("end_node_id", gdal::vector::OGRFieldType::OFTInteger64),
                                            ("graph_config_option", gdal::vector::OGRFieldType::OFTString),
-                                           ("length_m", gdal::vector::OGRFieldType::OFTReal)])
+                                           ("direction", gdal::vector::OGRFieldType::OFTString),
+                                           ("length_m", gdal::vector::OGRFieldType::OFTReal),
+                                           ("way_osmids", gdal::vector::OGRFieldType::OFTString),
+                                           ("osm_version", gdal::vector::OGRFieldType::OFTInteger),
+                                           ("osm_timestamp", gdal::vector::OGRFieldType::OFTString),
+                                           ("osm_changeset", gdal::vector::OGRFieldType::OFTInteger64),
+                                           ("osm_uid", gdal::vector::OGRFieldType::OFTInteger),
+                                           ("osm_user", gdal::vector::OGRFieldType::OFTString),
+                                           ("edge_id", gdal::vector::OGRFieldType::OFTInteger64),
+                                           ("start_vertex_id", gdal::vector::OGRFieldType::OFTInteger64),
+                                           ("end_vertex_id", gdal::vector::OGRFieldType::OFTInteger64),
+                                           ("component", gdal::vector::OGRFieldType::OFTInteger64),
+                                           ("travel_time_forward_s", gdal::vector::OGRFieldType::OFTReal),
+                                           ("travel_time_backward_s", gdal::vector::OGRFieldType::OFTReal),
+                                           ("elevation_start_m", gdal::vector::OGRFieldType::OFTReal),
+                                           ("elevation_end_m", gdal::vector::OGRFieldType::OFTReal),
+                                           ("ascent_m", gdal::vector::OGRFieldType::OFTReal),
+                                           ("descent_m", gdal::vector::OGRFieldType::OFTReal),
+                                           ("avg_grade_forward", gdal::vector::OGRFieldType::OFTReal),
+                                           ("avg_grade_backward", gdal::vector::OGRFieldType::OFTReal),
+                                           ("max_grade_forward", gdal::vector::OGRFieldType::OFTReal),
+                                           ("max_grade_backward", gdal::vector::OGRFieldType::OFTReal),
+                                           ("start_bearing_deg", gdal::vector::OGRFieldType::OFTReal),
+                                           ("end_bearing_deg", gdal::vector::OGRFieldType::OFTReal),
+                                           ("sinuosity", gdal::vector::OGRFieldType::OFTReal),
+                                           ("curvature_deg_per_km", gdal::vector::OGRFieldType::OFTReal)])
                     .map_err(Error::GdalError)?;
+                // area, boundary and route fields are only added to networks that have them
+                if el.edges.iter().any(|e| e.area) {
+                    layer.create_defn_fields(&[("area", gdal::vector::OGRFieldType::OFTInteger)])
+                        .map_err(Error::GdalError)?;
+                }
+                if el.edges.iter().any(|e| e.boundary) {
+                    layer.create_defn_fields(&[("boundary", gdal::vector::OGRFieldType::OFTInteger)])
+                        .map_err(Error::GdalError)?;
+                }
+                if el.edges.iter().any(|e| !e.routes.is_empty()) {
+                    layer.create_defn_fields(&[("route_osmids", gdal::vector::OGRFieldType::OFTString),
+                                               ("route_names", gdal::vector::OGRFieldType::OFTString),
+                                               ("route_refs", gdal::vector::OGRFieldType::OFTString),
+                                               ("route_networks", gdal::vector::OGRFieldType::OFTString),
+                                               ("route_roles", gdal::vector::OGRFieldType::OFTString)])
+                        .map_err(Error::GdalError)?;
+                }
                 for edge in el.edges.iter() {
-                    let geom = linestring_to_gdal(&edge.geometry)?;
-                    let field_names = ["way_osmid", 
+                    let geom = match z(edge) {
+                        Some(z) => linestring_z_to_gdal(&edge.geometry, &z)?,
+                        None => linestring_to_gdal(&edge.geometry)?
+                    };
+                    let mut field_names = vec!["way_osmid", 
                                        "start_node_id", 
                                        "end_node_id", 
                                        "graph_config_option", 
+                                       "direction",
                                        "length_m"];
-                    let field_values = [FieldValue::Integer64Value(edge.way_osmid.0),
+                    let mut field_values = vec![FieldValue::Integer64Value(edge.way_osmid.0),
                                         FieldValue::Integer64Value(edge.start_node_id.0),
                                         FieldValue::Integer64Value(edge.end_node_id.0),
                                         FieldValue::StringValue(edge.graph_config_option.name.clone()),
+                                        FieldValue::StringValue(edge.direction.to_string()),
                                         FieldValue::RealValue(edge.length_m)];
+                    // fields without a value are left null
+                    if edge.area {
+                        field_names.push("area");
+                        field_values.push(FieldValue::IntegerValue(1));
+                    }
+                    if edge.boundary {
+                        field_names.push("boundary");
+                        field_values.push(FieldValue::IntegerValue(1));
+                    }
+                    if !edge.routes.is_empty() {
+                        field_names.extend(&["route_osmids", "route_names", "route_refs", "route_networks", "route_roles"]);
+                        field_values.extend(vec![
+                            FieldValue::StringValue(join(edge.routes.iter().map(|r| r.relation_osmid.0))),
+                            FieldValue::StringValue(join(edge.routes.iter().map(|r| r.name.clone().unwrap_or_default()))),
+                            FieldValue::StringValue(join(edge.routes.iter().map(|r| r.route_ref.clone().unwrap_or_default()))),
+                            FieldValue::StringValue(join(edge.routes.iter().map(|r| r.network.clone().unwrap_or_default()))),
+                            FieldValue::StringValue(join(edge.routes.iter().map(|r| r.role.clone())))]);
+                    }
+                    if !edge.merged_way_osmids.is_empty() {
+                        field_names.push("way_osmids");
+                        field_values.push(FieldValue::StringValue(join(
+                            std::iter::once(edge.way_osmid.0).chain(edge.merged_way_osmids.iter().map(|w| w.0)))));
+                    }
+                    if let Some(dense_ids) = edge.dense_ids {
+                        field_names.extend(&["edge_id", "start_vertex_id", "end_vertex_id"]);
+                        field_values.extend(vec![FieldValue::Integer64Value(dense_ids.edge_id as i64),
+                                                 FieldValue::Integer64Value(dense_ids.start_vertex_id as i64),
+                                                 FieldValue::Integer64Value(dense_ids.end_vertex_id as i64)]);
+                    }
+                    if let Some(component) = edge.component {
+                        field_names.push("component");
+                        field_values.push(FieldValue::Integer64Value(component as i64));
+                    }
+                    if let Some(forward_s) = edge.travel_time.and_then(|t| t.forward_s) {
+                        field_names.push("travel_time_forward_s");
+                        field_values.push(FieldValue::RealValue(forward_s));
+                    }
+                    if let Some(backward_s) = edge.travel_time.and_then(|t| t.backward_s) {
+                        field_names.push("travel_time_backward_s");
+                        field_values.push(FieldValue::RealValue(backward_s));
+                    }
+                    if let Some(elevation) = &edge.elevation {
+                        field_names.extend(&["elevation_start_m", "elevation_end_m", "ascent_m", "descent_m",
+                                             "avg_grade_forward", "avg_grade_backward",
+                                             "max_grade_forward", "max_grade_backward"]);
+                        field_values.extend(vec![FieldValue::RealValue(elevation.start_m),
+                                                 FieldValue::RealValue(elevation.end_m),
+                                                 FieldValue::RealValue(elevation.ascent_m),
+                                                 FieldValue::RealValue(elevation.descent_m),
+                                                 FieldValue::RealValue(elevation.avg_grade),
+                                                 FieldValue::RealValue(-elevation.avg_grade),
+                                                 FieldValue::RealValue(elevation.max_grade_forward),
+                                                 FieldValue::RealValue(elevation.max_grade_backward)]);
+                    }
+                    if let Some(measures) = edge.measures {
+                        let measures = [("start_bearing_deg", measures.start_bearing_deg),
+                                        ("end_bearing_deg", measures.end_bearing_deg),
+                                        ("sinuosity", measures.sinuosity),
+                                        ("curvature_deg_per_km", measures.curvature_deg_per_km)];
+                        for (name, value) in measures.iter() {
+                            if let Some(value) = value {
+                                field_names.push(name);
+                                field_values.push(FieldValue::RealValue(*value));
+                            }
+                        }
+                    }
+                    if let Some(info) = &edge.info {
+                        field_names.push("osm_version");
+                        field_values.push(FieldValue::IntegerValue(info.version));
+                        if let Some(timestamp) = info.timestamp_string() {
+                            field_names.push("osm_timestamp");
+                            field_values.push(FieldValue::StringValue(timestamp));
+                        }
+                        if let Some(changeset) = info.changeset {
+                            field_names.push("osm_changeset");
+                            field_values.push(FieldValue::Integer64Value(changeset));
+                        }
+                        if let Some(uid) = info.uid {
+                            field_names.push("osm_uid");
+                            field_values.push(FieldValue::IntegerValue(uid));
+                        }
+                        if let Some(user) = &info.user {
+                            field_names.push("osm_user");
+                            field_values.push(FieldValue::StringValue(user.clone()));
+                        }
+                    }
                     layer.create_feature_fields(
                         geom,
                         &field_names,
                         &field_values)
                     .map_err(Error::GdalError)?;
                 }
+                if let Some(nl) = nl {
+                    let mut layer = ds.create_layer("nodes",
+                                                Some(&srs),
+                                                gdal::vector::OGRwkbGeometryType::wkbPoint)
+                        .map_err(Error::GdalError)?;
+                    layer.create_defn_fields(&[("vertex_id", gdal::vector::OGRFieldType::OFTInteger64),
+                                               ("node_id", gdal::vector::OGRFieldType::OFTInteger64),
+                                               ("degree", gdal::vector::OGRFieldType::OFTInteger),
+                                               ("in_degree", gdal::vector::OGRFieldType::OFTInteger),
+                                               ("out_degree", gdal::vector::OGRFieldType::OFTInteger),
+                                               ("component", gdal::vector::OGRFieldType::OFTInteger64),
+                                               ("tags", gdal::vector::OGRFieldType::OFTString)])
+                        .map_err(Error::GdalError)?;
+                    for vertex in nl.nodes.iter() {
+                        let geom = coordinate_to_gdal(&vertex.coordinate)?;
+                        let mut field_names = vec!["node_id", "degree", "in_degree", "out_degree"];
+                        let mut field_values = vec![FieldValue::Integer64Value(vertex.id.0),
+                                                    FieldValue::IntegerValue(vertex.degree as i32),
+                                                    FieldValue::IntegerValue(vertex.in_degree as i32),
+                                                    FieldValue::IntegerValue(vertex.out_degree as i32)];
+                        if let Some(vertex_id) = vertex.vertex_id {
+                            field_names.push("vertex_id");
+                            field_values.push(FieldValue::Integer64Value(vertex_id as i64));
+                        }
+                        if let Some(component) = vertex.component {
+                            field_names.push("component");
+                            field_values.push(FieldValue::Integer64Value(component as i64));
+                        }
+                        // tags are written as a json object
+                        if let Some(tags) = &vertex.tags {
+                            let tags: serde_json::Map<String, serde_json::Value> = tags.iter()
+                                .map(|(k, v)| (k.to_string(), v.to_string().into()))
+                                .collect();
+                            field_names.push("tags");
+                            field_values.push(FieldValue::StringValue(serde_json::Value::Object(tags).to_string()));
+                        }
+                        layer.create_feature_fields(
+                            geom,
+                            &field_names,
+                            &field_values)
+                        .map_err(Error::GdalError)?;
+                    }
+                }
             }
         }
         Ok(())
@@ -1,26 +1,94 @@
 pub mod formats;
+pub mod progress_bar;
+#[cfg(feature="formats-geojson")]
+pub mod route;
 
-use std::convert::TryInto;
 use clap::{
     Arg,
-    App
+    App,
 };
+#[cfg(feature="formats-geojson")]
+use clap::AppSettings;
 use serde_json::from_reader;
-use crate::formats::Format;
+use crate::{
+    formats::Format,
+    progress_bar::ProgressBar,
+};
 use sulu_lib::{
-    graph_config::GraphConfig,
-    osm_cache::OSMCache,
-    edge_list::EdgeList,
+    graph_config::{
+        GraphConfig,
+        SplitAt,
+    },
+    osm_cache::{
+        OSMCache,
+        InputFormat,
+    },
+    edge_list::{
+        EdgeList,
+        EdgeChanges,
+    },
+    osm_change::apply_change,
+    osm_info::parse_timestamp,
+    node_list::NodeList,
+    components::Connectivity,
+    simplify::SimplifyMethod,
+    crs::Crs,
+    diagnostics::IntegrityReport,
+    node_store::{
+        NodeStore,
+        MemoryNodeStore,
+        FileNodeStore,
+    },
+    snapshot::source_hash,
 };
+#[cfg(feature="formats-gdal")]
+use sulu_lib::elevation::Raster;
 
 
-fn main() {
+/// Keep node locations in a sparse file at `path`, or in memory
+fn node_store(path: Option<&str>) -> Box<dyn NodeStore> {
+    match path {
+        Some(path) => Box::new(FileNodeStore::create(path).unwrap()),
+        None => Box::new(MemoryNodeStore::default())
+    }
+}
+
+/// `network.geojson` becomes `network.nodes.geojson`
+fn nodes_path(output_path: &str) -> std::path::PathBuf {
+    let path = std::path::Path::new(output_path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.nodes.{}", stem, ext.to_string_lossy()),
+        None => format!("{}.nodes", stem)
+    };
+    path.with_file_name(name)
+}
+
+fn load_osm_cache(
+    graph_config: GraphConfig,
+    input_paths: &[&str],
+    input_format: Option<InputFormat>,
+    node_store_path: Option<&str>,
+    progress_bar: &mut ProgressBar
+) -> OSMCache {
+    let mut osm_cache = OSMCache::with_node_store(graph_config, node_store(node_store_path));
+    for path in input_paths {
+        let format = input_format.unwrap_or_else(|| InputFormat::from_path(path));
+        progress_bar.set_input(path);
+        osm_cache.load_from_path_with_observer(path, format, progress_bar).unwrap();
+    }
+    progress_bar.finish();
+    osm_cache
+}
+
+
+fn app() -> App<'static, 'static> {
     let app = App::new("Sulu")
         .version("0.2.0")
         .author("Tom Watson <tom.watson@kinesis.org>")
         .about("Converts osm.pbf files into routable networks")
         .arg(Arg::with_name("INPUT")
-             .help("The osm.pbf file to process")
+             .help("The osm.pbf or .osm (optionally .gz or .bz2 compressed) file to process")
              .required(true)
              .index(1))
         .arg(Arg::with_name("OUTPUT")
@@ -30,7 +98,125 @@ fn main() {
         .arg(Arg::with_name("GRAPH-CONFIG")
              .required(true)
              .help("File containing the definition of the graph")
-             .index(3));
+             .index(3))
+        .arg(Arg::with_name("merge")
+             .long("merge")
+             .short("m")
+             .help("Additional input files to merge with INPUT, e.g. adjacent tiles")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("cache")
+             .long("cache")
+             .short("c")
+             .help("Reuse the filtered osm data in this file if the inputs and graph config are unchanged, otherwise save it there")
+             .takes_value(true))
+        .arg(Arg::with_name("node-store")
+             .long("node-store")
+             .help("Keep node locations in this (sparse, memory mapped) file rather than in memory, for very large networks")
+             .takes_value(true))
+        .arg(Arg::with_name("input-format")
+             .long("input-format")
+             .help("The format of the input files, otherwise guessed from the file extension")
+             .takes_value(true)
+             .possible_values(&["pbf", "xml"]))
+        .arg(Arg::with_name("apply-change")
+             .long("apply-change")
+             .help("OsmChange (.osc) files to apply, in order, after loading the inputs. With --cache the updated data is saved")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("change-report")
+             .long("change-report")
+             .help("Write the edges added, removed and modified by --apply-change to this json file")
+             .takes_value(true)
+             .requires("apply-change"))
+        .arg(Arg::with_name("split-all-ways")
+             .long("split-all-ways")
+             .help("Split edges wherever any way in the input shares a node with them, not just the ways in the graph"))
+        .arg(Arg::with_name("split-config")
+             .long("split-config")
+             .help("Split edges wherever a way matching the options in this graph config shares a node with them")
+             .takes_value(true)
+             .conflicts_with("split-all-ways"))
+        .arg(Arg::with_name("newer-than")
+             .long("newer-than")
+             .help("Only output edges whose way was last edited after this UTC date or timestamp, e.g. 2020-01-01")
+             .takes_value(true))
+        .arg(Arg::with_name("older-than")
+             .long("older-than")
+             .help("Only output edges whose way was last edited before this UTC date or timestamp, e.g. 2015-01-01T00:00:00Z")
+             .takes_value(true))
+        .arg(Arg::with_name("quiet")
+             .long("quiet")
+             .short("q")
+             .help("Don't show progress or summaries"))
+        .arg(Arg::with_name("nodes")
+             .long("nodes")
+             .help("Also write the nodes, with their degree, as a second layer (gdal) or a second file ending in .nodes.geojson (geojson)"))
+        .arg(Arg::with_name("node-tags")
+             .long("node-tags")
+             .help("Include the tags of each node in the nodes output")
+             .requires("nodes"))
+        .arg(Arg::with_name("dense-ids")
+             .long("dense-ids")
+             .help("Number the edges and vertices 0..n, in order of OSM id, in edge_id, start_vertex_id, end_vertex_id and vertex_id fields"))
+        .arg(Arg::with_name("parallel-report")
+             .long("parallel-report")
+             .help("Write a json report of duplicate edges and edges joining the same pair of nodes to this file")
+             .takes_value(true))
+        .arg(Arg::with_name("drop-duplicate-edges")
+             .long("drop-duplicate-edges")
+             .help("Drop edges with the same geometry as another edge, e.g. from overlapping ways"))
+        .arg(Arg::with_name("split-parallel-edges")
+             .long("split-parallel-edges")
+             .help("Split edges joining the same pair of nodes as another edge at a synthetic node, so the graph is simple"))
+        .arg(Arg::with_name("max-edge-length")
+             .long("max-edge-length")
+             .help("Split edges longer than this many metres into equal pieces at synthetic nodes")
+             .takes_value(true))
+        .arg(Arg::with_name("densify")
+             .long("densify")
+             .help("Add points to edge geometries so no segment is longer than this many metres")
+             .takes_value(true))
+        .arg(Arg::with_name("simplify")
+             .long("simplify")
+             .help("Simplify edge geometries with this tolerance in metres, keeping their end points and length_m")
+             .takes_value(true))
+        .arg(Arg::with_name("simplify-method")
+             .long("simplify-method")
+             .help("The simplification algorithm, douglas-peucker unless given. For visvalingam, points are dropped if their triangle's area is under the tolerance squared")
+             .takes_value(true)
+             .possible_values(&["douglas-peucker", "visvalingam"])
+             .requires("simplify"))
+        .arg(Arg::with_name("components")
+             .long("components")
+             .help("Label edges and nodes with their connected component in a component field, 0 being the longest")
+             .takes_value(true)
+             .possible_values(&["weak", "strong"]))
+        .arg(Arg::with_name("largest-component")
+             .long("largest-component")
+             .help("Only output the longest connected component (weak unless --components strong)"))
+        .arg(Arg::with_name("min-component-length")
+             .long("min-component-length")
+             .help("Drop connected components whose edges total less than this many metres (weak unless --components strong)")
+             .takes_value(true))
+        .arg(Arg::with_name("movements")
+             .long("movements")
+             .help("Write a csv table of the movements between edges, with turn angles and restrictions, to this file")
+             .takes_value(true))
+        .arg(Arg::with_name("crs")
+             .long("crs")
+             .help("Write geometries in this coordinate system: an EPSG code (4326, 3857, UTM 326xx/327xx, MGA 283xx/78xx), or utm for the UTM zone at the centre of the network")
+             .takes_value(true))
+        .arg(Arg::with_name("diagnostics")
+             .long("diagnostics")
+             .help("Write a json report of ways that reference nodes missing from the inputs to this file")
+             .takes_value(true));
+
+    #[cfg(feature="formats-geojson")]
+    let app = app.setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(route::subcommand());
 
     #[cfg(feature="formats-gdal")]
     let app = app.clone().arg(Arg::with_name("gdal-driver")
@@ -38,9 +224,30 @@ fn main() {
                 .short("d")
                 .help("Use gdal to output file with a specific driver")
                 .takes_value(true)
-                .conflicts_with("geojson"));
+                .conflicts_with("geojson"))
+            .arg(Arg::with_name("dem")
+                .long("dem")
+                .help("Sample elevations along edges from this DEM (e.g. a GeoTIFF), adding elevations, ascent, descent and grades")
+                .takes_value(true))
+            .arg(Arg::with_name("dem-spacing")
+                .long("dem-spacing")
+                .help("Sample the DEM every this many metres along edges, as well as at their points, 10 unless given")
+                .takes_value(true)
+                .requires("dem"))
+            .arg(Arg::with_name("elevation-z")
+                .long("elevation-z")
+                .help("Write 3D edge geometries, with the DEM elevation of each point")
+                .requires("dem"));
+    app
+}
 
-    let matches = app.get_matches();
+fn main() {
+    let matches = app().get_matches();
+    #[cfg(feature="formats-geojson")]
+    if let Some(route_matches) = matches.subcommand_matches("route") {
+        route::run(route_matches);
+        return
+    }
 
     let graph_config_path = matches.value_of("GRAPH-CONFIG")
         .expect("No value for GRAPH-CONFIG");
@@ -48,12 +255,170 @@ fn main() {
     let input_file_path = matches.value_of("INPUT")
         .expect("No value for INPUT");
 
-    let graph_config: GraphConfig = from_reader(graph_config_file).unwrap();
+    let mut graph_config: GraphConfig = from_reader(graph_config_file).unwrap();
+    if matches.is_present("split-all-ways") {
+        graph_config.split_at = SplitAt::AllWays;
+    } else if let Some(split_config_path) = matches.value_of("split-config") {
+        let split_config_file = std::fs::File::open(split_config_path).unwrap();
+        let split_config: GraphConfig = from_reader(split_config_file).unwrap();
+        graph_config.split_at = SplitAt::Matching(split_config.options);
+    }
+    // restriction relations are only cached when they're needed
+    if matches.is_present("movements") {
+        graph_config.turn_restrictions = true;
+    }
+
+    let mut input_paths = vec![input_file_path];
+    if let Some(merge_paths) = matches.values_of("merge") {
+        input_paths.extend(merge_paths);
+    }
+
+    let input_format: Option<InputFormat> = matches.value_of("input-format")
+        .map(|f| f.parse().unwrap());
+
+    let quiet = matches.is_present("quiet");
+    let mut progress_bar = ProgressBar::new(quiet);
+
+    let cache_path = matches.value_of("cache");
+    let node_store_path = matches.value_of("node-store");
+    let source_hash = cache_path.map(|_| source_hash(&input_paths).unwrap());
+
+    let mut osm_cache = match (cache_path, source_hash) {
+        (Some(cache_path), Some(source_hash)) => {
+            let loaded = OSMCache::load_snapshot_from_path_with_node_store(
+                cache_path, &graph_config, source_hash, node_store(node_store_path));
+            match loaded {
+                Ok(osm_cache) => osm_cache,
+                Err(_) => {
+                    let osm_cache = load_osm_cache(graph_config, &input_paths, input_format, node_store_path, &mut progress_bar);
+                    osm_cache.save_snapshot_to_path(cache_path, source_hash).unwrap();
+                    osm_cache
+                }
+            }
+        },
+        _ => load_osm_cache(graph_config, &input_paths, input_format, node_store_path, &mut progress_bar)
+    };
+
+    let mut edge_list = EdgeList::from_cache_with_observer(&osm_cache, &mut progress_bar).unwrap();
+    progress_bar.finish();
+
+    if let Some(change_paths) = matches.values_of("apply-change") {
+        let mut report = EdgeChanges::default();
+        for change_path in change_paths {
+            let change_file = std::fs::File::open(change_path).unwrap();
+            let changes = apply_change(&mut osm_cache, &mut edge_list, change_file).unwrap();
+            report.merge(changes);
+        }
+        if !quiet {
+            eprintln!("Applied changes: {} edges added, {} removed, {} modified",
+                      report.added.len(), report.removed.len(), report.modified.len());
+        }
+        if !report.incomplete_ways.is_empty() {
+            eprintln!("Warning: {} changed ways reference nodes in neither the cache nor the changes",
+                      report.incomplete_ways.len());
+        }
+        if let (Some(cache_path), Some(source_hash)) = (cache_path, source_hash) {
+            osm_cache.save_snapshot_to_path(cache_path, source_hash).unwrap();
+        }
+        if let Some(report_path) = matches.value_of("change-report") {
+            let report_file = std::fs::File::create(report_path).unwrap();
+            serde_json::to_writer(report_file, &report).unwrap();
+        }
+    }
+
+    let integrity_report = IntegrityReport::from_cache(&osm_cache);
+    if !integrity_report.is_empty() {
+        eprintln!("Warning: {}", integrity_report);
+    }
+    if let Some(diagnostics_path) = matches.value_of("diagnostics") {
+        let diagnostics_file = std::fs::File::create(diagnostics_path).unwrap();
+        serde_json::to_writer(diagnostics_file, &integrity_report).unwrap();
+    }
+
+    let newer_than = matches.value_of("newer-than")
+        .map(|t| parse_timestamp(t).unwrap());
+    let older_than = matches.value_of("older-than")
+        .map(|t| parse_timestamp(t).unwrap());
+    edge_list.retain_by_timestamp(newer_than, older_than);
+
+    if let Some(merge_chains) = &osm_cache.graph_config.merge_chains {
+        edge_list.merge_chains(&osm_cache, merge_chains);
+    }
+
+    if let Some(report_path) = matches.value_of("parallel-report") {
+        let report_file = std::fs::File::create(report_path).unwrap();
+        serde_json::to_writer(report_file, &edge_list.parallel_edges()).unwrap();
+    }
+    if matches.is_present("drop-duplicate-edges") {
+        edge_list.drop_duplicate_edges();
+    }
+    if matches.is_present("split-parallel-edges") {
+        edge_list.split_parallel_edges();
+    }
+
+    if let Some(max_length) = matches.value_of("max-edge-length") {
+        edge_list.split_long_edges(max_length.parse().unwrap());
+    }
+    if let Some(max_segment) = matches.value_of("densify") {
+        edge_list.densify(max_segment.parse().unwrap());
+    }
+
+    if let Some(tolerance) = matches.value_of("simplify") {
+        let method: SimplifyMethod = matches.value_of("simplify-method")
+            .unwrap_or("douglas-peucker")
+            .parse()
+            .unwrap();
+        edge_list.simplify(method, tolerance.parse().unwrap());
+    }
+
+    #[cfg(feature="formats-gdal")]
+    {
+        if let Some(dem_path) = matches.value_of("dem") {
+            let dem = Raster::from_gdal(dem_path).unwrap();
+            let spacing: f64 = matches.value_of("dem-spacing").unwrap_or("10").parse().unwrap();
+            edge_list.sample_elevation(&dem, spacing, matches.is_present("elevation-z"));
+        }
+    }
+
+    edge_list.measure_geometry(&osm_cache.graph_config.geometry_measures);
+
+    let connectivity: Connectivity = matches.value_of("components")
+        .unwrap_or("weak")
+        .parse()
+        .unwrap();
+    if matches.is_present("largest-component") {
+        edge_list.keep_largest_component(connectivity);
+    }
+    if let Some(min_length) = matches.value_of("min-component-length") {
+        edge_list.drop_short_components(connectivity, min_length.parse().unwrap());
+    }
+    if matches.is_present("components") {
+        edge_list.label_components(connectivity);
+    }
 
-    let mut osm_cache = OSMCache::new(graph_config);
-    osm_cache.load_from_path(input_file_path).unwrap();
+    if matches.is_present("dense-ids") {
+        edge_list.reindex();
+    }
 
-    let edge_list: EdgeList<f64> = osm_cache.try_into().unwrap();
+    if let Some(movements_path) = matches.value_of("movements") {
+        let movements_file = std::fs::File::create(movements_path).unwrap();
+        let movements = edge_list.movements(&osm_cache);
+        edge_list.write_movements(&movements, std::io::BufWriter::new(movements_file)).unwrap();
+    }
+
+    if let Some(crs) = matches.value_of("crs") {
+        let crs: Crs = match crs {
+            "utm" => edge_list.utm_crs(),
+            code => code.parse().unwrap()
+        };
+        edge_list.reproject(&crs).unwrap();
+    }
+
+    let node_list = if matches.is_present("nodes") {
+        Some(NodeList::from_edges(&edge_list, &osm_cache, matches.is_present("node-tags")))
+    } else {
+        None
+    };
 
     match matches.value_of("gdal-driver") {
         #[cfg(feature="formats-gdal")]
@@ -67,18 +432,40 @@ fn main() {
                 .expect("Not a valid path");
             let dataset = driver.create_vector_only(output_path).unwrap();
             let format = Format::Gdal(dataset);
-            format.write(edge_list).unwrap();
+            format.write(edge_list, node_list).unwrap();
         },
         _ => {
             let output_path = matches.value_of("OUTPUT")
                 .expect("No value for OUTPUT");
-            let file = std::fs::OpenOptions::new()
+            let create = |path: &std::path::Path| std::fs::OpenOptions::new()
                 .write(true)
                 .create_new(true)
-                .open(output_path)
+                .open(path)
                 .unwrap();
-            let format = Format::GeoJson(file);
-            format.write(edge_list).unwrap();
+            let file = create(std::path::Path::new(output_path));
+            let nodes_file = node_list.as_ref()
+                .map(|_| create(&nodes_path(output_path)));
+            let format = Format::GeoJson(file, nodes_file);
+            format.write(edge_list, node_list).unwrap();
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let args = ["sulu", "in.osm.pbf", "out.geojson", "graph.json"];
+        let matches = app().get_matches_from_safe(args.iter()).unwrap();
+        assert_eq!(matches.value_of("INPUT"), Some("in.osm.pbf"));
+        assert!(!matches.is_present("dem-spacing"));
+
+        #[cfg(feature="formats-gdal")]
+        {
+            let with_spacing = args.iter().chain(["--dem-spacing", "5"].iter());
+            assert!(app().get_matches_from_safe(with_spacing).is_err());
+        }
+    }
+}
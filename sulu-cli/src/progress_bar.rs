@@ -0,0 +1,92 @@
+use std::{
+    io::{
+        IsTerminal,
+        Write,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use sulu_lib::progress::{
+    Observer,
+    Phase,
+    Progress,
+};
+
+const WIDTH: usize = 30;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Draws the progress of a build on stderr, when stderr is a terminal
+pub struct ProgressBar {
+    enabled: bool,
+    /// The size of the input being read, to show how far through it we are
+    total_bytes: Option<u64>,
+    /// The latest update, which may not have been drawn yet
+    last: Option<Progress>,
+    last_draw: Option<Instant>
+}
+
+impl ProgressBar {
+    pub fn new(quiet: bool) -> Self {
+        ProgressBar {
+            enabled: !quiet && std::io::stderr().is_terminal(),
+            total_bytes: None,
+            last: None,
+            last_draw: None
+        }
+    }
+
+    /// Start reading a new input file
+    pub fn set_input(&mut self, path: &str) {
+        self.finish();
+        self.total_bytes = std::fs::metadata(path).ok().map(|m| m.len());
+    }
+
+    /// Draw the latest update and end the line
+    pub fn finish(&mut self) {
+        if let Some(progress) = self.last.take() {
+            self.draw(&progress);
+            eprintln!();
+        }
+        self.last_draw = None;
+    }
+
+    fn draw(&self, progress: &Progress) {
+        let fraction = match (progress.phase, progress.total_ways, self.total_bytes) {
+            (Phase::Edges, Some(total), _) if total > 0 => progress.ways as f64 / total as f64,
+            (Phase::Edges, _, _) => 1.0,
+            (_, _, Some(total)) if total > 0 => progress.bytes_read as f64 / total as f64,
+            _ => 0.0
+        }.min(1.0);
+        let filled = (fraction * WIDTH as f64).round() as usize;
+        let detail = match progress.phase {
+            _ if progress.phase == Phase::Edges || progress.blobs == 0 => format!("{} ways", progress.ways),
+            _ => format!("{} blobs, {} ways", progress.blobs, progress.ways)
+        };
+        eprint!("\r{:<20} [{}{}] {:>3}% {}\x1b[K",
+                progress.phase.to_string(),
+                "#".repeat(filled),
+                " ".repeat(WIDTH - filled),
+                (fraction * 100.0).round(),
+                detail);
+        let _ = std::io::stderr().flush();
+    }
+}
+
+impl Observer for ProgressBar {
+    fn progress(&mut self, progress: &Progress) -> bool {
+        if !self.enabled {
+            return true
+        }
+        if self.last.as_ref().is_some_and(|p| p.phase != progress.phase) {
+            self.finish();
+        }
+        if self.last_draw.is_none_or(|t| t.elapsed() >= REDRAW_INTERVAL) {
+            self.draw(progress);
+            self.last_draw = Some(Instant::now());
+        }
+        self.last = Some(progress.clone());
+        true
+    }
+}
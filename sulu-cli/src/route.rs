@@ -0,0 +1,157 @@
+use std::convert::TryFrom;
+use clap::{
+    App,
+    Arg,
+    ArgMatches,
+    SubCommand,
+};
+use serde_json::{json, Map, Value};
+use sulu_lib::{
+    crs::Crs,
+    edge_list::EdgeList,
+    routing::{
+        Algorithm,
+        Graph,
+    },
+};
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("route")
+        .about("Finds the shortest path through a network written as GeoJSON by sulu, to check it routes")
+        .arg(Arg::with_name("NETWORK")
+             .help("The network, a GeoJSON file of edges in longitude and latitude")
+             .required(true)
+             .index(1))
+        .arg(Arg::with_name("FROM")
+             .help("Where to start, as lon,lat, snapped to the nearest edge")
+             .required(true)
+             .allow_hyphen_values(true)
+             .index(2))
+        .arg(Arg::with_name("TO")
+             .help("Where to end, as lon,lat, snapped to the nearest edge")
+             .required(true)
+             .allow_hyphen_values(true)
+             .index(3))
+        .arg(Arg::with_name("weight")
+             .long("weight")
+             .help("The numeric edge field to minimise, where null means the edge can't be travelled")
+             .takes_value(true)
+             .default_value("length_m"))
+        .arg(Arg::with_name("weight-backward")
+             .long("weight-backward")
+             .help("The field to use travelling edges from their end node to their start node, e.g. travel_time_backward_s, otherwise --weight")
+             .takes_value(true))
+        .arg(Arg::with_name("algorithm")
+             .long("algorithm")
+             .help("The search algorithm")
+             .takes_value(true)
+             .possible_values(&["astar", "dijkstra"])
+             .default_value("astar"))
+        .arg(Arg::with_name("output")
+             .long("output")
+             .short("o")
+             .help("Write the path to this file rather than to stdout")
+             .takes_value(true))
+}
+
+/// Print an error and exit, as for any other bad input
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+/// Parse `lon,lat`
+fn lon_lat(value: &str) -> Option<(f64, f64)> {
+    let parts: Vec<f64> = value.split(',')
+        .map(|p| p.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    match parts.as_slice() {
+        [lon, lat] => Some((*lon, *lat)),
+        _ => None
+    }
+}
+
+pub fn run(matches: &ArgMatches) {
+    let network_path = matches.value_of("NETWORK")
+        .expect("No value for NETWORK");
+    let network = std::fs::read_to_string(network_path)
+        .unwrap_or_else(|e| fail(format!("Can't read {}: {}", network_path, e)));
+    let fc = match network.parse::<geojson::GeoJson>() {
+        Ok(geojson::GeoJson::FeatureCollection(fc)) => fc,
+        Ok(_) => fail(format!("{} isn't a GeoJSON FeatureCollection", network_path)),
+        Err(e) => fail(format!("{} isn't valid GeoJSON: {}", network_path, e))
+    };
+
+    // the weights are read before the features become edges
+    let weight = matches.value_of("weight").unwrap();
+    let weight_backward = matches.value_of("weight-backward").unwrap_or(weight);
+    let field = |props: Option<&Map<String, Value>>, name: &str| props.and_then(|p| p.get(name)).and_then(|v| v.as_f64());
+    let weights: Vec<(Option<f64>, Option<f64>)> = fc.features.iter()
+        .map(|f| (field(f.properties.as_ref(), weight), field(f.properties.as_ref(), weight_backward)))
+        .collect();
+    if !weights.iter().any(|(forward, backward)| forward.is_some() || backward.is_some()) {
+        fail(format!("No edge has a numeric {} field", weight));
+    }
+
+    let edge_list = EdgeList::try_from(fc)
+        .unwrap_or_else(|e| fail(format!("{} isn't a sulu network: {}", network_path, e)));
+    if edge_list.crs != Crs::default() {
+        fail(format!("The network must be in longitude and latitude, not EPSG:{}", edge_list.crs.epsg));
+    }
+    let graph = Graph::new(&edge_list, |i, _, forward| if forward { weights[i].0 } else { weights[i].1 });
+    let algorithm: Algorithm = matches.value_of("algorithm").unwrap().parse().unwrap();
+
+    let snap = |name: &str| {
+        let value = matches.value_of(name).unwrap();
+        let (lon, lat) = lon_lat(value)
+            .unwrap_or_else(|| fail(format!("{} is not a lon,lat: {}", name, value)));
+        edge_list.snap(lon, lat)
+            .unwrap_or_else(|| fail("The network has no edges".to_string()))
+    };
+    let (from, to) = (snap("FROM"), snap("TO"));
+    let route = match graph.route(&from, &to, algorithm) {
+        Some(route) => route,
+        None => fail("No route found".to_string())
+    };
+
+    let mut props: Map<String, Value> = Map::new();
+    props.insert("cost".to_string(), json!(route.cost));
+    props.insert("weight".to_string(), json!(weight));
+    props.insert("length_m".to_string(), json!(route.length_m(&edge_list)));
+    props.insert("from_snap_distance_m".to_string(), json!(from.distance_m));
+    props.insert("to_snap_distance_m".to_string(), json!(to.distance_m));
+    props.insert("way_osmids".to_string(),
+                 json!(route.steps.iter().map(|s| edge_list.edges[s.edge].way_osmid.0).collect::<Vec<_>>()));
+    let feature = geojson::Feature {
+        bbox: None,
+        geometry: Some(geojson::Geometry::new((&route.geometry(&edge_list)).into())),
+        id: None,
+        properties: Some(props),
+        foreign_members: None
+    };
+    let fc = geojson::FeatureCollection {
+        bbox: None,
+        features: vec![feature],
+        foreign_members: None
+    };
+    let output = geojson::GeoJson::from(fc).to_string();
+    match matches.value_of("output") {
+        Some(path) => std::fs::write(path, output)
+            .unwrap_or_else(|e| fail(format!("Can't write {}: {}", path, e))),
+        None => println!("{}", output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lon_lat() {
+        assert_eq!(lon_lat("151.21,-33.86"), Some((151.21, -33.86)));
+        assert_eq!(lon_lat("151.21, -33.86"), Some((151.21, -33.86)));
+        assert_eq!(lon_lat("151.21"), None);
+        assert_eq!(lon_lat("151.21,-33.86,4"), None);
+        assert_eq!(lon_lat("east,-33.86"), None);
+    }
+}
@@ -0,0 +1,408 @@
+//! Walkable edges across areas such as pedestrian plazas
+//!
+//! An area is a closed way, or a multipolygon relation, matching one of the
+//! graph config's `areas` options. Its entry points are the outline nodes
+//! shared with other cached ways. Edges are built between every pair of
+//! entry points that can see each other across the area (a visibility graph),
+//! so routes can cross a plaza rather than walking around its outline.
+
+use std::collections::HashMap;
+use geo::{
+    LineString,
+    Coordinate,
+    algorithm::geodesic_length::GeodesicLength,
+};
+use osmpbfreader::{
+    OsmId,
+    OsmObj,
+    NodeId,
+    WayId,
+};
+use crate::{
+    graph_config::GraphConfigOption,
+    osm_cache::OSMCache,
+    edge_list::{
+        Edge,
+        Direction,
+        WaySpan,
+    },
+    error::Error,
+};
+
+#[derive(Debug, Clone)]
+pub struct Area {
+    pub osmid: OsmId,
+    /// The way the area's edges are attributed to: the way itself, or the
+    /// first outer way of a multipolygon
+    pub way_osmid: WayId,
+    /// Every way making up the outline
+    pub outline_ways: Vec<WayId>,
+    pub graph_config_option: GraphConfigOption,
+    /// Whether the outline is itself part of the network
+    pub outline_is_edge: bool,
+    pub outer: Vec<Vec<NodeId>>,
+    pub inner: Vec<Vec<NodeId>>
+}
+
+/// Join ways end to end into closed rings, dropping any that can't be closed
+fn assemble_rings(ways: Vec<Vec<NodeId>>) -> Vec<Vec<NodeId>> {
+    let mut rings = vec![];
+    let (closed, mut open): (Vec<_>, Vec<_>) = ways.into_iter()
+        .filter(|w| w.len() > 1)
+        .partition(|w| w.first() == w.last());
+    rings.extend(closed);
+    while let Some(mut current) = open.pop() {
+        loop {
+            if current.first() == current.last() {
+                rings.push(current);
+                break
+            }
+            let last = current[current.len() - 1];
+            match open.iter().position(|w| w[0] == last || w[w.len() - 1] == last) {
+                Some(i) => {
+                    let mut next = open.swap_remove(i);
+                    if next[0] != last {
+                        next.reverse();
+                    }
+                    current.extend(next.into_iter().skip(1));
+                },
+                None => break
+            }
+        }
+    }
+    rings
+}
+
+/// Find the areas in the cache
+pub fn find_areas(osm_cache: &OSMCache) -> Vec<Area> {
+    let gc = &osm_cache.graph_config;
+    if gc.areas.is_empty() {
+        return vec![]
+    }
+    let mut areas = vec![];
+    for (osmid, obj) in osm_cache.osm_cache.iter() {
+        match obj {
+            OsmObj::Way(way) => {
+                if way.nodes.len() < 4 || !way.is_closed() {
+                    continue
+                }
+                if let Some(gco) = gc.matching_area_option(&way.tags) {
+                    areas.push(Area {
+                        osmid: *osmid,
+                        way_osmid: way.id,
+                        outline_ways: vec![way.id],
+                        graph_config_option: gco,
+                        outline_is_edge: gc.is_match(&way.tags),
+                        outer: vec![way.nodes.clone()],
+                        inner: vec![]
+                    });
+                }
+            },
+            OsmObj::Relation(rel) => {
+                if !rel.tags.contains("type", "multipolygon") {
+                    continue
+                }
+                let gco = match gc.matching_area_option(&rel.tags) {
+                    Some(gco) => gco,
+                    None => continue
+                };
+                let mut outer = vec![];
+                let mut inner = vec![];
+                let mut outline_ways = vec![];
+                for member in rel.refs.iter() {
+                    let way = match member.member.way()
+                        .and_then(|w| osm_cache.osm_cache.get(&OsmId::Way(w)))
+                        .and_then(|o| o.way()) {
+                            Some(way) => way,
+                            None => continue
+                        };
+                    outline_ways.push(way.id);
+                    if member.role == "inner" {
+                        inner.push(way.nodes.clone());
+                    } else {
+                        outer.push(way.nodes.clone());
+                    }
+                }
+                if outline_ways.is_empty() {
+                    continue
+                }
+                areas.push(Area {
+                    osmid: *osmid,
+                    way_osmid: outline_ways[0],
+                    outline_ways,
+                    graph_config_option: gco,
+                    outline_is_edge: false,
+                    outer: assemble_rings(outer),
+                    inner: assemble_rings(inner)
+                });
+            },
+            OsmObj::Node(_) => ()
+        }
+    }
+    areas
+}
+
+type Point = (f64, f64);
+
+fn cross(o: Point, a: Point, b: Point) -> f64 {
+    (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+}
+
+/// Whether segments ab and cd cross at a single point interior to both
+fn segments_cross(a: Point, b: Point, c: Point, d: Point) -> bool {
+    let d1 = cross(c, d, a);
+    let d2 = cross(c, d, b);
+    let d3 = cross(a, b, c);
+    let d4 = cross(a, b, d);
+    d1 * d2 < 0.0 && d3 * d4 < 0.0
+}
+
+fn on_segment(p: Point, a: Point, b: Point) -> bool {
+    let scale = (b.0 - a.0).abs().max((b.1 - a.1).abs()).max(1e-12);
+    cross(a, b, p).abs() <= 1e-9 * scale * scale
+        && p.0 >= a.0.min(b.0) && p.0 <= a.0.max(b.0)
+        && p.1 >= a.1.min(b.1) && p.1 <= a.1.max(b.1)
+}
+
+fn inside_ring(p: Point, ring: &[Point]) -> bool {
+    let mut inside = false;
+    for w in ring.windows(2) {
+        let (a, b) = (w[0], w[1]);
+        if (a.1 > p.1) != (b.1 > p.1)
+            && p.0 < (b.0 - a.0) * (p.1 - a.1) / (b.1 - a.1) + a.0 {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+struct Rings {
+    outer: Vec<Vec<(NodeId, Point)>>,
+    inner: Vec<Vec<(NodeId, Point)>>
+}
+
+impl Rings {
+    fn all(&self) -> impl Iterator<Item=&Vec<(NodeId, Point)>> {
+        self.outer.iter().chain(self.inner.iter())
+    }
+
+    fn on_boundary(&self, p: Point) -> bool {
+        self.all().any(|r| r.windows(2).any(|w| on_segment(p, w[0].1, w[1].1)))
+    }
+
+    fn contains(&self, p: Point) -> bool {
+        let points = |r: &Vec<(NodeId, Point)>| r.iter().map(|v| v.1).collect::<Vec<_>>();
+        self.outer.iter().any(|r| inside_ring(p, &points(r)))
+            && !self.inner.iter().any(|r| inside_ring(p, &points(r)))
+    }
+
+    /// Whether the straight line between two outline nodes stays within the area
+    fn visible(&self, a: (NodeId, Point), b: (NodeId, Point)) -> bool {
+        for ring in self.all() {
+            for w in ring.windows(2) {
+                let (c, d) = (w[0], w[1]);
+                let touches_end = |v: NodeId| v == a.0 || v == b.0;
+                if !touches_end(c.0) && !touches_end(d.0) && segments_cross(a.1, b.1, c.1, d.1) {
+                    return false
+                }
+                // passing through another outline node might leave the area
+                if !touches_end(c.0) && on_segment(c.1, a.1, b.1) {
+                    return false
+                }
+            }
+        }
+        let mid = ((a.1.0 + b.1.0) / 2.0, (a.1.1 + b.1.1) / 2.0);
+        self.on_boundary(mid) || self.contains(mid)
+    }
+}
+
+impl Area {
+    /// Build the edges across the area between each pair of visible entry points
+    pub fn edges(&self, osm_cache: &OSMCache) -> Result<Vec<Edge<f64>>, Error> {
+        let coords = |ring: &Vec<NodeId>| -> Option<Vec<(NodeId, Point)>> {
+            ring.iter()
+                .map(|nid| osm_cache.node_coords(*nid).map(|c| (*nid, c)))
+                .collect()
+        };
+        // areas with nodes outside the extract are skipped
+        let rings = match (
+            self.outer.iter().map(coords).collect::<Option<Vec<_>>>(),
+            self.inner.iter().map(coords).collect::<Option<Vec<_>>>()
+        ) {
+            (Some(outer), Some(inner)) => Rings { outer, inner },
+            _ => return Ok(vec![])
+        };
+
+        // an outline node is an entry point if other ways reference it
+        let mut own_count: HashMap<NodeId, usize> = HashMap::new();
+        for wid in self.outline_ways.iter() {
+            if let Some(way) = osm_cache.osm_cache.get(&OsmId::Way(*wid)).and_then(|o| o.way()) {
+                // counted as in count_nodes, skipping nodes repeated straight after themselves
+                let mut prev = None;
+                for nid in way.nodes.iter() {
+                    if prev.replace(nid) != Some(nid) {
+                        *own_count.entry(*nid).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+        let mut entries: Vec<(NodeId, Point)> = vec![];
+        for vertex in rings.all().flatten() {
+            let count = osm_cache.node_count(vertex.0);
+            if count > own_count.get(&vertex.0).copied().unwrap_or(0)
+                && !entries.iter().any(|e| e.0 == vertex.0) {
+                entries.push(*vertex);
+            }
+        }
+
+        // neighbouring outline nodes are already joined by the outline's own edges
+        let adjacent = |a: NodeId, b: NodeId| self.outline_is_edge && rings.all()
+            .any(|r| r.windows(2).any(|w| (w[0].0 == a && w[1].0 == b) || (w[0].0 == b && w[1].0 == a)));
+
+        let tags = osm_cache.osm_cache.get(&self.osmid).map(|o| o.tags().clone()).unwrap_or_default();
+        let mut edges = vec![];
+        // the edges across an area are numbered along its way after the
+        // outline, whose own edges may come from the same way
+        let mut offset_m: f64 = rings.all()
+            .map(|r| r.iter().map(|(_, p)| Coordinate { x: p.0, y: p.1 }).collect::<LineString<f64>>().geodesic_length())
+            .sum();
+        for (i, a) in entries.iter().enumerate() {
+            for b in entries[i + 1..].iter() {
+                if adjacent(a.0, b.0) || !rings.visible(*a, *b) {
+                    continue
+                }
+                let geom: LineString<f64> = vec![
+                    Coordinate { x: a.1.0, y: a.1.1 },
+                    Coordinate { x: b.1.0, y: b.1.1 }
+                ].into();
+                let length_m = geom.geodesic_length();
+                edges.push(Edge {
+                    way_osmid: self.way_osmid,
+                    merged_way_osmids: vec![],
+                    start_node_id: a.0,
+                    end_node_id: b.0,
+                    graph_config_option: self.graph_config_option.clone(),
+                    length_m,
+                    way_spans: vec![WaySpan::along(self.way_osmid, offset_m, length_m)],
+                    geometry: geom,
+                    routes: vec![],
+                    direction: Direction::Both,
+                    area: true,
+                    boundary: false,
+                    info: None,
+                    dense_ids: None,
+                    component: None,
+                    travel_time: osm_cache.graph_config.travel_time.as_ref().map(|tt| tt.travel_times(
+                        &self.graph_config_option.name, &tags, length_m, &[a.0, b.0], osm_cache)),
+                    elevation: None,
+                    measures: None
+                });
+                offset_m += edges[edges.len() - 1].length_m;
+            }
+        }
+        Ok(edges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        graph_config::GraphConfig,
+        edge_list::edge_list_from_xml_with_config,
+    };
+
+    fn graph_config() -> GraphConfig {
+        serde_json::from_str(r#"{
+            "name": "walk",
+            "options": [
+                {"name": "path", "requires": [{"key": "highway", "kind": {"exact": "footway"}}], "excludes": []}
+            ],
+            "areas": [
+                {"name": "plaza", "requires": [{"key": "highway", "kind": {"exact": "pedestrian"}}], "excludes": []}
+            ]
+        }"#).unwrap()
+    }
+
+    fn area_edges(xml: &str) -> Vec<(i64, i64)> {
+        let (_, edge_list) = edge_list_from_xml_with_config(graph_config(), xml);
+        let mut edges: Vec<_> = edge_list.edges.iter()
+            .filter(|e| e.area)
+            .map(|e| (e.start_node_id.0.min(e.end_node_id.0), e.start_node_id.0.max(e.end_node_id.0)))
+            .collect();
+        edges.sort();
+        edges
+    }
+
+    // An L shaped plaza
+    //
+    //  4-----3
+    //  |     |
+    //  |     2-----1
+    //  |           |
+    //  5-----------6
+    //
+    // with footways leaving from nodes 1, 3 and 5
+    const NODES: &str = r#"
+  <node id="1" lat="0.0010" lon="0.0020"/>
+  <node id="2" lat="0.0010" lon="0.0010"/>
+  <node id="3" lat="0.0020" lon="0.0010"/>
+  <node id="4" lat="0.0020" lon="0.0000"/>
+  <node id="5" lat="0.0000" lon="0.0000"/>
+  <node id="6" lat="0.0000" lon="0.0020"/>
+  <node id="11" lat="0.0010" lon="0.0030"/>
+  <node id="13" lat="0.0030" lon="0.0010"/>
+  <node id="15" lat="-0.0010" lon="0.0000"/>
+  <way id="21"><nd ref="1"/><nd ref="11"/><tag k="highway" v="footway"/></way>
+  <way id="23"><nd ref="3"/><nd ref="13"/><tag k="highway" v="footway"/></way>
+  <way id="25"><nd ref="5"/><nd ref="15"/><tag k="highway" v="footway"/></way>"#;
+
+    #[test]
+    fn test_closed_way_area() {
+        let xml = format!(r#"<osm>{}
+  <way id="30">
+    <nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="5"/><nd ref="6"/><nd ref="1"/>
+    <tag k="highway" v="pedestrian"/><tag k="area" v="yes"/>
+  </way>
+</osm>"#, NODES);
+        // 1 and 3 can't see each other across the corner at 2
+        assert_eq!(area_edges(&xml), vec![(1, 5), (3, 5)]);
+
+        // only the edges across the area have the area field
+        #[cfg(feature="formats-geojson")]
+        {
+            let (_, edge_list) = edge_list_from_xml_with_config(graph_config(), &xml);
+            for edge in edge_list.edges {
+                let area = edge.area;
+                let feature: geojson::Feature = edge.into();
+                assert_eq!(feature.properties.unwrap().contains_key("area"), area);
+            }
+        }
+    }
+
+    #[test]
+    fn test_multipolygon_area() {
+        // the same plaza split into two untagged outer ways
+        let xml = format!(r#"<osm>{}
+  <way id="31"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/></way>
+  <way id="32"><nd ref="1"/><nd ref="6"/><nd ref="5"/><nd ref="4"/></way>
+  <relation id="40">
+    <member type="way" ref="31" role="outer"/>
+    <member type="way" ref="32" role="outer"/>
+    <tag k="type" v="multipolygon"/><tag k="highway" v="pedestrian"/>
+  </relation>
+</osm>"#, NODES);
+        assert_eq!(area_edges(&xml), vec![(1, 5), (3, 5)]);
+
+        // the multipolygon isn't a route
+        let (_, edge_list) = edge_list_from_xml_with_config(graph_config(), &xml);
+        assert!(edge_list.edges.iter().all(|e| e.routes.is_empty()));
+    }
+
+    #[test]
+    fn test_assemble_rings() {
+        let ids = |v: &[i64]| v.iter().map(|n| NodeId(*n)).collect::<Vec<_>>();
+        let rings = assemble_rings(vec![ids(&[1, 2, 3]), ids(&[5, 4, 3]), ids(&[5, 1]), ids(&[7, 8])]);
+        assert_eq!(rings, vec![ids(&[5, 1, 2, 3, 4, 5])]);
+    }
+}
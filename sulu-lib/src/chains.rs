@@ -0,0 +1,200 @@
+//! Merging chains of edges through nodes of degree 2
+//!
+//! Mappers split roads wherever a tag changes, so one stretch of road
+//! between junctions is often several ways and so several edges. Where
+//! exactly two edges meet at a node, and they agree on the configured
+//! attributes, they can be merged into one.
+
+use std::collections::BTreeMap;
+use osmpbfreader::{
+    NodeId,
+    OsmId,
+};
+use crate::{
+    edge_list::{
+        Edge,
+        EdgeList,
+    },
+    graph_config::{
+        EdgeAttribute,
+        MergeChains,
+    },
+    measures::GeometryMeasures,
+    osm_cache::OSMCache,
+};
+
+/// Flip an edge so it runs from its end node to its start node
+fn reverse(edge: &mut Edge<f64>) {
+    edge.geometry.0.reverse();
+    edge.way_spans = edge.way_spans.iter().rev().map(|s| s.reversed()).collect();
+    std::mem::swap(&mut edge.start_node_id, &mut edge.end_node_id);
+    edge.direction = edge.direction.reversed();
+    edge.travel_time = edge.travel_time.map(|t| t.reversed());
+    edge.elevation = edge.elevation.as_ref().map(|e| e.reversed());
+    edge.measures = edge.measures.map(|m| GeometryMeasures::of(edge, &m.selected()));
+}
+
+/// Whether two edges agree on the attributes and tags in `config`, which
+/// don't depend on which way the edges run
+fn matches(a: &Edge<f64>, b: &Edge<f64>, config: &MergeChains, osm_cache: &OSMCache) -> bool {
+    let attributes_match = config.attributes.iter().all(|attr| match attr {
+        EdgeAttribute::GraphConfigOption => a.graph_config_option.name == b.graph_config_option.name,
+        EdgeAttribute::Routes => a.routes == b.routes,
+        EdgeAttribute::Area => a.area == b.area
+    });
+    if !attributes_match {
+        return false
+    }
+    let tags = |edge: &Edge<f64>| osm_cache.osm_cache.get(&OsmId::Way(edge.way_osmid))
+        .and_then(|o| o.way())
+        .map(|w| &w.tags);
+    let (a_tags, b_tags) = (tags(a), tags(b));
+    config.tags.iter().all(|key| {
+        a_tags.and_then(|t| t.get(key.as_str())) == b_tags.and_then(|t| t.get(key.as_str()))
+    })
+}
+
+impl EdgeList<f64> {
+    /// Merge pairs of edges that are the only edges at a node, and agree on
+    /// the attributes and tags in `config`, until no more can be merged.
+    /// Merged edges keep the way id of their first edge, list the other
+    /// ways in `merged_way_osmids`, and have the summed length.
+    ///
+    /// Nodes where a way outside the network splits it (see
+    /// `GraphConfig::split_at`) are kept. Merged edges may be reversed
+    /// relative to their ways so that they run in one direction.
+    pub fn merge_chains(&mut self, osm_cache: &OSMCache, config: &MergeChains) {
+        let mut edges: Vec<Option<Edge<f64>>> = std::mem::take(&mut self.edges)
+            .into_iter()
+            .map(Some)
+            .collect();
+        let mut incident: BTreeMap<NodeId, Vec<usize>> = BTreeMap::new();
+        for (i, edge) in edges.iter().flatten().enumerate() {
+            incident.entry(edge.start_node_id).or_default().push(i);
+            incident.entry(edge.end_node_id).or_default().push(i);
+        }
+
+        let nodes: Vec<NodeId> = incident.keys().copied().collect();
+        for node in nodes {
+            let (i, j) = match incident.get(&node).map(|e| e.as_slice()) {
+                Some([i, j]) if i != j && osm_cache.nodes.entry(node).splits == 0 => (*i, *j),
+                _ => continue
+            };
+            let (first, second) = match (&edges[i], &edges[j]) {
+                (Some(first), Some(second)) => (first, second),
+                _ => continue
+            };
+            // the chain runs first -> node -> second, flipping edges that
+            // run the other way only once they are merged
+            let flip_first = first.end_node_id != node;
+            let flip_second = second.start_node_id != node;
+            let start = if flip_first { first.end_node_id } else { first.start_node_id };
+            let end = if flip_second { second.start_node_id } else { second.end_node_id };
+            let direction = |edge: &Edge<f64>, flip: bool| if flip { edge.direction.reversed() } else { edge.direction };
+            // merging the two halves of a ring would make a self-loop
+            if start == end
+                || direction(first, flip_first) != direction(second, flip_second)
+                || !matches(first, second, config, osm_cache) {
+                continue
+            }
+            let (mut first, mut second) = (edges[i].take().unwrap(), edges[j].take().unwrap());
+            if flip_first {
+                reverse(&mut first);
+            }
+            if flip_second {
+                reverse(&mut second);
+            }
+
+            first.geometry.0.extend(second.geometry.0.into_iter().skip(1));
+            first.end_node_id = second.end_node_id;
+            first.elevation = match (&first.elevation, &second.elevation) {
+                (Some(a), Some(b)) => Some(a.then(first.length_m, b, second.length_m)),
+                _ => None
+            };
+            first.length_m += second.length_m;
+            first.measures = first.measures.map(|m| GeometryMeasures::of(&first, &m.selected()));
+            first.travel_time = match (first.travel_time, second.travel_time) {
+                (Some(a), Some(b)) => Some(a.then(b)),
+                _ => None
+            };
+            first.way_spans.extend(second.way_spans);
+            first.merged_way_osmids.push(second.way_osmid);
+            first.merged_way_osmids.extend(second.merged_way_osmids);
+            first.boundary |= second.boundary;
+            first.area &= second.area;
+            for route in second.routes {
+                if !first.routes.contains(&route) {
+                    first.routes.push(route);
+                }
+            }
+            incident.remove(&node);
+            if let Some(ends) = incident.get_mut(&first.end_node_id) {
+                for e in ends.iter_mut().filter(|e| **e == j) {
+                    *e = i;
+                }
+            }
+            edges[i] = Some(first);
+        }
+        self.edges = edges.into_iter().flatten().collect();
+        self.invalidate_ids();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use osmpbfreader::WayId;
+    use crate::edge_list::{
+        edge_list_from_xml,
+        synthetic_node_id,
+    };
+
+    #[test]
+    fn test_merge_chains() {
+        // George Street is mapped as three ways, because the speed limit
+        // changes, with a side street joining at node 3
+        let xml = r#"<osm>
+  <node id="1" lat="0.0" lon="0.000"/>
+  <node id="2" lat="0.0" lon="0.001"/>
+  <node id="3" lat="0.0" lon="0.002"/>
+  <node id="4" lat="0.0" lon="0.003"/>
+  <node id="5" lat="0.001" lon="0.002"/>
+  <way id="10"><nd ref="2"/><nd ref="1"/><tag k="highway" v="primary"/><tag k="name" v="George Street"/></way>
+  <way id="11"><nd ref="2"/><nd ref="3"/><tag k="highway" v="primary"/><tag k="name" v="George Street"/><tag k="maxspeed" v="40"/></way>
+  <way id="12"><nd ref="3"/><nd ref="4"/><tag k="highway" v="primary"/><tag k="name" v="George Street"/></way>
+  <way id="13"><nd ref="3"/><nd ref="5"/><tag k="highway" v="residential"/></way>
+</osm>"#;
+        let (cache, mut edge_list) = edge_list_from_xml(xml);
+        let length: f64 = edge_list.edges.iter().take(2).map(|e| e.length_m).sum();
+
+        let config = MergeChains { attributes: vec![EdgeAttribute::GraphConfigOption], tags: vec!["name".to_string()] };
+        edge_list.merge_chains(&cache, &config);
+        assert_eq!(edge_list.edges.len(), 3);
+        let merged = &edge_list.edges[0];
+        assert_eq!((merged.start_node_id, merged.end_node_id), (NodeId(1), NodeId(3)));
+        assert_eq!(merged.merged_way_osmids, vec![osmpbfreader::WayId(11)]);
+        assert_eq!(merged.geometry.0.len(), 3);
+        assert!((merged.length_m - length).abs() < 1e-9);
+
+        // way 10 runs from node 2 back to node 1, so a split 10 m along the
+        // merged edge is 10 m short of the end of way 10
+        let way_10 = EdgeList::try_from(&cache).unwrap().edges[0].length_m;
+        let (head, tail) = merged.split_at(10.0).unwrap();
+        assert_eq!(head.end_node_id, synthetic_node_id(WayId(10), way_10 - 10.0));
+        assert_eq!(tail.way_spans.len(), 2);
+        // and a split in way 11 is along way 11
+        let (head, _) = merged.split_at(way_10 + 10.0).unwrap();
+        assert_eq!(head.end_node_id, synthetic_node_id(WayId(11), 10.0));
+
+        // node 2 is kept when the speed limit has to match, and the edges
+        // that aren't merged are left as they were
+        let mut edge_list = EdgeList::try_from(&cache).unwrap();
+        let config = MergeChains { attributes: vec![], tags: vec!["maxspeed".to_string()] };
+        edge_list.merge_chains(&cache, &config);
+        assert_eq!(edge_list.edges.len(), 4);
+        let unmerged = &edge_list.edges[0];
+        assert_eq!((unmerged.way_osmid.0, unmerged.start_node_id, unmerged.end_node_id), (10, NodeId(2), NodeId(1)));
+        assert_eq!(unmerged.geometry.0, EdgeList::try_from(&cache).unwrap().edges[0].geometry.0);
+    }
+}
@@ -0,0 +1,241 @@
+//! Connected components of an `EdgeList`, for finding and pruning the
+//! fragments of a network that can't be reached from the rest of it
+
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+use osmpbfreader::NodeId;
+use crate::{
+    edge_list::EdgeList,
+    error::Error,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connectivity {
+    /// Connected ignoring the direction of edges
+    Weak,
+    /// Every node can reach every other following the direction of edges
+    Strong
+}
+
+impl std::str::FromStr for Connectivity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "weak" => Ok(Connectivity::Weak),
+            "strong" => Ok(Connectivity::Strong),
+            _ => Err(Error::ConversionError(format!("Unknown connectivity: {}", s)))
+        }
+    }
+}
+
+fn find(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+fn weak_labels(n: usize, links: &[(usize, usize, bool, bool)]) -> Vec<usize> {
+    let mut parents: Vec<usize> = (0..n).collect();
+    for (a, b, _, _) in links {
+        let (ra, rb) = (find(&mut parents, *a), find(&mut parents, *b));
+        if ra != rb {
+            parents[ra.max(rb)] = ra.min(rb);
+        }
+    }
+    (0..n).map(|i| find(&mut parents, i)).collect()
+}
+
+/// Tarjan's algorithm, without recursion so long chains don't overflow the stack
+fn strong_labels(n: usize, links: &[(usize, usize, bool, bool)]) -> Vec<usize> {
+    let mut adjacent: Vec<Vec<usize>> = vec![vec![]; n];
+    for (a, b, forward, backward) in links {
+        if *forward {
+            adjacent[*a].push(*b);
+        }
+        if *backward {
+            adjacent[*b].push(*a);
+        }
+    }
+    const UNVISITED: usize = usize::MAX;
+    let mut index = vec![UNVISITED; n];
+    let mut low = vec![0; n];
+    let mut on_stack = vec![false; n];
+    let mut stack = vec![];
+    let mut labels = vec![UNVISITED; n];
+    let mut next_index = 0;
+    for root in 0..n {
+        if index[root] != UNVISITED {
+            continue
+        }
+        // (vertex, position in its adjacency list)
+        let mut calls = vec![(root, 0)];
+        while let Some((v, i)) = calls.pop() {
+            if i == 0 {
+                index[v] = next_index;
+                low[v] = next_index;
+                next_index += 1;
+                stack.push(v);
+                on_stack[v] = true;
+            } else {
+                let w = adjacent[v][i - 1];
+                low[v] = low[v].min(low[w]);
+            }
+            let mut descended = false;
+            for (j, w) in adjacent[v].iter().enumerate().skip(i) {
+                if index[*w] == UNVISITED {
+                    calls.push((v, j + 1));
+                    calls.push((*w, 0));
+                    descended = true;
+                    break
+                } else if on_stack[*w] {
+                    low[v] = low[v].min(index[*w]);
+                }
+            }
+            if descended || low[v] != index[v] {
+                continue
+            }
+            while let Some(w) = stack.pop() {
+                on_stack[w] = false;
+                labels[w] = v;
+                if w == v {
+                    break
+                }
+            }
+        }
+    }
+    labels
+}
+
+impl EdgeList<f64> {
+    /// Label the edges and nodes with the component they are in, numbered
+    /// from 0 in order of decreasing total edge length. For strong
+    /// connectivity, edges between two components are left unlabelled.
+    /// Returns the total length of each component.
+    pub fn label_components(&mut self, connectivity: Connectivity) -> Vec<f64> {
+        let mut vertices: BTreeMap<NodeId, usize> = BTreeMap::new();
+        for edge in self.edges.iter() {
+            vertices.entry(edge.start_node_id).or_insert(0);
+            vertices.entry(edge.end_node_id).or_insert(0);
+        }
+        for (i, v) in vertices.values_mut().enumerate() {
+            *v = i;
+        }
+        let links: Vec<(usize, usize, bool, bool)> = self.edges.iter()
+            .map(|e| (vertices[&e.start_node_id], vertices[&e.end_node_id],
+                      e.direction.forward(), e.direction.backward()))
+            .collect();
+        let raw = match connectivity {
+            Connectivity::Weak => weak_labels(vertices.len(), &links),
+            Connectivity::Strong => strong_labels(vertices.len(), &links)
+        };
+
+        // the raw labels are vertex indices, so ties in length go to the
+        // component with the lowest node id
+        let mut lengths: BTreeMap<usize, f64> = raw.iter().map(|r| (*r, 0.0)).collect();
+        for (edge, (a, b, _, _)) in self.edges.iter().zip(links.iter()) {
+            if raw[*a] == raw[*b] {
+                *lengths.entry(raw[*a]).or_insert(0.0) += edge.length_m;
+            }
+        }
+        let mut order: Vec<(usize, f64)> = lengths.into_iter().collect();
+        order.sort_by(|x, y| y.1.partial_cmp(&x.1).unwrap_or(std::cmp::Ordering::Equal).then(x.0.cmp(&y.0)));
+        let ids: HashMap<usize, usize> = order.iter()
+            .enumerate()
+            .map(|(id, (r, _))| (*r, id))
+            .collect();
+
+        for (edge, (a, b, _, _)) in self.edges.iter_mut().zip(links.iter()) {
+            edge.component = if raw[*a] == raw[*b] { Some(ids[&raw[*a]]) } else { None };
+        }
+        self.node_components = Some(vertices.into_iter()
+            .map(|(nid, i)| (nid, ids[&raw[i]]))
+            .collect());
+        order.into_iter().map(|(_, length)| length).collect()
+    }
+
+    /// The component of a node, once components have been labelled
+    pub fn component(&self, node_id: NodeId) -> Option<usize> {
+        self.node_components.as_ref()?.get(&node_id).copied()
+    }
+
+    /// Keep only the edges of the longest component, and relabel
+    pub fn keep_largest_component(&mut self, connectivity: Connectivity) {
+        self.label_components(connectivity);
+        self.edges.retain(|e| e.component == Some(0));
+        self.invalidate_ids();
+        self.label_components(connectivity);
+    }
+
+    /// Drop the edges of components shorter than `min_length_m`, and relabel.
+    /// Edges between two components are kept if both of them are kept.
+    pub fn drop_short_components(&mut self, connectivity: Connectivity, min_length_m: f64) {
+        let lengths = self.label_components(connectivity);
+        let long = |node_id: NodeId| self.component(node_id).is_some_and(|c| lengths[c] >= min_length_m);
+        let keep: Vec<bool> = self.edges.iter()
+            .map(|e| long(e.start_node_id) && long(e.end_node_id))
+            .collect();
+        let mut keep = keep.into_iter();
+        self.edges.retain(|_| keep.next().unwrap_or(false));
+        self.invalidate_ids();
+        self.label_components(connectivity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edge_list::edge_list_from_xml;
+
+    const XML: &str = r#"<osm>
+  <node id="1" lat="0.0" lon="0.000"/>
+  <node id="2" lat="0.0" lon="0.001"/>
+  <node id="3" lat="0.0" lon="0.002"/>
+  <node id="4" lat="0.0" lon="0.003"/>
+  <node id="8" lat="0.01" lon="0.000"/>
+  <node id="9" lat="0.01" lon="0.0005"/>
+  <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+  <way id="11"><nd ref="2"/><nd ref="3"/><tag k="highway" v="primary"/><tag k="oneway" v="yes"/></way>
+  <way id="12"><nd ref="3"/><nd ref="4"/><tag k="highway" v="primary"/></way>
+  <way id="13"><nd ref="8"/><nd ref="9"/><tag k="highway" v="residential"/></way>
+</osm>"#;
+
+    fn edge_list() -> EdgeList<f64> {
+        edge_list_from_xml(XML).1
+    }
+
+    #[test]
+    fn test_components() {
+        let mut el = edge_list();
+        let lengths = el.label_components(Connectivity::Weak);
+        assert_eq!(lengths.len(), 2);
+        let components: Vec<_> = el.edges.iter().map(|e| e.component).collect();
+        assert_eq!(components, vec![Some(0), Some(0), Some(0), Some(1)]);
+        assert_eq!(el.component(NodeId(9)), Some(1));
+
+        // the one way street can't be travelled back, so 1-2 and 3-4 are separate
+        let lengths = el.label_components(Connectivity::Strong);
+        assert_eq!(lengths.len(), 3);
+        let components: Vec<_> = el.edges.iter().map(|e| e.component).collect();
+        assert_eq!(components, vec![Some(0), None, Some(1), Some(2)]);
+
+        el.keep_largest_component(Connectivity::Weak);
+        assert_eq!(el.edges.len(), 3);
+
+        let mut el = edge_list();
+        el.drop_short_components(Connectivity::Weak, 100.0);
+        assert_eq!(el.edges.len(), 3);
+        el.drop_short_components(Connectivity::Weak, 1000.0);
+        assert!(el.edges.is_empty());
+
+        // the one way street joins two components that are both kept
+        let mut el = edge_list();
+        el.drop_short_components(Connectivity::Strong, 100.0);
+        let ways: Vec<_> = el.edges.iter().map(|e| e.way_osmid.0).collect();
+        assert_eq!(ways, vec![10, 11, 12]);
+    }
+}
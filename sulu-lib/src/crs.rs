@@ -0,0 +1,273 @@
+//! Reprojecting edge and node geometries
+//!
+//! Edges are built in longitude and latitude (EPSG:4326). For output in a
+//! projected coordinate system, sulu has built in transforms for the common
+//! ones, so it works without GDAL: Web Mercator, UTM and the Australian MGA
+//! zones. MGA is treated as UTM on WGS 84, ignoring the GDA94 and GDA2020
+//! datum shifts of around a metre.
+
+use geo::{
+    Coordinate,
+    LineString,
+};
+use crate::{
+    edge_list::EdgeList,
+    node_list::NodeList,
+    error::Error,
+};
+#[cfg(feature="formats-geojson")]
+use serde_json::{json, Map, Value};
+
+/// WGS 84 semi-major axis, in metres
+const A: f64 = 6_378_137.0;
+/// WGS 84 flattening
+const F: f64 = 1.0 / 298.257_223_563;
+/// The UTM scale factor on the central meridian
+const UTM_K0: f64 = 0.9996;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum Projection {
+    #[default]
+    LonLat,
+    WebMercator,
+    TransverseMercator {
+        zone: u32,
+        south: bool
+    }
+}
+
+/// A coordinate system, identified by its EPSG code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crs {
+    pub epsg: u32,
+    projection: Projection
+}
+
+impl Default for Crs {
+    fn default() -> Self {
+        Crs { epsg: 4326, projection: Projection::LonLat }
+    }
+}
+
+impl Crs {
+    /// The coordinate system for an EPSG code, if sulu can transform to it
+    pub fn from_epsg(epsg: u32) -> Result<Self, Error> {
+        let projection = match epsg {
+            4326 => Projection::LonLat,
+            3857 => Projection::WebMercator,
+            32601..=32660 => Projection::TransverseMercator { zone: epsg - 32600, south: false },
+            32701..=32760 => Projection::TransverseMercator { zone: epsg - 32700, south: true },
+            // GDA94 / MGA zones 48 to 58
+            28348..=28358 => Projection::TransverseMercator { zone: epsg - 28300, south: true },
+            // GDA2020 / MGA zones 46 to 59
+            7846..=7859 => Projection::TransverseMercator { zone: epsg - 7800, south: true },
+            _ => return Err(Error::ConversionError(format!("Unsupported coordinate system: EPSG:{}", epsg)))
+        };
+        Ok(Crs { epsg, projection })
+    }
+
+    /// The WGS 84 UTM zone containing a point
+    pub fn utm_for(lon: f64, lat: f64) -> Self {
+        let zone = (((lon + 180.0) / 6.0).floor() as i64).clamp(0, 59) as u32 + 1;
+        let south = lat < 0.0;
+        Crs {
+            epsg: if south { 32700 + zone } else { 32600 + zone },
+            projection: Projection::TransverseMercator { zone, south }
+        }
+    }
+
+    /// Whether coordinates in this system are longitude and latitude
+    pub fn is_lon_lat(&self) -> bool {
+        self.projection == Projection::LonLat
+    }
+
+    /// Project a longitude and latitude into this coordinate system
+    pub fn project(&self, lon: f64, lat: f64) -> (f64, f64) {
+        match self.projection {
+            Projection::LonLat => (lon, lat),
+            Projection::WebMercator => {
+                let lat = lat.clamp(-85.051_128_78, 85.051_128_78);
+                (A * lon.to_radians(), A * (std::f64::consts::FRAC_PI_4 + lat.to_radians() / 2.0).tan().ln())
+            },
+            Projection::TransverseMercator { zone, south } => {
+                let central_meridian = zone as f64 * 6.0 - 183.0;
+                let (easting, northing) = transverse_mercator(lon - central_meridian, lat);
+                (500_000.0 + easting, if south { 10_000_000.0 + northing } else { northing })
+            }
+        }
+    }
+
+    /// The old style GeoJSON `crs` member naming this coordinate system,
+    /// which GDAL and QGIS read
+    #[cfg(feature="formats-geojson")]
+    pub fn geojson_member(&self) -> Map<String, Value> {
+        let mut member = Map::new();
+        member.insert("crs".to_string(), json!({
+            "type": "name",
+            "properties": { "name": format!("urn:ogc:def:crs:EPSG::{}", self.epsg) }
+        }));
+        member
+    }
+
+    /// The coordinate system named by the `crs` member of a GeoJSON object,
+    /// longitude and latitude if there isn't one
+    #[cfg(feature="formats-geojson")]
+    pub fn from_geojson_member(members: Option<&Map<String, Value>>) -> Result<Self, Error> {
+        let name = members
+            .and_then(|m| m.get("crs"))
+            .and_then(|crs| crs.pointer("/properties/name"))
+            .and_then(|name| name.as_str());
+        match name {
+            Some(name) => name.rsplit(':').next().unwrap_or(name).parse(),
+            None => Ok(Crs::default())
+        }
+    }
+}
+
+impl std::str::FromStr for Crs {
+    type Err = Error;
+
+    /// Parses `4326` or `EPSG:4326`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code = s.strip_prefix("EPSG:").or_else(|| s.strip_prefix("epsg:")).unwrap_or(s);
+        let epsg = code.parse()
+            .map_err(|_| Error::ConversionError(format!("Not an EPSG code: {}", s)))?;
+        Crs::from_epsg(epsg)
+    }
+}
+
+/// Easting and northing (before false easting and northing) on a
+/// transverse Mercator projection with the UTM scale factor, using Krüger's
+/// series to third order in n, which is accurate to well under a millimetre
+/// within a zone
+fn transverse_mercator(dlon: f64, lat: f64) -> (f64, f64) {
+    let n = F / (2.0 - F);
+    let big_a = A / (1.0 + n) * (1.0 + n * n / 4.0 + n.powi(4) / 64.0);
+    let alpha = [
+        n / 2.0 - 2.0 * n * n / 3.0 + 5.0 * n.powi(3) / 16.0,
+        13.0 * n * n / 48.0 - 3.0 * n.powi(3) / 5.0,
+        61.0 * n.powi(3) / 240.0
+    ];
+    let (phi, lambda) = (lat.to_radians(), dlon.to_radians());
+    let c = 2.0 * n.sqrt() / (1.0 + n);
+    let t = (phi.sin().atanh() - c * (c * phi.sin()).atanh()).sinh();
+    let xi_prime = t.atan2(lambda.cos());
+    let eta_prime = (lambda.sin() / (1.0 + t * t).sqrt()).atanh();
+    let mut xi = xi_prime;
+    let mut eta = eta_prime;
+    for (j, a) in alpha.iter().enumerate() {
+        let k = 2.0 * (j + 1) as f64;
+        xi += a * (k * xi_prime).sin() * (k * eta_prime).cosh();
+        eta += a * (k * xi_prime).cos() * (k * eta_prime).sinh();
+    }
+    (UTM_K0 * big_a * eta, UTM_K0 * big_a * xi)
+}
+
+fn project_linestring(crs: &Crs, linestring: &mut LineString<f64>) {
+    for coord in linestring.0.iter_mut() {
+        let (x, y) = crs.project(coord.x, coord.y);
+        *coord = Coordinate { x, y };
+    }
+}
+
+impl EdgeList<f64> {
+    /// The UTM zone containing the centre of the edges' bounding box
+    pub fn utm_crs(&self) -> Crs {
+        let (mut min, mut max) = ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN));
+        for coord in self.edges.iter().flat_map(|e| e.geometry.0.iter()) {
+            min = (min.0.min(coord.x), min.1.min(coord.y));
+            max = (max.0.max(coord.x), max.1.max(coord.y));
+        }
+        if self.edges.is_empty() {
+            return Crs::utm_for(0.0, 0.0)
+        }
+        Crs::utm_for((min.0 + max.0) / 2.0, (min.1 + max.1) / 2.0)
+    }
+
+    /// Transform the edge geometries from longitude and latitude to `crs`.
+    /// This should be the last step before output: lengths are kept, but
+    /// anything that measures geometry expects longitude and latitude.
+    /// Fails if the edges are already projected to another system.
+    pub fn reproject(&mut self, crs: &Crs) -> Result<(), Error> {
+        if self.crs == *crs {
+            return Ok(())
+        }
+        check_lon_lat(&self.crs, crs)?;
+        for edge in self.edges.iter_mut() {
+            project_linestring(crs, &mut edge.geometry);
+        }
+        self.crs = *crs;
+        Ok(())
+    }
+}
+
+impl NodeList {
+    /// Transform the node locations from longitude and latitude to `crs`,
+    /// as for `EdgeList::reproject`
+    pub fn reproject(&mut self, crs: &Crs) -> Result<(), Error> {
+        if self.crs == *crs {
+            return Ok(())
+        }
+        check_lon_lat(&self.crs, crs)?;
+        for vertex in self.nodes.iter_mut() {
+            let (x, y) = crs.project(vertex.coordinate.x, vertex.coordinate.y);
+            vertex.coordinate = Coordinate { x, y };
+        }
+        self.crs = *crs;
+        Ok(())
+    }
+}
+
+/// Geometries can only be projected from longitude and latitude, projecting
+/// them again would treat metres as degrees
+fn check_lon_lat(from: &Crs, to: &Crs) -> Result<(), Error> {
+    if from.is_lon_lat() {
+        Ok(())
+    } else {
+        Err(Error::ConversionError(format!(
+            "Can't reproject from EPSG:{} to EPSG:{}, only from longitude and latitude", from.epsg, to.epsg)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edge_list::edge_list_from_xml;
+
+    #[test]
+    fn test_project() {
+        // the CN Tower, in UTM zone 17
+        let crs = Crs::utm_for(-79.387139, 43.642567);
+        assert_eq!(crs.epsg, 32617);
+        let (x, y) = crs.project(-79.387139, 43.642567);
+        assert!((x - 630_084.0).abs() < 1.0 && (y - 4_833_439.0).abs() < 1.0, "{} {}", x, y);
+
+        // the central meridian of MGA zone 56, on the equator
+        let crs: Crs = "EPSG:7856".parse().unwrap();
+        assert_eq!(crs.project(153.0, 0.0), (500_000.0, 10_000_000.0));
+
+        let crs: Crs = "3857".parse().unwrap();
+        let (x, y) = crs.project(180.0, 0.0);
+        assert!((x - 20_037_508.34).abs() < 0.01 && y.abs() < 1e-6);
+        assert!("EPSG:2193".parse::<Crs>().is_err());
+    }
+
+    #[test]
+    fn test_reproject_projected() {
+        let (cache, mut edge_list) = edge_list_from_xml(include_str!("../../examples/basic_roads.osm"));
+        let web_mercator: Crs = "3857".parse().unwrap();
+        edge_list.reproject(&web_mercator).unwrap();
+        let geometries: Vec<_> = edge_list.edges.iter().map(|e| e.geometry.clone()).collect();
+        let mut node_list = NodeList::from_edges(&edge_list, &cache, false);
+
+        // projecting again would treat metres as degrees
+        let utm = edge_list.utm_crs();
+        assert!(edge_list.reproject(&utm).is_err());
+        assert!(node_list.reproject(&utm).is_err());
+        assert_eq!(edge_list.crs, web_mercator);
+        assert!(edge_list.edges.iter().map(|e| &e.geometry).eq(geometries.iter()));
+        // to the same system is a no-op
+        edge_list.reproject(&web_mercator).unwrap();
+        node_list.reproject(&web_mercator).unwrap();
+    }
+}
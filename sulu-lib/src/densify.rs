@@ -0,0 +1,118 @@
+//! Splitting long edges and densifying their geometry
+//!
+//! Accessibility models and snapping work better with short edges and
+//! without long straight segments. Long edges are split into equal pieces
+//! at synthetic nodes, and long segments get evenly spaced extra points.
+
+use geo::{
+    Coordinate,
+    Point,
+    algorithm::{
+        geodesic_distance::GeodesicDistance,
+        geodesic_length::GeodesicLength,
+    },
+};
+use crate::edge_list::EdgeList;
+
+impl EdgeList<f64> {
+    /// Split every edge longer than `max_length_m` into the fewest equal
+    /// pieces no longer than it, at synthetic nodes (see
+    /// `synthetic_node_id`). Returns the number of edges added.
+    pub fn split_long_edges(&mut self, max_length_m: f64) -> usize {
+        if max_length_m <= 0.0 {
+            return 0
+        }
+        let mut added = 0;
+        for mut edge in std::mem::take(&mut self.edges) {
+            let mut pieces = (edge.length_m / max_length_m).ceil() as usize;
+            while pieces > 1 {
+                match edge.split_at(edge.length_m / pieces as f64) {
+                    Some((first, second)) => {
+                        self.edges.push(first);
+                        edge = second;
+                        added += 1;
+                    },
+                    None => break
+                }
+                pieces -= 1;
+            }
+            self.edges.push(edge);
+        }
+        if added > 0 {
+            self.invalidate_ids();
+        }
+        added
+    }
+
+    /// Add points along every segment longer than `max_segment_m`, evenly
+    /// spaced so no segment is longer than it. Lengths are recomputed from
+    /// the new geometry, and elevations need sampling again.
+    pub fn densify(&mut self, max_segment_m: f64) {
+        if max_segment_m <= 0.0 {
+            return
+        }
+        for edge in self.edges.iter_mut() {
+            let coords = &edge.geometry.0;
+            let mut dense: Vec<Coordinate<f64>> = Vec::with_capacity(coords.len());
+            for segment in coords.windows(2) {
+                dense.push(segment[0]);
+                let length = Point::from(segment[0]).geodesic_distance(&Point::from(segment[1]));
+                let pieces = (length / max_segment_m).ceil() as usize;
+                for i in 1..pieces {
+                    let fraction = i as f64 / pieces as f64;
+                    dense.push(Coordinate {
+                        x: segment[0].x + (segment[1].x - segment[0].x) * fraction,
+                        y: segment[0].y + (segment[1].y - segment[0].y) * fraction
+                    });
+                }
+            }
+            if dense.len() + 1 == coords.len() {
+                continue
+            }
+            dense.extend(coords.last());
+            edge.geometry = dense.into();
+            edge.length_m = edge.geometry.geodesic_length();
+            edge.elevation = None;
+            edge.measures = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use osmpbfreader::{
+        NodeId,
+        WayId,
+    };
+    use crate::edge_list::{
+        edge_list_from_xml,
+        synthetic_node_id,
+    };
+
+    #[test]
+    fn test_split_and_densify() {
+        // about 445 m along the equator, then 111 m north
+        let xml = r#"<osm>
+  <node id="1" lat="0.0" lon="0.000"/>
+  <node id="2" lat="0.0" lon="0.004"/>
+  <node id="3" lat="0.001" lon="0.004"/>
+  <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><tag k="highway" v="primary"/></way>
+</osm>"#;
+        let (_, mut edge_list) = edge_list_from_xml(xml);
+        let length = edge_list.edges[0].length_m;
+
+        assert_eq!(edge_list.split_long_edges(200.0), 2);
+        let ends: Vec<_> = edge_list.edges.iter().map(|e| (e.start_node_id, e.end_node_id)).collect();
+        let (a, b) = (synthetic_node_id(WayId(10), length / 3.0), synthetic_node_id(WayId(10), 2.0 * length / 3.0));
+        assert_eq!(ends, vec![(NodeId(1), a), (a, b), (b, NodeId(3))]);
+        for edge in edge_list.edges.iter() {
+            assert!((edge.length_m - length / 3.0).abs() < 0.01);
+        }
+        assert_eq!(edge_list.edges[2].geometry.0.len(), 3);
+
+        edge_list.densify(50.0);
+        let first = &edge_list.edges[0];
+        assert_eq!(first.geometry.0.len(), 5);
+        assert!((first.length_m - length / 3.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,152 @@
+//! Referential integrity checks on an `OSMCache`
+//!
+//! Ways that reference nodes missing from the input (usually because they
+//! cross the boundary of the extract) are broken into pieces when building
+//! edges. This reports which ways were affected and where.
+
+use serde::Serialize;
+use osmpbfreader::{
+    NodeId,
+    WayId,
+};
+use crate::osm_cache::OSMCache;
+
+/// A node where a way stops because the next or previous node is missing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Truncation {
+    pub node_id: NodeId,
+    /// The index of the node in the way
+    pub position: usize,
+    pub lon: f64,
+    pub lat: f64
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WayDiagnostic {
+    pub way_osmid: WayId,
+    pub missing_nodes: Vec<NodeId>,
+    /// The number of segments between consecutive nodes that were dropped
+    pub dropped_segments: usize,
+    pub truncations: Vec<Truncation>
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct IntegrityReport {
+    pub ways: Vec<WayDiagnostic>
+}
+
+impl IntegrityReport {
+    /// Check every way in the network for references to missing nodes
+    pub fn from_cache(osm_cache: &OSMCache) -> Self {
+        let mut ways = vec![];
+        for obj in osm_cache.osm_cache.values() {
+            let way = match obj.way() {
+                Some(way) => way,
+                None => continue
+            };
+            if osm_cache.graph_config.matching_option(&way.tags).is_none() {
+                continue
+            }
+            let present: Vec<bool> = way.nodes.iter()
+                .map(|n| osm_cache.contains_node(*n))
+                .collect();
+            if present.iter().all(|p| *p) {
+                continue
+            }
+            let missing_nodes = way.nodes.iter()
+                .zip(present.iter())
+                .filter(|(_, p)| !**p)
+                .map(|(n, _)| *n)
+                .collect();
+            let dropped_segments = present.windows(2)
+                .filter(|w| !(w[0] && w[1]))
+                .count();
+            let mut truncations = vec![];
+            for (i, nid) in way.nodes.iter().enumerate() {
+                let next_to_gap = (i > 0 && !present[i - 1])
+                    || (i + 1 < present.len() && !present[i + 1]);
+                if !present[i] || !next_to_gap {
+                    continue
+                }
+                if let Some((lon, lat)) = osm_cache.node_coords(*nid) {
+                    truncations.push(Truncation {
+                        node_id: *nid,
+                        position: i,
+                        lon,
+                        lat
+                    });
+                }
+            }
+            ways.push(WayDiagnostic {
+                way_osmid: way.id,
+                missing_nodes,
+                dropped_segments,
+                truncations
+            });
+        }
+        IntegrityReport { ways }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ways.is_empty()
+    }
+
+    pub fn missing_node_count(&self) -> usize {
+        self.ways.iter().map(|w| w.missing_nodes.len()).sum()
+    }
+
+    pub fn dropped_segment_count(&self) -> usize {
+        self.ways.iter().map(|w| w.dropped_segments).sum()
+    }
+}
+
+impl std::fmt::Display for IntegrityReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ways reference {} missing nodes, {} segments dropped",
+               self.ways.len(), self.missing_node_count(), self.dropped_segment_count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edge_list::edge_list_from_xml;
+
+    #[test]
+    fn test_missing_nodes() {
+        // node 3 isn't in the extract, so George Street is cut in two
+        let xml = r#"<osm>
+  <node id="1" lat="0.0" lon="0.000"/>
+  <node id="2" lat="0.0" lon="0.001"/>
+  <node id="4" lat="0.0" lon="0.003"/>
+  <node id="5" lat="0.0" lon="0.004"/>
+  <way id="100">
+    <nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="5"/>
+    <tag k="highway" v="primary"/>
+  </way>
+</osm>"#;
+        let (cache, edge_list) = edge_list_from_xml(xml);
+
+        let report = IntegrityReport::from_cache(&cache);
+        assert_eq!(report.ways.len(), 1);
+        let way = &report.ways[0];
+        assert_eq!(way.missing_nodes, vec![NodeId(3)]);
+        assert_eq!(way.dropped_segments, 2);
+        assert_eq!(way.truncations.iter().map(|t| t.position).collect::<Vec<_>>(), vec![1, 3]);
+
+        let edges: Vec<_> = edge_list.edges.iter()
+            .map(|e| (e.start_node_id.0, e.end_node_id.0, e.boundary))
+            .collect();
+        assert_eq!(edges, vec![(1, 2, true), (4, 5, true)]);
+
+        // edges that aren't truncated don't have the boundary field
+        #[cfg(feature="formats-geojson")]
+        {
+            let (_, edge_list) = edge_list_from_xml(include_str!("../../examples/basic_roads.osm"));
+            for edge in edge_list.edges {
+                let feature: geojson::Feature = edge.into();
+                assert!(!feature.properties.unwrap().contains_key("boundary"));
+            }
+        }
+    }
+}
@@ -1,16 +1,41 @@
-/// Types for representing a graph as an edge list
+//! Types for representing a graph as an edge list
 
-use std::convert::TryFrom;
+use std::{
+    convert::TryFrom,
+    collections::{
+        HashMap,
+        BTreeMap,
+        BTreeSet,
+    },
+};
 use geo::{
     LineString,
     Coordinate,
     CoordinateType,
-    algorithm::geodesic_length::GeodesicLength,
+    Point,
+    algorithm::{
+        geodesic_distance::GeodesicDistance,
+        geodesic_length::GeodesicLength,
+    },
 };
-use osmpbfreader::{OsmId, WayId, NodeId};
+use osmpbfreader::{OsmId, WayId, NodeId, RelationId, Tags};
+use serde::Serialize;
 use crate::{
+    area::find_areas,
+    crs::Crs,
+    elevation::ElevationProfile,
     graph_config::GraphConfigOption,
+    measures::GeometryMeasures,
     osm_cache::OSMCache,
+    osm_info::OsmInfo,
+    travel_time::TravelTimes,
+    progress::{
+        Phase,
+        Progress,
+        Observer,
+        NoProgress,
+        report,
+    },
     error::Error,
 };
 #[cfg(feature="formats-geojson")]
@@ -20,19 +45,482 @@ use geojson;
 #[cfg(feature="formats-gdal")]
 use gdal;
 
-#[derive(Debug)]
+/// The membership of an edge's way in a route relation
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteMembership {
+    pub relation_osmid: RelationId,
+    pub name: Option<String>,
+    pub route_ref: Option<String>,
+    pub network: Option<String>,
+    pub role: String
+}
+
+/// The directions an edge can be travelled in, relative to its geometry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all="kebab-case")]
+pub enum Direction {
+    Both,
+    Forward,
+    Backward
+}
+
+impl Direction {
+    /// Read the `oneway` tag, which roundabouts imply
+    pub fn from_tags(tags: &Tags) -> Self {
+        match Direction::from_oneway_tag(tags, "oneway") {
+            Some(direction) => direction,
+            None if tags.contains("junction", "roundabout") => Direction::Forward,
+            None => Direction::Both
+        }
+    }
+
+    /// Read a tag with `oneway` values, e.g. `oneway:bicycle`, if it's given
+    pub fn from_oneway_tag(tags: &Tags, key: &str) -> Option<Self> {
+        match tags.get(key).map(|v| v.as_str()) {
+            Some("yes") | Some("true") | Some("1") => Some(Direction::Forward),
+            Some("-1") | Some("reverse") => Some(Direction::Backward),
+            Some("no") => Some(Direction::Both),
+            _ => None
+        }
+    }
+
+    pub fn forward(&self) -> bool {
+        *self != Direction::Backward
+    }
+
+    pub fn backward(&self) -> bool {
+        *self != Direction::Forward
+    }
+
+    /// The direction of the edge run from its end node to its start node
+    pub fn reversed(&self) -> Self {
+        match self {
+            Direction::Forward => Direction::Backward,
+            Direction::Backward => Direction::Forward,
+            Direction::Both => Direction::Both
+        }
+    }
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Direction::Both => "both",
+            Direction::Forward => "forward",
+            Direction::Backward => "backward"
+        })
+    }
+}
+
+impl std::str::FromStr for Direction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "both" => Ok(Direction::Both),
+            "forward" => Ok(Direction::Forward),
+            "backward" => Ok(Direction::Backward),
+            _ => Err(Error::ConversionError(format!("Unknown direction: {}", s)))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Edge<T: CoordinateType> {
     pub way_osmid: WayId,
+    /// The ways of the edges merged into this one, after `way_osmid`,
+    /// see `EdgeList::merge_chains`
+    pub merged_way_osmids: Vec<WayId>,
     pub start_node_id: NodeId,
     pub end_node_id: NodeId,
     pub graph_config_option: GraphConfigOption,
     pub length_m: f64,
-    pub geometry: LineString<T>
+    /// The stretches of ways the edge follows, from its start node. More
+    /// than one for merged edges. Synthetic nodes are numbered from them.
+    pub way_spans: Vec<WaySpan>,
+    pub geometry: LineString<T>,
+    pub routes: Vec<RouteMembership>,
+    pub direction: Direction,
+    /// Whether the edge crosses an area rather than following a way
+    pub area: bool,
+    /// Whether the edge ends where its way was truncated by missing nodes,
+    /// e.g. at the boundary of the extract
+    pub boundary: bool,
+    /// The version, timestamp, changeset and user of the way, if recorded
+    pub info: Option<OsmInfo>,
+    /// Contiguous ids for the edge and its ends, see `EdgeList::reindex`
+    pub dense_ids: Option<DenseIds>,
+    /// The connected component of the edge, see `EdgeList::label_components`
+    pub component: Option<usize>,
+    /// The time to travel the edge, if the graph config has `travel-time`
+    pub travel_time: Option<TravelTimes>,
+    /// Elevation and grades from a DEM, see `EdgeList::sample_elevation`
+    pub elevation: Option<ElevationProfile>,
+    /// Bearings and curvature, see `EdgeList::measure_geometry`
+    pub measures: Option<GeometryMeasures>
+}
+
+/// A stretch of a way that an edge follows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WaySpan {
+    pub way_osmid: WayId,
+    /// How far along the way the stretch starts, in metres, not counting
+    /// the gaps around missing nodes
+    pub offset_m: f64,
+    pub length_m: f64,
+    /// Whether the edge runs the same way as the way
+    pub forward: bool
+}
+
+impl WaySpan {
+    /// The span of an edge running the same way as its way
+    pub fn along(way_osmid: WayId, offset_m: f64, length_m: f64) -> Self {
+        WaySpan { way_osmid, offset_m, length_m, forward: true }
+    }
+
+    /// How far along the way a point `distance_m` into the span is
+    pub fn offset_at(&self, distance_m: f64) -> f64 {
+        if self.forward {
+            self.offset_m + distance_m
+        } else {
+            self.offset_m - distance_m
+        }
+    }
+
+    /// The span of the edge run from its end node to its start node
+    pub fn reversed(&self) -> Self {
+        WaySpan {
+            offset_m: self.offset_at(self.length_m),
+            forward: !self.forward,
+            ..*self
+        }
+    }
+}
+
+/// Split spans `distance_m` from their start, returning the spans before
+/// and after the split. The split is in the first span of the second.
+fn split_spans(spans: &[WaySpan], distance_m: f64) -> (Vec<WaySpan>, Vec<WaySpan>) {
+    let mut head = vec![];
+    let mut tail = vec![];
+    let mut travelled = 0.0;
+    for (i, span) in spans.iter().enumerate() {
+        let last = i + 1 == spans.len();
+        if !tail.is_empty() {
+            tail.push(*span);
+        } else if travelled + span.length_m > distance_m || last {
+            let into = (distance_m - travelled).max(0.0).min(span.length_m);
+            head.push(WaySpan { length_m: into, ..*span });
+            tail.push(WaySpan { offset_m: span.offset_at(into), length_m: span.length_m - into, ..*span });
+        } else {
+            head.push(*span);
+        }
+        travelled += span.length_m;
+    }
+    (head, tail)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DenseIds {
+    pub edge_id: usize,
+    pub start_vertex_id: usize,
+    pub end_vertex_id: usize
+}
+
+/// Identifies an edge by the way it comes from and its end nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct EdgeKey {
+    pub way_osmid: WayId,
+    pub start_node_id: NodeId,
+    pub end_node_id: NodeId
+}
+
+impl<T: CoordinateType> Edge<T> {
+    pub fn key(&self) -> EdgeKey {
+        EdgeKey {
+            way_osmid: self.way_osmid,
+            start_node_id: self.start_node_id,
+            end_node_id: self.end_node_id
+        }
+    }
+}
+
+/// Synthetic node ids are at or below this. OSM doesn't use negative ids
+/// for uploaded data, and editors like JOSM number new objects -1, -2, ...,
+/// so the range is far from any id in an input, and reading XML rejects
+/// node ids in it.
+pub const SYNTHETIC_NODE_IDS: i64 = -(1 << 62);
+
+/// The id of a node added to split a way `offset_m` metres along it. The
+/// same split of the same way always gets the same id: -2^62 - (way id *
+/// 10^7 + offset in decimetres), see `SYNTHETIC_NODE_IDS`.
+pub fn synthetic_node_id(way_osmid: WayId, offset_m: f64) -> NodeId {
+    NodeId(SYNTHETIC_NODE_IDS - (way_osmid.0 * 10_000_000 + (offset_m * 10.0).round() as i64))
+}
+
+/// Whether a node id is in the range reserved for synthetic nodes
+pub fn is_synthetic_node_id(id: NodeId) -> bool {
+    id.0 <= SYNTHETIC_NODE_IDS
+}
+
+impl Edge<f64> {
+    /// Split the edge `distance_m` metres from its start, at a synthetic
+    /// node. The point is interpolated in longitude and latitude within
+    /// the segment it falls in. Returns `None` unless the distance is
+    /// strictly inside the edge. The pieces keep the dense ids of the edge
+    /// until the edge list they go into is invalidated (see
+    /// `EdgeList::invalidate_ids`).
+    pub fn split_at(&self, distance_m: f64) -> Option<(Edge<f64>, Edge<f64>)> {
+        if distance_m <= 0.0 || distance_m >= self.length_m {
+            return None
+        }
+        let coords = &self.geometry.0;
+        let mut travelled = 0.0;
+        for (i, segment) in coords.windows(2).enumerate() {
+            let length = Point::from(segment[0]).geodesic_distance(&Point::from(segment[1]));
+            if travelled + length < distance_m {
+                travelled += length;
+                continue
+            }
+            let fraction = if length > 0.0 { (distance_m - travelled) / length } else { 0.0 };
+            let point = Coordinate {
+                x: segment[0].x + (segment[1].x - segment[0].x) * fraction,
+                y: segment[0].y + (segment[1].y - segment[0].y) * fraction
+            };
+            let mut head: Vec<Coordinate<f64>> = coords[..=i].to_vec();
+            let mut tail: Vec<Coordinate<f64>> = coords[i + 1..].to_vec();
+            if point != segment[0] {
+                head.push(point);
+            }
+            if point != segment[1] {
+                tail.insert(0, point);
+            }
+            let (head_spans, tail_spans) = split_spans(&self.way_spans, distance_m);
+            let node_id = match tail_spans.first() {
+                Some(span) => synthetic_node_id(span.way_osmid, span.offset_m),
+                None => synthetic_node_id(self.way_osmid, distance_m)
+            };
+            let mut first = self.clone();
+            let mut second = self.clone();
+            first.geometry = head.into();
+            first.length_m = first.geometry.geodesic_length();
+            first.end_node_id = node_id;
+            first.way_spans = head_spans;
+            second.geometry = tail.into();
+            second.length_m = second.geometry.geodesic_length();
+            second.start_node_id = node_id;
+            second.way_spans = tail_spans;
+            // penalties at nodes along the edge are shared out by length too
+            let total = first.length_m + second.length_m;
+            first.travel_time = self.travel_time.map(|t| t.scaled(first.length_m / total));
+            second.travel_time = self.travel_time.map(|t| t.scaled(second.length_m / total));
+            first.elevation = None;
+            second.elevation = None;
+            first.measures = None;
+            second.measures = None;
+            return Some((first, second))
+        }
+        None
+    }
 }
 
 #[derive(Debug)]
 pub struct EdgeList<T: CoordinateType> {
-    pub edges: Vec<Edge<T>>
+    pub edges: Vec<Edge<T>>,
+    /// The OSM node id of each vertex id, once the list has been reindexed
+    pub node_ids: Option<Vec<NodeId>>,
+    /// The component of each node, once components have been labelled
+    pub node_components: Option<HashMap<NodeId, usize>>,
+    /// The coordinate system of the geometries, see `EdgeList::reproject`
+    pub crs: Crs
+}
+
+/// The edges added, removed and modified when rebuilding part of an `EdgeList`
+#[derive(Debug, Default, Serialize)]
+pub struct EdgeChanges {
+    pub added: Vec<EdgeKey>,
+    pub removed: Vec<EdgeKey>,
+    /// Edges whose geometry, option, routes, direction, travel times or
+    /// way info (e.g. version) changed
+    pub modified: Vec<EdgeKey>,
+    /// Rebuilt ways in the network that reference nodes in neither the
+    /// cache nor the change, so their edges are missing or cut short. See
+    /// `IntegrityReport` for where.
+    pub incomplete_ways: Vec<WayId>
+}
+
+impl EdgeChanges {
+    /// Combine with the changes from a later rebuild, so that e.g. an edge
+    /// added and then modified is reported as added
+    pub fn merge(&mut self, later: EdgeChanges) {
+        for key in later.added {
+            match self.removed.iter().position(|k| *k == key) {
+                Some(i) => { self.removed.remove(i); self.modified.push(key); },
+                None => self.added.push(key)
+            }
+        }
+        for key in later.removed {
+            if let Some(i) = self.added.iter().position(|k| *k == key) {
+                self.added.remove(i);
+                continue
+            }
+            self.modified.retain(|k| *k != key);
+            self.removed.push(key);
+        }
+        for key in later.modified {
+            if !self.added.contains(&key) && !self.modified.contains(&key) {
+                self.modified.push(key);
+            }
+        }
+        for way in later.incomplete_ways {
+            if !self.incomplete_ways.contains(&way) {
+                self.incomplete_ways.push(way);
+            }
+        }
+    }
+}
+
+impl EdgeList<f64> {
+    /// Replace the edges of the given ways with edges rebuilt from the cache.
+    /// Ways that are no longer in the cache lose their edges.
+    pub fn rebuild_ways(
+        &mut self,
+        osm_cache: &OSMCache,
+        ways: &BTreeSet<WayId>
+    ) -> Result<EdgeChanges, Error> {
+        // an area's edges depend on every way of its outline
+        let areas = find_areas(osm_cache);
+        let mut ways = ways.clone();
+        for area in areas.iter() {
+            if area.outline_ways.iter().any(|w| ways.contains(w)) {
+                ways.insert(area.way_osmid);
+            }
+        }
+
+        let (old_edges, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.edges)
+            .into_iter()
+            .partition(|e| ways.contains(&e.way_osmid));
+        self.edges = kept;
+        let mut old_edges: BTreeMap<EdgeKey, Edge<f64>> = old_edges.into_iter()
+            .map(|e| (e.key(), e))
+            .collect();
+
+        let mut changes = EdgeChanges::default();
+        let mut new_edges = vec![];
+        for way in ways.iter() {
+            let osmid = OsmId::Way(*way);
+            if let Some(obj) = osm_cache.osm_cache.get(&osmid) {
+                new_edges.extend(edges_from_osm_id(&osmid, osm_cache)?.unwrap_or_default());
+                let incomplete = obj.way().is_some_and(|w|
+                    osm_cache.graph_config.is_match(&w.tags)
+                    && w.nodes.iter().any(|n| !osm_cache.contains_node(*n)));
+                if incomplete {
+                    changes.incomplete_ways.push(*way);
+                }
+            }
+        }
+        for area in areas.iter().filter(|a| ways.contains(&a.way_osmid)) {
+            new_edges.extend(area.edges(osm_cache)?);
+        }
+
+        let routes = route_memberships(osm_cache);
+        for mut edge in new_edges.into_iter() {
+            edge.routes = routes.get(&edge.way_osmid).cloned().unwrap_or_default();
+            edge.info = osm_cache.way_info.get(&edge.way_osmid).cloned();
+            match old_edges.remove(&edge.key()) {
+                Some(old) => {
+                    if old.geometry != edge.geometry
+                        || old.graph_config_option.name != edge.graph_config_option.name
+                        || old.routes != edge.routes
+                        || old.direction != edge.direction
+                        || old.travel_time != edge.travel_time
+                        || old.info != edge.info {
+                        changes.modified.push(edge.key());
+                    }
+                },
+                None => changes.added.push(edge.key())
+            }
+            self.edges.push(edge);
+        }
+        changes.removed = old_edges.into_keys().collect();
+        self.invalidate_ids();
+        Ok(changes)
+    }
+}
+
+impl<T: CoordinateType> EdgeList<T> {
+    /// Forget the dense ids and component labels, which no longer cover
+    /// every edge once edges are added, removed, split or merged
+    pub fn invalidate_ids(&mut self) {
+        self.node_ids = None;
+        self.node_components = None;
+        for edge in self.edges.iter_mut() {
+            edge.dense_ids = None;
+            edge.component = None;
+        }
+    }
+
+    /// Keep the edges whose way was last edited after `newer_than` and before
+    /// `older_than` (in seconds since the unix epoch). Edges without a
+    /// timestamp are dropped if either bound is given.
+    pub fn retain_by_timestamp(&mut self, newer_than: Option<i64>, older_than: Option<i64>) {
+        if newer_than.is_none() && older_than.is_none() {
+            return
+        }
+        self.edges.retain(|e| match e.info.as_ref().and_then(|i| i.timestamp) {
+            Some(t) => newer_than.is_none_or(|n| t > n) && older_than.is_none_or(|o| t < o),
+            None => false
+        });
+        self.invalidate_ids();
+    }
+}
+
+impl<T: CoordinateType> EdgeList<T> {
+    /// Number the vertices `0..n` in order of node id and the edges `0..m`
+    /// in order of way id, start and end node, sorting the edges into that
+    /// order. The OSM ids are kept. Reindex after any other changes to the
+    /// edges, as they invalidate the ids.
+    pub fn reindex(&mut self) {
+        self.edges.sort_by_key(|e| e.key());
+        let mut node_ids: Vec<NodeId> = self.edges.iter()
+            .flat_map(|e| vec![e.start_node_id, e.end_node_id])
+            .collect();
+        node_ids.sort();
+        node_ids.dedup();
+        for (edge_id, edge) in self.edges.iter_mut().enumerate() {
+            edge.dense_ids = Some(DenseIds {
+                edge_id,
+                start_vertex_id: node_ids.binary_search(&edge.start_node_id).unwrap_or_default(),
+                end_vertex_id: node_ids.binary_search(&edge.end_node_id).unwrap_or_default()
+            });
+        }
+        self.node_ids = Some(node_ids);
+    }
+
+    /// The vertex id of an OSM node, if the list has been reindexed
+    pub fn vertex_id(&self, node_id: NodeId) -> Option<usize> {
+        self.node_ids.as_ref()?.binary_search(&node_id).ok()
+    }
+
+    /// The OSM node of a vertex id
+    pub fn node_id(&self, vertex_id: usize) -> Option<NodeId> {
+        self.node_ids.as_ref()?.get(vertex_id).copied()
+    }
+
+    /// The edge id of an edge, if the list has been reindexed. Edges with
+    /// the same key (e.g. both halves of a loop) get the first id.
+    pub fn edge_id(&self, key: &EdgeKey) -> Option<usize> {
+        self.node_ids.as_ref()?;
+        let i = self.edges.partition_point(|e| e.key() < *key);
+        self.edges.get(i)
+            .filter(|e| e.key() == *key)
+            .and_then(|e| e.dense_ids)
+            .map(|d| d.edge_id)
+    }
+
+    /// The edge with an edge id
+    pub fn edge(&self, edge_id: usize) -> Option<&Edge<T>> {
+        self.edges.get(edge_id).filter(|e| e.dense_ids.map(|d| d.edge_id) == Some(edge_id))
+    }
 }
 
 impl TryFrom<OSMCache> for EdgeList<f64> 
@@ -40,124 +528,199 @@ impl TryFrom<OSMCache> for EdgeList<f64>
     type Error = Error;
 
     fn try_from(osm_cache: OSMCache) -> Result<Self, Self::Error> {
-        let edgelist = osm_cache.osm_cache.keys()
+        EdgeList::try_from(&osm_cache)
+    }
+}
+
+impl TryFrom<&OSMCache> for EdgeList<f64> 
+{
+    type Error = Error;
+
+    fn try_from(osm_cache: &OSMCache) -> Result<Self, Self::Error> {
+        EdgeList::from_cache_with_observer(osm_cache, &mut NoProgress)
+    }
+}
+
+/// How many ways to convert between progress reports
+const WAYS_PER_REPORT: usize = 10_000;
+
+impl EdgeList<f64> {
+    /// Build the edge list as with `try_from`, reporting progress as ways
+    /// are converted
+    pub fn from_cache_with_observer(
+        osm_cache: &OSMCache,
+        observer: &mut dyn Observer
+    ) -> Result<Self, Error> {
+        let way_ids: Vec<&OsmId> = osm_cache.osm_cache.keys()
             .filter(|o| o.is_way())
-            .map(|o| edges_from_osm_id(o, &osm_cache))
-            .collect::<Result<Vec<Option<_>>, _>>()?
-            .into_iter()
-            .filter(|x| x.is_some())
-            .collect::<Option<Vec<_>>>()
-            .map(|v| EdgeList { edges : v.into_iter()
-                .flatten()
-                .collect() })
-            .unwrap_or(EdgeList { edges : vec![] });
+            .collect();
+        let mut progress = Progress::new(Phase::Edges);
+        progress.total_ways = Some(way_ids.len());
+        report(observer, &progress)?;
+        let mut edgelist = EdgeList { edges: vec![], node_ids: None, node_components: None, crs: Crs::default() };
+        for (i, osmid) in way_ids.into_iter().enumerate() {
+            if let Some(edges) = edges_from_osm_id(osmid, osm_cache)? {
+                edgelist.edges.extend(edges);
+            }
+            if (i + 1) % WAYS_PER_REPORT == 0 {
+                progress.ways = i + 1;
+                report(observer, &progress)?;
+            }
+        }
+        for area in find_areas(osm_cache) {
+            edgelist.edges.extend(area.edges(osm_cache)?);
+        }
+        let routes = route_memberships(osm_cache);
+        for edge in edgelist.edges.iter_mut() {
+            edge.routes = routes.get(&edge.way_osmid).cloned().unwrap_or_default();
+            edge.info = osm_cache.way_info.get(&edge.way_osmid).cloned();
+        }
+        progress.ways = progress.total_ways.unwrap_or(0);
+        report(observer, &progress)?;
         Ok(edgelist)
     }
 }
 
+/// The route relations each way is a member of
+fn route_memberships(osm_cache: &OSMCache) -> HashMap<WayId, Vec<RouteMembership>> {
+    let mut routes: HashMap<WayId, Vec<RouteMembership>> = HashMap::new();
+    for obj in osm_cache.osm_cache.values() {
+        let rel = match obj.relation() {
+            // areas and turn restrictions are cached too
+            Some(rel) if osm_cache.graph_config.is_route_match(&rel.tags) => rel,
+            _ => continue
+        };
+        let tag = |key: &str| rel.tags.get(key).map(|v| v.to_string());
+        for member in rel.refs.iter() {
+            if let Some(way_id) = member.member.way() {
+                routes.entry(way_id).or_default().push(RouteMembership {
+                    relation_osmid: rel.id,
+                    name: tag("name"),
+                    route_ref: tag("ref"),
+                    network: tag("network"),
+                    role: member.role.to_string()
+                });
+            }
+        }
+    }
+    routes
+}
+
 fn edges_from_osm_id(
     osmid: &OsmId,
     osm_cache: &OSMCache
 ) -> Result<Option<Vec<Edge<f64>>>, Error> {
     let objs = &osm_cache.osm_cache;
-    let node_count = &osm_cache.node_count;
     let way = match objs.get(osmid)
         .ok_or(Error::MissingInfo(format!("OSM id not in cache: {:?}", osmid)))?
         .way() {
             Some(w) => w,
             None => return Ok(None),
         };
-    let gco = osm_cache.graph_config
-        .matching_option(&way.tags)
-        .ok_or(Error::MissingInfo(format!("Way doesn't match the graph config: {:?}", osmid)))?;
-    let max_edges = way.nodes.len();
-    let mut edges: Vec<Edge<f64>> = Vec::with_capacity(max_edges);
-    let mut points: Vec<Coordinate<f64>> = Vec::with_capacity(max_edges);
-    let mut start: Option<NodeId> = None;
-    let mut end: Option<NodeId> = None;
+    // areas and their outlines are cached without being part of the network
+    let gco = match osm_cache.graph_config.matching_option(&way.tags) {
+        Some(gco) => gco,
+        None => return Ok(None)
+    };
+    // one way streets are one way by car, but perhaps not for the travel mode
+    let direction = match &osm_cache.graph_config.travel_time {
+        Some(tt) => tt.mode.direction(&way.tags),
+        None => Direction::from_tags(&way.tags)
+    };
+    let way_osmid = osmid.way().ok_or(Error::NotAWayId(*osmid))?;
+    let edge = |nodes: &[NodeId], points: &[Coordinate<f64>], boundary: bool, offset_m: f64| {
+        let geom: LineString<f64> = points.to_vec().into();
+        let length_m = geom.geodesic_length();
+        Edge {
+            way_osmid,
+            merged_way_osmids: vec![],
+            start_node_id: nodes[0],
+            end_node_id: nodes[nodes.len() - 1],
+            graph_config_option: gco.clone(),
+            length_m,
+            way_spans: vec![WaySpan::along(way_osmid, offset_m, length_m)],
+            geometry: geom,
+            routes: vec![],
+            direction,
+            area: false,
+            boundary,
+            info: None,
+            dense_ids: None,
+            component: None,
+            travel_time: osm_cache.graph_config.travel_time.as_ref()
+                .map(|tt| tt.travel_times(&gco.name, &way.tags, length_m, nodes, osm_cache)),
+            elevation: None,
+            measures: None
+        }
+    };
+    // A run of nodes becomes one edge, unless it starts and ends at the same
+    // node (a closed way, or a loop through a node the way visits twice).
+    // That is split at its middle node so there are no self-loops.
+    // The offset is the length of the way so far, not counting the gaps
+    // around missing nodes.
+    let push_edges = |edges: &mut Vec<Edge<f64>>, nodes: &[NodeId], points: &[Coordinate<f64>],
+                      start_at_boundary: bool, end_at_boundary: bool, offset_m: &mut f64| {
+        let last = nodes.len() - 1;
+        if nodes[0] == nodes[last] {
+            let mid = nodes.len() / 2;
+            let first = edge(&nodes[..=mid], &points[..=mid], start_at_boundary, *offset_m);
+            *offset_m += first.length_m;
+            edges.push(first);
+            let second = edge(&nodes[mid..], &points[mid..], end_at_boundary, *offset_m);
+            *offset_m += second.length_m;
+            edges.push(second);
+        } else {
+            let edge = edge(nodes, points, start_at_boundary || end_at_boundary, *offset_m);
+            *offset_m += edge.length_m;
+            edges.push(edge);
+        }
+    };
+
+    let mut edges: Vec<Edge<f64>> = Vec::with_capacity(way.nodes.len());
+    let mut nodes: Vec<NodeId> = vec![];
+    let mut points: Vec<Coordinate<f64>> = vec![];
+    // whether the current edge starts just after a missing node
+    let mut start_at_boundary = false;
+    let mut prev_missing = false;
+    let mut offset_m = 0.0;
     for nid in way.nodes.iter() {
-        let node_osmid = nid.clone().into();
-        let count = node_count.get(&node_osmid).unwrap_or(&1);
-        match objs.get(&node_osmid) {
+        match osm_cache.node_coords(*nid) {
             // start or continue edge
-            Some(obj) => {
-                end = Some(*nid);
-                let node = obj.node().ok_or(Error::NotANode(obj.clone()))?;
-                match start {
-                    // continue edge if count is 1, otherwise end edge and start a new one
-                    Some(sid) => {
-                        let coords = Coordinate { x: node.lon().into(), y: node.lat().into() };
-                        points.push(coords);
-                        if (count > &1) && start != end {
-                            points.shrink_to_fit();
-                            let geom: LineString<f64> = points.clone().into();
-                            edges.push( Edge {
-                                way_osmid: osmid.way().ok_or(Error::NotAWayId(*osmid))?,
-                                start_node_id: sid,
-                                end_node_id: end.unwrap_or(nid.clone()),
-                                graph_config_option: gco.clone(),
-                                length_m: geom.geodesic_length(),
-                                geometry: geom
-                            });
-                            points = Vec::with_capacity(max_edges);
-                            points.push(coords);
-                            start = Some(*nid);
-                            end = None;
-                        }
-                    },
-                    // start new edge
-                    None =>  {
-                        points = Vec::with_capacity(max_edges);
-                        let coords = Coordinate { x: node.lon().into(), y: node.lat().into() };
-                        points.push(coords);
-                        start = Some(*nid);
-                        end = None;
-                    }
+            Some((lon, lat)) => {
+                // a node repeated straight after itself adds nothing
+                if nodes.last() == Some(nid) {
+                    continue
+                }
+                if nodes.is_empty() {
+                    start_at_boundary = prev_missing;
                 }
+                let coords = Coordinate { x: lon, y: lat };
+                nodes.push(*nid);
+                points.push(coords);
+                // nodes are counted once for each time a way passes through
+                // them, so a node a way visits twice is a junction too
+                if osm_cache.node_count(*nid) > 1 && nodes.len() > 1 {
+                    push_edges(&mut edges, &nodes, &points, start_at_boundary, false, &mut offset_m);
+                    nodes = vec![*nid];
+                    points = vec![coords];
+                    start_at_boundary = false;
+                }
+                prev_missing = false;
             },
-            // skip or end edge
+            // end edge at the missing node, or skip it
             None => {
-                match start {
-                    // end edge
-                    Some(sid) => {
-                        if points.len() > 1 {
-                            points.shrink_to_fit();
-                            let geom: LineString<f64> = points.clone().into();
-                            edges.push( Edge {
-                                way_osmid: osmid.way().ok_or(Error::NotAWayId(*osmid))?,
-                                start_node_id: sid,
-                                end_node_id: end.unwrap_or(nid.clone()),
-                                graph_config_option: gco.clone(),
-                                length_m: geom.geodesic_length(),
-                                geometry: geom
-                            });
-                        }
-                        points = Vec::with_capacity(max_edges);
-                        start = None;
-                        end = None;
-                    },
-                    // skip
-                    None => ()
+                if nodes.len() > 1 {
+                    push_edges(&mut edges, &nodes, &points, start_at_boundary, true, &mut offset_m);
                 }
+                nodes.clear();
+                points.clear();
+                prev_missing = true;
             }
         }
     }
-    // Finalise the last edge
-    match end {
-        // There's a dangling edge, end it
-        Some(nid) => {
-            let geom: LineString<f64> = points.clone().into();
-            edges.push( Edge {
-                way_osmid: osmid.way().ok_or(Error::NotAWayId(*osmid))?,
-                start_node_id: start.ok_or(Error::MakeGraphError)?,
-                end_node_id: nid,
-                graph_config_option: gco.clone(),
-                length_m: geom.geodesic_length(),
-                geometry: geom
-            });
-        },
-        // There's no dangling edge, continue
-        None => () 
+    // There's a dangling edge, end it
+    if nodes.len() > 1 {
+        push_edges(&mut edges, &nodes, &points, start_at_boundary, false, &mut offset_m);
     }
 
     edges.shrink_to_fit();
@@ -170,14 +733,85 @@ impl From<Edge<f64>> for geojson::Feature {
     fn from(edge: Edge<f64>) -> geojson::Feature {
         let mut props: Map<String, Value> = Map::new();
         props.insert("way_osmid".to_string(), json!(edge.way_osmid));
+        if !edge.merged_way_osmids.is_empty() {
+            let mut way_osmids = vec![edge.way_osmid];
+            way_osmids.extend(edge.merged_way_osmids.iter());
+            props.insert("way_osmids".to_string(), json!(way_osmids));
+        }
         props.insert("start_node_id".to_string(), json!(edge.start_node_id));
         props.insert("end_node_id".to_string(), json!(edge.end_node_id));
+        if let Some(dense_ids) = edge.dense_ids {
+            props.insert("edge_id".to_string(), json!(dense_ids.edge_id));
+            props.insert("start_vertex_id".to_string(), json!(dense_ids.start_vertex_id));
+            props.insert("end_vertex_id".to_string(), json!(dense_ids.end_vertex_id));
+        }
+        if let Some(component) = edge.component {
+            props.insert("component".to_string(), json!(component));
+        }
+        if let Some(travel_time) = edge.travel_time {
+            props.insert("travel_time_forward_s".to_string(), json!(travel_time.forward_s));
+            props.insert("travel_time_backward_s".to_string(), json!(travel_time.backward_s));
+        }
+        if let Some(measures) = edge.measures {
+            let fields = [
+                ("start_bearing_deg", measures.start_bearing_deg),
+                ("end_bearing_deg", measures.end_bearing_deg),
+                ("sinuosity", measures.sinuosity),
+                ("curvature_deg_per_km", measures.curvature_deg_per_km)
+            ];
+            for (name, value) in fields.iter() {
+                if let Some(value) = value {
+                    props.insert(name.to_string(), json!(value));
+                }
+            }
+        }
+        if let Some(elevation) = &edge.elevation {
+            props.insert("elevation_start_m".to_string(), json!(elevation.start_m));
+            props.insert("elevation_end_m".to_string(), json!(elevation.end_m));
+            props.insert("ascent_m".to_string(), json!(elevation.ascent_m));
+            props.insert("descent_m".to_string(), json!(elevation.descent_m));
+            props.insert("avg_grade_forward".to_string(), json!(elevation.avg_grade));
+            props.insert("avg_grade_backward".to_string(), json!(-elevation.avg_grade));
+            props.insert("max_grade_forward".to_string(), json!(elevation.max_grade_forward));
+            props.insert("max_grade_backward".to_string(), json!(elevation.max_grade_backward));
+        }
+        props.insert("direction".to_string(), json!(edge.direction));
         props.insert("graph_config_option".to_string(), json!(edge.graph_config_option.name));
         props.insert("length_m".to_string(), json!(edge.length_m));
+        if edge.area {
+            props.insert("area".to_string(), json!(true));
+        }
+        if edge.boundary {
+            props.insert("boundary".to_string(), json!(true));
+        }
+        if !edge.routes.is_empty() {
+            props.insert("route_osmids".to_string(),
+                         json!(edge.routes.iter().map(|r| r.relation_osmid.0).collect::<Vec<_>>()));
+            props.insert("route_names".to_string(),
+                         json!(edge.routes.iter().map(|r| &r.name).collect::<Vec<_>>()));
+            props.insert("route_refs".to_string(),
+                         json!(edge.routes.iter().map(|r| &r.route_ref).collect::<Vec<_>>()));
+            props.insert("route_networks".to_string(),
+                         json!(edge.routes.iter().map(|r| &r.network).collect::<Vec<_>>()));
+            props.insert("route_roles".to_string(),
+                         json!(edge.routes.iter().map(|r| &r.role).collect::<Vec<_>>()));
+        }
+        if let Some(info) = &edge.info {
+            props.insert("osm_version".to_string(), json!(info.version));
+            props.insert("osm_timestamp".to_string(), json!(info.timestamp_string()));
+            props.insert("osm_changeset".to_string(), json!(info.changeset));
+            props.insert("osm_uid".to_string(), json!(info.uid));
+            props.insert("osm_user".to_string(), json!(info.user));
+        }
+        let value = match edge.elevation.as_ref().and_then(|e| e.z.as_ref()) {
+            Some(z) if z.len() == edge.geometry.0.len() => geojson::Value::LineString(
+                edge.geometry.0.iter().zip(z).map(|(c, z)| vec![c.x, c.y, *z]).collect()),
+            _ => (&edge.geometry).into()
+        };
         let geom = geojson::Geometry {
             bbox: None,
             foreign_members: None,
-            value: (&edge.geometry).into()
+            value
         };
         geojson::Feature {
             bbox: None,
@@ -194,12 +828,69 @@ impl From<EdgeList<f64>> for geojson::FeatureCollection {
     fn from(el: EdgeList<f64>) -> geojson::FeatureCollection {
         geojson::FeatureCollection {
             bbox: None,
-            foreign_members: None,
+            foreign_members: Some(el.crs).filter(|c| *c != Crs::default()).map(|c| c.geojson_member()),
             features: el.edges.into_iter().map(|e| e.into()).collect()
         }
     }
 }
 
+/// Read back the edges of a network written as GeoJSON, with their ids,
+/// graph config option, length, direction and geometry, in order
+#[cfg(feature="formats-geojson")]
+impl TryFrom<geojson::FeatureCollection> for EdgeList<f64> {
+    type Error = Error;
+
+    fn try_from(fc: geojson::FeatureCollection) -> Result<Self, Self::Error> {
+        let crs = Crs::from_geojson_member(fc.foreign_members.as_ref())?;
+        let mut edges = Vec::with_capacity(fc.features.len());
+        for (i, feature) in fc.features.into_iter().enumerate() {
+            let invalid = |what: &str| Error::ConversionError(format!("Feature {} {}", i, what));
+            let props = feature.properties.unwrap_or_default();
+            let id = |key: &str| props.get(key).and_then(|v| v.as_i64())
+                .ok_or_else(|| invalid(&format!("has no {}", key)));
+            let coords = match feature.geometry.map(|g| g.value) {
+                Some(geojson::Value::LineString(coords)) => coords,
+                _ => return Err(invalid("is not a LineString"))
+            };
+            let geometry: LineString<f64> = coords.iter()
+                .filter(|c| c.len() >= 2)
+                .map(|c| Coordinate { x: c[0], y: c[1] })
+                .collect();
+            let length_m = props.get("length_m").and_then(|v| v.as_f64())
+                .unwrap_or_else(|| geometry.geodesic_length());
+            let direction = match props.get("direction").and_then(|v| v.as_str()) {
+                Some(direction) => direction.parse()?,
+                None => Direction::Both
+            };
+            edges.push(Edge {
+                way_osmid: WayId(id("way_osmid")?),
+                merged_way_osmids: vec![],
+                start_node_id: NodeId(id("start_node_id")?),
+                end_node_id: NodeId(id("end_node_id")?),
+                graph_config_option: GraphConfigOption {
+                    name: props.get("graph_config_option").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+                    requires: vec![],
+                    excludes: vec![]
+                },
+                length_m,
+                way_spans: vec![WaySpan::along(WayId(id("way_osmid")?), 0.0, length_m)],
+                geometry,
+                routes: vec![],
+                direction,
+                area: props.get("area").and_then(|v| v.as_bool()).unwrap_or(false),
+                boundary: props.get("boundary").and_then(|v| v.as_bool()).unwrap_or(false),
+                info: None,
+                dense_ids: None,
+                component: None,
+                travel_time: None,
+                elevation: None,
+                measures: None
+            });
+        }
+        Ok(EdgeList { edges, node_ids: None, node_components: None, crs })
+    }
+}
+
 #[cfg(feature="formats-gdal")]
 pub fn linestring_to_gdal(linestring: &LineString<f64>) -> Result<gdal::vector::Geometry, Error> {
     // gdal version 0.6.0 relies on geo-types 0.4.0 (not 0.6.0), but I don't want to change
@@ -212,3 +903,156 @@ pub fn linestring_to_gdal(linestring: &LineString<f64>) -> Result<gdal::vector::
     }
     Ok(geom)
 }
+
+/// A 3D linestring, with `z` for each point
+#[cfg(feature="formats-gdal")]
+pub fn linestring_z_to_gdal(linestring: &LineString<f64>, z: &[f64]) -> Result<gdal::vector::Geometry, Error> {
+    let mut geom = gdal::vector::Geometry::empty(gdal::vector::OGRwkbGeometryType::wkbLineString25D)
+        .map_err(Error::GdalError)?;
+    for (i, (pt, z)) in linestring.points_iter().zip(z).enumerate() {
+        geom.set_point(i, (pt.x(), pt.y(), *z));
+    }
+    Ok(geom)
+}
+
+/// The graph config of the examples, for tests
+#[cfg(test)]
+pub(crate) fn basic_roads_config() -> crate::graph_config::GraphConfig {
+    serde_json::from_str(include_str!("../../examples/basic_roads.json")).unwrap()
+}
+
+/// Load `xml` into a cache with the examples' graph config and build its
+/// edges, for tests
+#[cfg(test)]
+pub(crate) fn edge_list_from_xml(xml: &str) -> (OSMCache, EdgeList<f64>) {
+    edge_list_from_xml_with_config(basic_roads_config(), xml)
+}
+
+/// Load `xml` into a cache with `gc` and build its edges, for tests
+#[cfg(test)]
+pub(crate) fn edge_list_from_xml_with_config(gc: crate::graph_config::GraphConfig, xml: &str) -> (OSMCache, EdgeList<f64>) {
+    let mut cache = OSMCache::new(gc);
+    cache.load_xml(std::io::Cursor::new(xml)).unwrap();
+    let edge_list = EdgeList::try_from(&cache).unwrap();
+    (cache, edge_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph_config::GraphConfig;
+
+    const BASIC_ROADS_OSM: &str = include_str!("../../examples/basic_roads.osm");
+    const RINGS_OSM: &str = include_str!("../../examples/rings.osm");
+
+    fn way_edges(edge_list: &EdgeList<f64>, way_id: i64) -> Vec<(i64, i64, usize)> {
+        edge_list.edges.iter()
+            .filter(|e| e.way_osmid == WayId(way_id))
+            .map(|e| (e.start_node_id.0, e.end_node_id.0, e.geometry.0.len()))
+            .collect()
+    }
+
+    fn rings_edge_list() -> EdgeList<f64> {
+        edge_list_from_xml(RINGS_OSM).1
+    }
+
+    #[test]
+    fn test_route_memberships() {
+        let gc: GraphConfig = serde_json::from_str(r#"{
+            "name": "roads",
+            "options": [
+                {"name": "road", "requires": [{"key": "highway", "kind": "all"}], "excludes": []}
+            ],
+            "routes": [
+                {"name": "bus", "requires": [{"key": "route", "kind": {"exact": "bus"}}], "excludes": []}
+            ]
+        }"#).unwrap();
+        let (_, edge_list) = edge_list_from_xml_with_config(gc, BASIC_ROADS_OSM);
+
+        for edge in edge_list.edges.iter() {
+            match edge.way_osmid.0 {
+                100 | 101 => {
+                    assert_eq!(edge.routes.len(), 1);
+                    let route = &edge.routes[0];
+                    assert_eq!(route.relation_osmid, RelationId(1000));
+                    assert_eq!(route.route_ref.as_deref(), Some("333"));
+                    assert_eq!(route.network.as_deref(), Some("Sydney Buses"));
+                    let role = if edge.way_osmid.0 == 100 { "" } else { "forward" };
+                    assert_eq!(route.role, role);
+                },
+                _ => assert!(edge.routes.is_empty())
+            }
+        }
+
+        // only edges on a route get the route fields
+        #[cfg(feature="formats-geojson")]
+        for edge in edge_list.edges.iter() {
+            let on_route = !edge.routes.is_empty();
+            let feature: geojson::Feature = edge.clone().into();
+            assert_eq!(feature.properties.unwrap().contains_key("route_osmids"), on_route);
+        }
+    }
+
+    #[test]
+    fn test_retain_by_timestamp() {
+        let (_, mut edge_list) = edge_list_from_xml(BASIC_ROADS_OSM);
+        let info = edge_list.edges[0].info.as_ref().unwrap();
+        assert_eq!(info.changeset, Some(105623411));
+        assert_eq!(info.user.as_deref(), Some("alice"));
+
+        // King Street hasn't been edited since 2014
+        let cutoff = crate::osm_info::parse_timestamp("2015-01-01").unwrap();
+        edge_list.retain_by_timestamp(None, Some(cutoff));
+        assert_eq!(edge_list.edges.len(), 2);
+        assert!(edge_list.edges.iter().all(|e| e.way_osmid == WayId(101)));
+        edge_list.retain_by_timestamp(Some(cutoff), None);
+        assert!(edge_list.edges.is_empty());
+    }
+
+    #[test]
+    fn test_reindex() {
+        let (_, mut edge_list) = edge_list_from_xml(BASIC_ROADS_OSM);
+        assert_eq!(edge_list.vertex_id(NodeId(1)), None);
+        edge_list.reindex();
+
+        // nodes 1, 3, 4, 5 and 7 become vertices 0 to 4
+        let ids: Vec<_> = edge_list.edges.iter()
+            .map(|e| e.dense_ids.map(|d| (d.edge_id, d.start_vertex_id, d.end_vertex_id)).unwrap())
+            .collect();
+        assert_eq!(ids, vec![(0, 0, 1), (1, 1, 2), (2, 1, 4), (3, 3, 1)]);
+        assert_eq!(edge_list.vertex_id(NodeId(5)), Some(3));
+        assert_eq!(edge_list.node_id(4), Some(NodeId(7)));
+        assert_eq!(edge_list.node_id(5), None);
+        let key = EdgeKey { way_osmid: WayId(101), start_node_id: NodeId(3), end_node_id: NodeId(7) };
+        assert_eq!(edge_list.edge_id(&key), Some(2));
+        assert_eq!(edge_list.edge(2).unwrap().key(), key);
+    }
+
+    #[test]
+    fn test_closed_ways() {
+        let edge_list = rings_edge_list();
+        // a ring on its own is split in two at its middle node
+        assert_eq!(way_edges(&edge_list, 200), vec![(10, 12, 3), (12, 10, 3)]);
+        assert!(edge_list.edges.iter().all(|e| e.start_node_id != e.end_node_id));
+        // so is a ring whose only junction is where it starts and ends
+        assert_eq!(way_edges(&edge_list, 201), vec![(21, 23, 3), (23, 21, 3)]);
+        // a ring with a junction part way round is split there
+        assert_eq!(way_edges(&edge_list, 205), vec![(50, 52, 3), (52, 50, 3)]);
+        let length: f64 = edge_list.edges.iter()
+            .filter(|e| e.way_osmid == WayId(200))
+            .map(|e| e.length_m)
+            .sum();
+        assert!((length - 203.4).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_repeated_nodes() {
+        let edge_list = rings_edge_list();
+        // a node the way passes through twice is a junction, and the loop
+        // between the two visits is split in two
+        assert_eq!(way_edges(&edge_list, 203),
+                   vec![(30, 31, 2), (31, 33, 3), (33, 31, 2), (31, 34, 2)]);
+        // a node repeated straight after itself is dropped
+        assert_eq!(way_edges(&edge_list, 204), vec![(40, 42, 3)]);
+    }
+}
@@ -0,0 +1,269 @@
+//! Elevation and gradient along edges, sampled from a DEM
+//!
+//! A digital elevation model is read (through GDAL) into a `Raster`, which
+//! is sampled with bilinear interpolation at the points of each edge and at
+//! regular steps between them. Any raster can be used as long as its
+//! coordinate system is one `Crs` supports.
+
+use geo::{
+    Coordinate,
+    Point,
+    algorithm::geodesic_distance::GeodesicDistance,
+};
+use crate::{
+    crs::Crs,
+    edge_list::{
+        Edge,
+        EdgeList,
+    },
+};
+#[cfg(feature="formats-gdal")]
+use crate::error::Error;
+
+/// Something that knows the elevation of a longitude and latitude
+pub trait ElevationSource {
+    /// The elevation in metres, if known
+    fn elevation(&self, lon: f64, lat: f64) -> Option<f64>;
+}
+
+/// A grid of elevations, as read from a GeoTIFF
+#[derive(Debug, Clone)]
+pub struct Raster {
+    pub width: usize,
+    pub height: usize,
+    /// The GDAL geotransform, mapping pixel positions to coordinates in `crs`
+    pub geo_transform: [f64; 6],
+    /// The elevations, row by row from the top
+    pub data: Vec<f64>,
+    pub no_data: Option<f64>,
+    pub crs: Crs
+}
+
+impl Raster {
+    fn value(&self, col: usize, row: usize) -> Option<f64> {
+        let value = *self.data.get(row * self.width + col)?;
+        if Some(value) == self.no_data || value.is_nan() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    /// Read the first band of a raster, e.g. a GeoTIFF DEM
+    #[cfg(feature="formats-gdal")]
+    pub fn from_gdal<P: AsRef<std::path::Path>>(path: P) -> Result<Self, Error> {
+        let dataset = gdal::Dataset::open(path.as_ref()).map_err(Error::GdalError)?;
+        let geo_transform = dataset.geo_transform().map_err(Error::GdalError)?;
+        let crs = match dataset.spatial_ref().and_then(|srs| srs.auth_code()) {
+            Ok(code) => Crs::from_epsg(code as u32)?,
+            Err(_) => Crs::default()
+        };
+        let band = dataset.rasterband(1).map_err(Error::GdalError)?;
+        let no_data = band.no_data_value();
+        let buffer = band.read_band_as::<f64>().map_err(Error::GdalError)?;
+        Ok(Raster {
+            width: buffer.size.0,
+            height: buffer.size.1,
+            geo_transform,
+            data: buffer.data,
+            no_data,
+            crs
+        })
+    }
+}
+
+impl ElevationSource for Raster {
+    /// Bilinear interpolation between the centres of the four nearest pixels
+    fn elevation(&self, lon: f64, lat: f64) -> Option<f64> {
+        let (x, y) = self.crs.project(lon, lat);
+        let gt = &self.geo_transform;
+        // invert the geotransform to get the pixel position
+        let det = gt[1] * gt[5] - gt[2] * gt[4];
+        if det == 0.0 {
+            return None
+        }
+        let (dx, dy) = (x - gt[0], y - gt[3]);
+        let col = (gt[5] * dx - gt[2] * dy) / det - 0.5;
+        let row = (gt[1] * dy - gt[4] * dx) / det - 0.5;
+        if col < -0.5 || row < -0.5 || col > self.width as f64 - 0.5 || row > self.height as f64 - 0.5 {
+            return None
+        }
+        let clamp = |v: f64, max: usize| (v.max(0.0) as usize).min(max - 1);
+        let (c0, r0) = (clamp(col.floor(), self.width), clamp(row.floor(), self.height));
+        let (c1, r1) = (clamp(col.floor() + 1.0, self.width), clamp(row.floor() + 1.0, self.height));
+        let (fc, fr) = ((col - col.floor()).clamp(0.0, 1.0), (row - row.floor()).clamp(0.0, 1.0));
+        let top = self.value(c0, r0)? * (1.0 - fc) + self.value(c1, r0)? * fc;
+        let bottom = self.value(c0, r1)? * (1.0 - fc) + self.value(c1, r1)? * fc;
+        Some(top * (1.0 - fr) + bottom * fr)
+    }
+}
+
+/// The elevation of an edge and how steep it is. Grades are fractions
+/// (0.05 is 5%), and forward is from the start node to the end node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElevationProfile {
+    pub start_m: f64,
+    pub end_m: f64,
+    /// The total climb going forward, which is the descent going backward
+    pub ascent_m: f64,
+    /// The total drop going forward, which is the ascent going backward
+    pub descent_m: f64,
+    /// The change in elevation over the length going forward; going
+    /// backward it is the negative of this
+    pub avg_grade: f64,
+    /// The steepest climb between samples going forward
+    pub max_grade_forward: f64,
+    /// The steepest climb between samples going backward
+    pub max_grade_backward: f64,
+    /// The elevation of each point of the geometry, if requested, for 3D output
+    pub z: Option<Vec<f64>>
+}
+
+impl ElevationProfile {
+    /// The profile of the edge travelled the other way
+    pub fn reversed(&self) -> Self {
+        ElevationProfile {
+            start_m: self.end_m,
+            end_m: self.start_m,
+            ascent_m: self.descent_m,
+            descent_m: self.ascent_m,
+            avg_grade: -self.avg_grade,
+            max_grade_forward: self.max_grade_backward,
+            max_grade_backward: self.max_grade_forward,
+            z: self.z.as_ref().map(|z| z.iter().rev().copied().collect())
+        }
+    }
+
+    /// The profile of an edge of `length_m` followed by `next`, of
+    /// `next_length_m`
+    pub fn then(&self, length_m: f64, next: &ElevationProfile, next_length_m: f64) -> Self {
+        let total_m = length_m + next_length_m;
+        ElevationProfile {
+            start_m: self.start_m,
+            end_m: next.end_m,
+            ascent_m: self.ascent_m + next.ascent_m,
+            descent_m: self.descent_m + next.descent_m,
+            avg_grade: if total_m > 0.0 { (next.end_m - self.start_m) / total_m } else { 0.0 },
+            max_grade_forward: self.max_grade_forward.max(next.max_grade_forward),
+            max_grade_backward: self.max_grade_backward.max(next.max_grade_backward),
+            z: match (&self.z, &next.z) {
+                (Some(a), Some(b)) => Some(a.iter().chain(b.iter().skip(1)).copied().collect()),
+                _ => None
+            }
+        }
+    }
+}
+
+fn interpolate(a: Coordinate<f64>, b: Coordinate<f64>, fraction: f64) -> Coordinate<f64> {
+    Coordinate { x: a.x + (b.x - a.x) * fraction, y: a.y + (b.y - a.y) * fraction }
+}
+
+/// Sample the elevation along an edge, at its points and every
+/// `spacing_m` between them. `None` if an end of the edge is off the DEM.
+fn profile(edge: &Edge<f64>, source: &dyn ElevationSource, spacing_m: f64, with_z: bool) -> Option<ElevationProfile> {
+    let coords = &edge.geometry.0;
+    let elevation = |c: Coordinate<f64>| source.elevation(c.x, c.y);
+    let (first, last) = (*coords.first()?, *coords.last()?);
+    let (start_m, end_m) = (elevation(first)?, elevation(last)?);
+
+    // (distance along the edge, elevation) for every sample that has one
+    let mut samples: Vec<(f64, f64)> = vec![(0.0, start_m)];
+    let mut travelled = 0.0;
+    for segment in coords.windows(2) {
+        let length = Point::from(segment[0]).geodesic_distance(&Point::from(segment[1]));
+        let steps = if spacing_m > 0.0 { (length / spacing_m).ceil().max(1.0) as usize } else { 1 };
+        for i in 1..=steps {
+            let fraction = i as f64 / steps as f64;
+            if let Some(z) = elevation(interpolate(segment[0], segment[1], fraction)) {
+                samples.push((travelled + length * fraction, z));
+            }
+        }
+        travelled += length;
+    }
+
+    let (mut ascent_m, mut descent_m) = (0.0, 0.0);
+    let (mut max_grade_forward, mut max_grade_backward) = (0.0_f64, 0.0_f64);
+    for pair in samples.windows(2) {
+        let (run, rise) = (pair[1].0 - pair[0].0, pair[1].1 - pair[0].1);
+        if rise > 0.0 {
+            ascent_m += rise;
+        } else {
+            descent_m -= rise;
+        }
+        if run > 0.0 {
+            max_grade_forward = max_grade_forward.max(rise / run);
+            max_grade_backward = max_grade_backward.max(-rise / run);
+        }
+    }
+    let z = if with_z {
+        // points off the DEM take the elevation of the previous point
+        let mut previous = start_m;
+        Some(coords.iter().map(|c| {
+            previous = elevation(*c).unwrap_or(previous);
+            previous
+        }).collect())
+    } else {
+        None
+    };
+    Some(ElevationProfile {
+        start_m,
+        end_m,
+        ascent_m,
+        descent_m,
+        avg_grade: if travelled > 0.0 { (end_m - start_m) / travelled } else { 0.0 },
+        max_grade_forward,
+        max_grade_backward,
+        z
+    })
+}
+
+impl EdgeList<f64> {
+    /// Sample elevations for every edge, from geometries in longitude and
+    /// latitude, so before `reproject`. With `with_z` the elevation of each
+    /// point is kept for 3D output. Merging chains keeps the profiles, but
+    /// splitting, densifying or simplifying afterwards drops them.
+    pub fn sample_elevation(&mut self, source: &dyn ElevationSource, spacing_m: f64, with_z: bool) {
+        for edge in self.edges.iter_mut() {
+            edge.elevation = profile(edge, source, spacing_m, with_z);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edge_list::edge_list_from_xml;
+
+    #[test]
+    fn test_sample_elevation() {
+        // a hill rising 1 m for every 0.0001 degrees east, peaking at 0.00105
+        let geo_transform = [0.0, 0.0001, 0.0, 0.0005, 0.0, -0.0001];
+        let data = (0..10).flat_map(|_| (0..30).map(|col| {
+            let lon = (col as f64 + 0.5) * 0.0001;
+            10.0 - ((lon - 0.00105) / 0.0001).abs()
+        })).collect();
+        let raster = Raster { width: 30, height: 10, geo_transform, data, no_data: None, crs: Crs::default() };
+        assert!((raster.elevation(0.0002, 0.0002).unwrap() - 1.5).abs() < 1e-9);
+        assert_eq!(raster.elevation(0.004, 0.0), None);
+
+        let xml = r#"<osm>
+  <node id="1" lat="0.0" lon="0.00005"/>
+  <node id="2" lat="0.0" lon="0.00205"/>
+  <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+</osm>"#;
+        let (_, mut edge_list) = edge_list_from_xml(xml);
+        // 12 samples, one of them on the top
+        edge_list.sample_elevation(&raster, 20.0, true);
+
+        // up 10 m to the top of the hill and down the other side
+        let profile = edge_list.edges[0].elevation.as_ref().unwrap();
+        assert!(profile.start_m.abs() < 1e-6 && profile.end_m.abs() < 1e-6);
+        assert!((profile.ascent_m - 10.0).abs() < 0.1, "{:?}", profile);
+        assert!((profile.descent_m - 10.0).abs() < 0.1);
+        assert!(profile.avg_grade.abs() < 1e-6);
+        // 1 m every 11.1 m
+        assert!((profile.max_grade_forward - 0.09).abs() < 0.001, "{:?}", profile);
+        assert!((profile.max_grade_backward - 0.09).abs() < 0.001);
+        assert_eq!(profile.z.as_ref().unwrap().len(), 2);
+    }
+}
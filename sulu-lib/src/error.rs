@@ -3,6 +3,7 @@
 #[derive(Debug)]
 pub enum Error {
     PbfError(osmpbfreader::error::Error),
+    XmlError(quick_xml::Error),
     IoError(std::io::Error),
     MissingInfo(String),
     NotANode(osmpbfreader::objects::OsmObj),
@@ -11,7 +12,10 @@ pub enum Error {
     #[cfg(feature="formats-gdal")]
     GdalError(gdal::errors::GdalError),
     NodeCountError,
-    MakeGraphError
+    MakeGraphError,
+    SnapshotError(String),
+    StaleSnapshot(String),
+    Cancelled
 }
 
 impl std::fmt::Display for Error {
@@ -21,6 +25,8 @@ impl std::fmt::Display for Error {
                 write!(f, "IoError: {}", e),
             Error::PbfError(e) =>
                 write!(f, "PbfError: {}", e),
+            Error::XmlError(e) =>
+                write!(f, "XmlError: {}", e),
             Error::MissingInfo(s) => 
                 write!(f, "MissingInfo: {}", s),
             Error::NotANode(o) =>
@@ -35,7 +41,13 @@ impl std::fmt::Display for Error {
             Error::NodeCountError => 
                 write!(f, "Error counting nodes"),
             Error::MakeGraphError =>
-                write!(f, "Error making graph")
+                write!(f, "Error making graph"),
+            Error::SnapshotError(s) =>
+                write!(f, "SnapshotError: {}", s),
+            Error::StaleSnapshot(s) =>
+                write!(f, "Snapshot is out of date: {}", s),
+            Error::Cancelled =>
+                write!(f, "Cancelled")
         }
     }
 }
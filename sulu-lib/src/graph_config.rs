@@ -1,10 +1,11 @@
+use std::collections::BTreeMap;
 use serde::{Serialize, Deserialize};
 use serde_json;
 use osmpbfreader::objects::Tags;
 use crate::matcher::{Matcher};
 
-#[serde(rename_all="kebab-case")]
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all="kebab-case")]
 pub struct GraphConfigOption {
     pub name: String,
     pub requires: Vec<Matcher>,
@@ -28,11 +29,144 @@ impl GraphConfigOption {
     }
 }
 
+/// Which ways split the network's edges at the nodes they share with them
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all="kebab-case")]
+pub enum SplitAt {
+    /// Only the ways in the network
+    #[default]
+    Selected,
+    /// Every way in the input, so networks built with different configs
+    /// share their junctions
+    AllWays,
+    /// The ways in the network and the ways matching any of these options
+    Matching(Vec<GraphConfigOption>)
+}
+
+/// A property of an edge that must match for chains to be merged
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all="kebab-case")]
+pub enum EdgeAttribute {
+    GraphConfigOption,
+    Routes,
+    Area
+}
+
+/// A measure of the shape of an edge, written as an output field
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all="kebab-case")]
+pub enum GeometryMeasure {
+    /// `start_bearing_deg`, the bearing the edge leaves its start node on
+    StartBearing,
+    /// `end_bearing_deg`, the bearing the edge arrives at its end node on
+    EndBearing,
+    /// `sinuosity`, the length over the distance between the end nodes
+    Sinuosity,
+    /// `curvature_deg_per_km`, the total change of bearing along the edge
+    /// per kilometre
+    Curvature
+}
+
+/// When consecutive edges that meet at a node joining only them are merged
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all="kebab-case")]
+pub struct MergeChains {
+    #[serde(default)]
+    pub attributes: Vec<EdgeAttribute>,
+    /// Keys of way tags whose values must match, e.g. `name`
+    #[serde(default)]
+    pub tags: Vec<String>
+}
+
+/// How a network is travelled, for its default speed and whether speed limits apply
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all="kebab-case")]
+pub enum TravelMode {
+    /// 50 km/h, using `maxspeed` where it's tagged
+    #[default]
+    Car,
+    /// 15 km/h
+    Bike,
+    /// 5 km/h
+    Walk
+}
+
+impl TravelMode {
+    pub fn default_speed_kmh(&self) -> f64 {
+        match self {
+            TravelMode::Car => 50.0,
+            TravelMode::Bike => 15.0,
+            TravelMode::Walk => 5.0
+        }
+    }
+}
+
+/// Multiplies the speed on ways with a tag, e.g. 0.5 for `surface=gravel`
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all="kebab-case")]
+pub struct SpeedFactor {
+    pub key: String,
+    pub value: String,
+    pub factor: f64
+}
+
+/// Multiplies the speed going up or down ways tagged with `incline`
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all="kebab-case")]
+pub struct InclineFactors {
+    pub uphill: f64,
+    pub downhill: f64
+}
+
+/// How to compute the travel time of edges in each direction
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all="kebab-case")]
+pub struct TravelTime {
+    #[serde(default)]
+    pub mode: TravelMode,
+    /// The speed where nothing else applies, otherwise the mode's speed
+    #[serde(default)]
+    pub default_speed_kmh: Option<f64>,
+    /// Speeds for the edges of graph config options, by option name
+    #[serde(default)]
+    pub option_speeds_kmh: BTreeMap<String, f64>,
+    #[serde(default)]
+    pub factors: Vec<SpeedFactor>,
+    #[serde(default)]
+    pub incline: Option<InclineFactors>,
+    /// Seconds added for each `highway=traffic_signals` node along an edge
+    #[serde(default)]
+    pub signal_penalty_s: f64,
+    /// Seconds added for each `highway=crossing` node along an edge
+    #[serde(default)]
+    pub crossing_penalty_s: f64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all="kebab-case")]
 pub struct GraphConfig {
     pub name: String,
-    pub options: Vec<GraphConfigOption>
+    pub options: Vec<GraphConfigOption>,
+    /// Route relations whose membership is attached to the edges of their ways
+    #[serde(default)]
+    pub routes: Vec<GraphConfigOption>,
+    /// Closed ways and multipolygons that are crossed by edges between their entry points
+    #[serde(default)]
+    pub areas: Vec<GraphConfigOption>,
+    #[serde(default)]
+    pub split_at: SplitAt,
+    /// Merge chains of edges through nodes of degree 2, if given
+    #[serde(default)]
+    pub merge_chains: Option<MergeChains>,
+    /// Compute the travel time of edges, if given
+    #[serde(default)]
+    pub travel_time: Option<TravelTime>,
+    /// The measures of edge geometry to output
+    #[serde(default)]
+    pub geometry_measures: Vec<GeometryMeasure>,
+    /// Cache `type=restriction` relations, for turn movements
+    #[serde(default)]
+    pub turn_restrictions: bool
 }
 
 impl GraphConfig {
@@ -55,6 +189,40 @@ impl GraphConfig {
         false
     }
 
+    /// Whether a relation's tags match any of the route options
+    pub fn is_route_match(&self, tags: &Tags) -> bool {
+        self.routes.iter().any(|opt| opt.check_match(tags))
+    }
+
+    pub fn matching_area_option(&self, tags: &Tags) -> Option<GraphConfigOption> {
+        self.areas.iter().find(|opt| opt.check_match(tags)).cloned()
+    }
+
+    /// Whether a way should be cached, either as part of the network or as an area
+    pub fn is_way_match(&self, tags: &Tags) -> bool {
+        self.is_match(tags) || self.areas.iter().any(|opt| opt.check_match(tags))
+    }
+
+    /// Whether a relation should be cached: a route, a multipolygon area or
+    /// a turn restriction, if `turn_restrictions` is set
+    pub fn is_relation_match(&self, tags: &Tags) -> bool {
+        self.is_route_match(tags)
+            || (self.turn_restrictions && tags.contains("type", "restriction"))
+            || (
+            tags.contains("type", "multipolygon")
+            && self.areas.iter().any(|opt| opt.check_match(tags))
+        )
+    }
+
+    /// Whether a way outside the network should still split its edges
+    pub fn is_split_match(&self, tags: &Tags) -> bool {
+        match &self.split_at {
+            SplitAt::Selected => false,
+            SplitAt::AllWays => true,
+            SplitAt::Matching(opts) => opts.iter().any(|opt| opt.check_match(tags))
+        }
+    }
+
     /// Make a graph config from a file of json
     pub fn from_path<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
         let data = std::fs::read_to_string(path)?;
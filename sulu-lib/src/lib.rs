@@ -2,4 +2,24 @@ pub mod matcher;
 pub mod graph_config;
 pub mod error;
 pub mod edge_list;
+pub mod node_list;
+pub mod chains;
+pub mod components;
+pub mod parallel;
+pub mod densify;
+pub mod simplify;
+pub mod crs;
+pub mod travel_time;
+pub mod elevation;
+pub mod turns;
+pub mod measures;
+pub mod routing;
+pub mod area;
 pub mod osm_cache;
+pub mod osm_info;
+pub mod node_store;
+pub mod osm_xml;
+pub mod osm_change;
+pub mod diagnostics;
+pub mod snapshot;
+pub mod progress;
@@ -1,16 +1,16 @@
 use serde::{Serialize, Deserialize};
 use smartstring::alias::String;
 
-#[serde(rename_all="kebab-case")]
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all="kebab-case")]
 pub enum MatchKind {
     InList(Vec<String>),
     Exact(String),
     All
 }
 
-#[serde(rename_all="kebab-case")]
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all="kebab-case")]
 pub struct Matcher {
     pub key: String,
     pub kind: MatchKind
@@ -39,25 +39,16 @@ mod tests {
         let s = "{\"key\": \"highway\", \"kind\": \"all\"}";
         let m: Matcher = serde_json::from_str(s).unwrap();
         assert_eq!(m.key.as_str(), "highway");
-        assert!(match m.kind {
-            MatchKind::All => true,
-            _ => false
-        });
+        assert!(matches!(m.kind, MatchKind::All));
 
         let s = "{\"key\": \"highway\", \"kind\": {\"exact\": \"primary\"}}";
         let m: Matcher = serde_json::from_str(s).unwrap();
         assert_eq!(m.key.as_str(), "highway");
-        assert!(match m.kind {
-            MatchKind::Exact(v) => v.as_str() == "primary",
-            _ => false
-        });
+        assert!(matches!(m.kind, MatchKind::Exact(v) if v.as_str() == "primary"));
 
         let s = "{\"key\": \"highway\", \"kind\": {\"in-list\": [\"primary\", \"secondary\"]}}";
         let m: Matcher = serde_json::from_str(s).unwrap();
         assert_eq!(m.key.as_str(), "highway");
-        assert!(match m.kind {
-            MatchKind::InList(v) => v.contains(&"primary".into()),
-            _ => false
-        });
+        assert!(matches!(m.kind, MatchKind::InList(v) if v.contains(&"primary".into())));
     }
 }
@@ -0,0 +1,161 @@
+//! Measures of the shape of edges, from the `geometry-measures` section of
+//! the graph config
+//!
+//! Bearings are the azimuths of geodesics on the WGS84 ellipsoid, 0 to 360
+//! clockwise from north, and lengths and distances are geodesic too, all from
+//! the longitude and latitude geometry of the edge as it will be written.
+
+use geo::{
+    Coordinate,
+    Point,
+    algorithm::{
+        geodesic_distance::GeodesicDistance,
+        geodesic_length::GeodesicLength,
+    },
+};
+use crate::{
+    edge_list::{
+        Edge,
+        EdgeList,
+    },
+    graph_config::GeometryMeasure,
+    turns::azimuths,
+};
+
+/// The selected measures of an edge, `None` where not selected or where
+/// the geometry has no length
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GeometryMeasures {
+    pub start_bearing_deg: Option<f64>,
+    pub end_bearing_deg: Option<f64>,
+    /// The length along the edge over the distance between its ends, 1 for
+    /// a straight edge
+    pub sinuosity: Option<f64>,
+    /// The sum of the changes in bearing at each point, left or right, in
+    /// degrees per kilometre
+    pub curvature_deg_per_km: Option<f64>
+}
+
+/// The change of bearing at `b`, coming from `a` and going on to `c`,
+/// 0 to 180 degrees
+fn bearing_change(a: Coordinate<f64>, b: Coordinate<f64>, c: Coordinate<f64>) -> f64 {
+    let arriving = azimuths(a, b).1;
+    let leaving = azimuths(b, c).0;
+    ((leaving - arriving + 180.0).rem_euclid(360.0) - 180.0).abs()
+}
+
+impl GeometryMeasures {
+    pub fn of(edge: &Edge<f64>, selected: &[GeometryMeasure]) -> Self {
+        let mut measures = GeometryMeasures::default();
+        let coords = &edge.geometry.0;
+        let length_m = edge.geometry.geodesic_length();
+        for measure in selected {
+            match measure {
+                GeometryMeasure::StartBearing => measures.start_bearing_deg = edge.start_bearing(),
+                GeometryMeasure::EndBearing => measures.end_bearing_deg = edge.end_bearing(),
+                GeometryMeasure::Sinuosity => {
+                    let distance_m = match (coords.first(), coords.last()) {
+                        (Some(a), Some(b)) => Point::from(*a).geodesic_distance(&Point::from(*b)),
+                        _ => 0.0
+                    };
+                    // rings have no distance between their ends
+                    if distance_m > 0.0 {
+                        measures.sinuosity = Some(length_m / distance_m);
+                    }
+                },
+                GeometryMeasure::Curvature => {
+                    let mut points = coords.clone();
+                    points.dedup();
+                    if length_m > 0.0 {
+                        let change: f64 = points.windows(3)
+                            .map(|w| bearing_change(w[0], w[1], w[2]))
+                            .sum();
+                        measures.curvature_deg_per_km = Some(change / (length_m / 1000.0));
+                    }
+                }
+            }
+        }
+        measures
+    }
+
+    /// Which measures these are, to measure them again
+    pub fn selected(&self) -> Vec<GeometryMeasure> {
+        let mut selected = vec![];
+        if self.start_bearing_deg.is_some() {
+            selected.push(GeometryMeasure::StartBearing);
+        }
+        if self.end_bearing_deg.is_some() {
+            selected.push(GeometryMeasure::EndBearing);
+        }
+        if self.sinuosity.is_some() {
+            selected.push(GeometryMeasure::Sinuosity);
+        }
+        if self.curvature_deg_per_km.is_some() {
+            selected.push(GeometryMeasure::Curvature);
+        }
+        selected
+    }
+}
+
+impl EdgeList<f64> {
+    /// Measure the geometry of every edge, before `reproject`. Merging
+    /// chains measures merged edges again, but splitting, densifying or
+    /// simplifying afterwards drops their measures.
+    pub fn measure_geometry(&mut self, selected: &[GeometryMeasure]) {
+        if selected.is_empty() {
+            return
+        }
+        for edge in self.edges.iter_mut() {
+            edge.measures = Some(GeometryMeasures::of(edge, selected));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::edge_list::{
+        basic_roads_config,
+        edge_list_from_xml,
+        edge_list_from_xml_with_config,
+    };
+
+    #[test]
+    fn test_measure_geometry() {
+        // east along the equator, north, then east again
+        let xml = r#"<osm>
+  <node id="1" lat="0.0" lon="0.0"/>
+  <node id="2" lat="0.0" lon="0.001"/>
+  <node id="3" lat="0.001" lon="0.001"/>
+  <node id="4" lat="0.001" lon="0.002"/>
+  <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><tag k="highway" v="primary"/></way>
+</osm>"#;
+        let mut gc = basic_roads_config();
+        gc.geometry_measures = serde_json::from_str(r#"["start-bearing", "end-bearing", "sinuosity", "curvature"]"#).unwrap();
+        let (cache, mut edge_list) = edge_list_from_xml_with_config(gc, xml);
+        edge_list.measure_geometry(&cache.graph_config.geometry_measures);
+
+        let measures = edge_list.edges[0].measures.unwrap();
+        assert!((measures.start_bearing_deg.unwrap() - 90.0).abs() < 1e-6);
+        assert!((measures.end_bearing_deg.unwrap() - 90.0).abs() < 0.01);
+        // three sides of 111 m over ends √5 × 111 m apart
+        assert!((measures.sinuosity.unwrap() - 3.0 / 5.0_f64.sqrt()).abs() < 0.01, "{:?}", measures);
+        // a left turn and a right turn of 90° in about 334 m
+        let km = edge_list.edges[0].length_m / 1000.0;
+        assert!((measures.curvature_deg_per_km.unwrap() - 180.0 / km).abs() < 0.1, "{:?}", measures);
+    }
+
+    #[test]
+    fn test_geodesic_bearings() {
+        // due east at 60° north, the geodesic bends towards the pole and back
+        let xml = r#"<osm>
+  <node id="1" lat="60.0" lon="0.0"/>
+  <node id="2" lat="60.0" lon="10.0"/>
+  <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+</osm>"#;
+        let (_, edge_list) = edge_list_from_xml(xml);
+        let edge = &edge_list.edges[0];
+        let (start, end) = (edge.start_bearing().unwrap(), edge.end_bearing().unwrap());
+        assert!((start - 85.67).abs() < 0.01, "{}", start);
+        assert!((start + end - 180.0).abs() < 1e-9, "{} {}", start, end);
+    }
+}
@@ -0,0 +1,159 @@
+//! The vertices of a graph, to go with its `EdgeList`
+
+use std::collections::BTreeMap;
+use geo::Coordinate;
+use osmpbfreader::{NodeId, Tags};
+use crate::{
+    crs::Crs,
+    edge_list::EdgeList,
+    osm_cache::OSMCache,
+};
+#[cfg(feature="formats-geojson")]
+use serde_json::{json, Value, Map};
+#[cfg(feature="formats-geojson")]
+use geojson;
+#[cfg(feature="formats-gdal")]
+use gdal;
+#[cfg(feature="formats-gdal")]
+use crate::error::Error;
+
+#[derive(Debug, Clone)]
+pub struct Vertex {
+    pub id: NodeId,
+    /// The contiguous id of the vertex, if the edge list has been reindexed
+    pub vertex_id: Option<usize>,
+    pub coordinate: Coordinate<f64>,
+    /// The number of edge ends at the vertex, so a loop counts twice
+    pub degree: usize,
+    /// The number of edges that can be travelled into the vertex
+    pub in_degree: usize,
+    /// The number of edges that can be travelled out of the vertex
+    pub out_degree: usize,
+    /// The connected component of the vertex, if components have been labelled
+    pub component: Option<usize>,
+    /// The tags of the node, if requested and it has any
+    pub tags: Option<Tags>
+}
+
+#[derive(Debug)]
+pub struct NodeList {
+    pub nodes: Vec<Vertex>,
+    /// The coordinate system of the edges the nodes were taken from
+    pub crs: Crs
+}
+
+impl NodeList {
+    /// Collect the end nodes of the edges, ordered by id. The coordinates
+    /// are taken from the edge geometries so they match them.
+    pub fn from_edges(edge_list: &EdgeList<f64>, osm_cache: &OSMCache, include_tags: bool) -> Self {
+        let mut vertices: BTreeMap<NodeId, Vertex> = BTreeMap::new();
+        for edge in edge_list.edges.iter() {
+            let coords = &edge.geometry.0;
+            let (first, last) = match (coords.first(), coords.last()) {
+                (Some(first), Some(last)) => (*first, *last),
+                _ => continue
+            };
+            let ends = [
+                (edge.start_node_id, first, edge.direction.backward(), edge.direction.forward()),
+                (edge.end_node_id, last, edge.direction.forward(), edge.direction.backward())
+            ];
+            for (id, coordinate, into, out_of) in ends.iter() {
+                let vertex = vertices.entry(*id).or_insert_with(|| Vertex {
+                    id: *id,
+                    vertex_id: edge_list.vertex_id(*id),
+                    coordinate: *coordinate,
+                    degree: 0,
+                    in_degree: 0,
+                    out_degree: 0,
+                    component: edge_list.component(*id),
+                    tags: None
+                });
+                vertex.degree += 1;
+                vertex.in_degree += *into as usize;
+                vertex.out_degree += *out_of as usize;
+            }
+        }
+        if include_tags {
+            for vertex in vertices.values_mut() {
+                vertex.tags = osm_cache.node(vertex.id)
+                    .map(|n| n.tags)
+                    .filter(|t| !t.is_empty());
+            }
+        }
+        NodeList { nodes: vertices.into_values().collect(), crs: edge_list.crs }
+    }
+}
+
+#[cfg(feature="formats-geojson")]
+impl From<Vertex> for geojson::Feature {
+    fn from(vertex: Vertex) -> geojson::Feature {
+        let mut props: Map<String, Value> = Map::new();
+        if let Some(vertex_id) = vertex.vertex_id {
+            props.insert("vertex_id".to_string(), json!(vertex_id));
+        }
+        props.insert("node_id".to_string(), json!(vertex.id));
+        props.insert("degree".to_string(), json!(vertex.degree));
+        props.insert("in_degree".to_string(), json!(vertex.in_degree));
+        props.insert("out_degree".to_string(), json!(vertex.out_degree));
+        if let Some(component) = vertex.component {
+            props.insert("component".to_string(), json!(component));
+        }
+        if let Some(tags) = &vertex.tags {
+            let tags: Map<String, Value> = tags.iter()
+                .map(|(k, v)| (k.to_string(), json!(v.as_str())))
+                .collect();
+            props.insert("tags".to_string(), Value::Object(tags));
+        }
+        let point = geojson::Value::Point(vec![vertex.coordinate.x, vertex.coordinate.y]);
+        geojson::Feature {
+            bbox: None,
+            geometry: Some(geojson::Geometry::new(point)),
+            id: None,
+            properties: Some(props),
+            foreign_members: None
+        }
+    }
+}
+
+#[cfg(feature="formats-geojson")]
+impl From<NodeList> for geojson::FeatureCollection {
+    fn from(nl: NodeList) -> geojson::FeatureCollection {
+        geojson::FeatureCollection {
+            bbox: None,
+            foreign_members: Some(nl.crs).filter(|c| *c != Crs::default()).map(|c| c.geojson_member()),
+            features: nl.nodes.into_iter().map(|v| v.into()).collect()
+        }
+    }
+}
+
+#[cfg(feature="formats-gdal")]
+pub fn coordinate_to_gdal(coordinate: &Coordinate<f64>) -> Result<gdal::vector::Geometry, Error> {
+    let mut geom = gdal::vector::Geometry::empty(gdal::vector::OGRwkbGeometryType::wkbPoint)
+        .map_err(Error::GdalError)?;
+    geom.set_point_2d(0, (coordinate.x, coordinate.y));
+    Ok(geom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edge_list::edge_list_from_xml;
+
+    #[test]
+    fn test_degrees() {
+        // make King Street one way
+        let xml = include_str!("../../examples/basic_roads.osm").replace(
+            r#"<tag k="name" v="King Street"/>"#,
+            r#"<tag k="name" v="King Street"/><tag k="oneway" v="yes"/>"#);
+        let (cache, edge_list) = edge_list_from_xml(&xml);
+        let node_list = NodeList::from_edges(&edge_list, &cache, true);
+
+        let summary: Vec<_> = node_list.nodes.iter()
+            .map(|v| (v.id.0, v.degree, v.in_degree, v.out_degree))
+            .collect();
+        assert_eq!(summary, vec![(1, 1, 1, 1), (3, 4, 3, 3), (4, 1, 1, 1), (5, 1, 0, 1), (7, 1, 1, 0)]);
+        let signals = node_list.nodes.iter().find(|v| v.id == NodeId(3)).unwrap();
+        assert!(signals.tags.as_ref().unwrap().contains("highway", "traffic_signals"));
+        assert!(node_list.nodes[0].tags.is_none());
+    }
+}
@@ -0,0 +1,300 @@
+//! Storage for the locations and counts of cached nodes
+//!
+//! Most nodes are untagged and only needed for their location, so an
+//! `OSMCache` keeps node locations in a `NodeStore` and only keeps tagged
+//! nodes as objects. The store also counts the ways passing through each
+//! node, which decides where edges split. `MemoryNodeStore` is the default.
+//! For networks whose nodes don't fit in memory, `FileNodeStore` keeps the
+//! entries in a memory mapped sparse file indexed by node id, like
+//! osmium's dense index.
+
+use std::{
+    convert::TryFrom,
+    collections::HashMap,
+    fs::{
+        File,
+        OpenOptions,
+    },
+    path::Path,
+};
+use memmap2::MmapMut;
+use osmpbfreader::NodeId;
+use serde::{Serialize, Deserialize};
+use crate::error::Error;
+
+/// What a store keeps for a node
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeEntry {
+    /// The decimicro latitude and longitude, once the node is loaded
+    pub location: Option<(i32, i32)>,
+    /// The version of the loaded node
+    pub version: i32,
+    /// The number of times cached ways pass through the node
+    pub ways: u16,
+    /// The number of times splitting ways pass through the node, see
+    /// `GraphConfig::split_at`
+    pub splits: u16
+}
+
+pub trait NodeStore: Send + Sync {
+    /// The entry of a node, or the default entry if nothing is stored
+    fn entry(&self, id: NodeId) -> NodeEntry;
+
+    /// Replace the entry of a node. Storing the default entry forgets the node.
+    fn set_entry(&mut self, id: NodeId, entry: NodeEntry) -> Result<(), Error>;
+
+    /// Call `f` with every stored entry, in no particular order, stopping
+    /// at the first error
+    fn for_each_entry(
+        &self,
+        f: &mut dyn FnMut(NodeId, NodeEntry) -> Result<(), Error>
+    ) -> Result<(), Error>;
+
+    /// The decimicro latitude and longitude of a node
+    fn get(&self, id: NodeId) -> Option<(i32, i32)> {
+        self.entry(id).location
+    }
+
+    /// The version of a node, so that inputs and changes only replace a
+    /// node with a newer one
+    fn version(&self, id: NodeId) -> Option<i32> {
+        let entry = self.entry(id);
+        entry.location.map(|_| entry.version)
+    }
+
+    /// Store the location of a node, keeping its counts
+    fn insert(&mut self, id: NodeId, decimicro_lat: i32, decimicro_lon: i32, version: i32) -> Result<(), Error> {
+        let entry = NodeEntry {
+            location: Some((decimicro_lat, decimicro_lon)),
+            version,
+            ..self.entry(id)
+        };
+        self.set_entry(id, entry)
+    }
+
+    /// Forget the location of a node, keeping its counts
+    fn remove(&mut self, id: NodeId) -> Result<(), Error> {
+        let entry = NodeEntry {
+            location: None,
+            version: 0,
+            ..self.entry(id)
+        };
+        self.set_entry(id, entry)
+    }
+
+    fn contains(&self, id: NodeId) -> bool {
+        self.get(id).is_some()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryNodeStore {
+    nodes: HashMap<NodeId, NodeEntry>
+}
+
+impl NodeStore for MemoryNodeStore {
+    fn entry(&self, id: NodeId) -> NodeEntry {
+        self.nodes.get(&id).copied().unwrap_or_default()
+    }
+
+    fn set_entry(&mut self, id: NodeId, entry: NodeEntry) -> Result<(), Error> {
+        if entry == NodeEntry::default() {
+            self.nodes.remove(&id);
+        } else {
+            self.nodes.insert(id, entry);
+        }
+        Ok(())
+    }
+
+    fn for_each_entry(
+        &self,
+        f: &mut dyn FnMut(NodeId, NodeEntry) -> Result<(), Error>
+    ) -> Result<(), Error> {
+        for (id, entry) in self.nodes.iter() {
+            f(*id, *entry)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bytes per node - the latitude, longitude and version as i32s, then the
+/// way and split counts as u16s
+const SLOT_SIZE: usize = 16;
+/// The number of slots the file starts with, it doubles as larger ids arrive
+const INITIAL_SLOTS: u64 = 1 << 20;
+/// Added to latitudes so that a stored location is never zero, which is
+/// how the unwritten parts of a sparse file read
+const LAT_OFFSET: i32 = 1_000_000_000;
+
+/// Node entries in a memory mapped sparse file, with the slot for each
+/// node at `id * 16` bytes. The file grows to fit the largest id stored,
+/// and only the pages holding nodes take up space on disk. Negative ids
+/// are kept in memory.
+pub struct FileNodeStore {
+    file: File,
+    mmap: MmapMut,
+    overflow: MemoryNodeStore
+}
+
+impl FileNodeStore {
+    /// Create (or truncate) the file at `path`. The file must not be
+    /// changed by anything else while the store is open.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(Error::IoError)?;
+        file.set_len(INITIAL_SLOTS * SLOT_SIZE as u64)
+            .map_err(Error::IoError)?;
+        let mmap = map(&file)?;
+        Ok(FileNodeStore {
+            file,
+            mmap,
+            overflow: MemoryNodeStore::default()
+        })
+    }
+
+    /// Extend the file to the next power of two slots that fits `id`
+    fn grow(&mut self, id: u64) -> Result<(), Error> {
+        let len = id.checked_add(1)
+            .and_then(u64::checked_next_power_of_two)
+            .and_then(|slots| slots.checked_mul(SLOT_SIZE as u64))
+            .ok_or_else(|| Error::IoError(std::io::ErrorKind::InvalidInput.into()))?;
+        self.file.set_len(len)
+            .map_err(Error::IoError)?;
+        self.mmap = map(&self.file)?;
+        Ok(())
+    }
+
+    fn slot(&self, id: NodeId) -> Option<usize> {
+        let offset = usize::try_from(id.0).ok()?.checked_mul(SLOT_SIZE)?;
+        if offset + SLOT_SIZE <= self.mmap.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+}
+
+/// Map the whole of a node store's file
+fn map(file: &File) -> Result<MmapMut, Error> {
+    // SAFETY: the map is only valid while the file isn't truncated or
+    // written to except through it. The store creates the file, truncating
+    // whatever was there, holds its only handle, and only ever grows it, so
+    // every map stays within the file for as long as it's used. Other
+    // processes changing the file are ruled out by `FileNodeStore::create`.
+    unsafe { MmapMut::map_mut(file) }
+        .map_err(Error::IoError)
+}
+
+impl FileNodeStore {
+    /// The entry in a slot, where an all zero slot is the default entry
+    fn read(&self, offset: usize) -> NodeEntry {
+        let bytes = &self.mmap[offset..offset + SLOT_SIZE];
+        let field = |i: usize| i32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let count = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let lat = field(0);
+        NodeEntry {
+            location: if lat == 0 { None } else { Some((lat - LAT_OFFSET, field(4))) },
+            version: field(8),
+            ways: count(12),
+            splits: count(14)
+        }
+    }
+
+    fn write(&mut self, offset: usize, entry: NodeEntry) {
+        let (lat, lon) = entry.location
+            .map(|(lat, lon)| (lat + LAT_OFFSET, lon))
+            .unwrap_or((0, 0));
+        let slot = &mut self.mmap[offset..offset + SLOT_SIZE];
+        slot[0..4].copy_from_slice(&lat.to_le_bytes());
+        slot[4..8].copy_from_slice(&lon.to_le_bytes());
+        slot[8..12].copy_from_slice(&entry.version.to_le_bytes());
+        slot[12..14].copy_from_slice(&entry.ways.to_le_bytes());
+        slot[14..16].copy_from_slice(&entry.splits.to_le_bytes());
+    }
+}
+
+impl NodeStore for FileNodeStore {
+    fn entry(&self, id: NodeId) -> NodeEntry {
+        match self.slot(id) {
+            Some(offset) => self.read(offset),
+            None if id.0 < 0 => self.overflow.entry(id),
+            None => NodeEntry::default()
+        }
+    }
+
+    fn set_entry(&mut self, id: NodeId, entry: NodeEntry) -> Result<(), Error> {
+        if id.0 < 0 {
+            return self.overflow.set_entry(id, entry)
+        }
+        if self.slot(id).is_none() {
+            if entry == NodeEntry::default() {
+                return Ok(())
+            }
+            self.grow(id.0 as u64)?;
+        }
+        match self.slot(id) {
+            Some(offset) => {
+                self.write(offset, entry);
+                Ok(())
+            },
+            None => Err(Error::IoError(std::io::ErrorKind::InvalidInput.into()))
+        }
+    }
+
+    fn for_each_entry(
+        &self,
+        f: &mut dyn FnMut(NodeId, NodeEntry) -> Result<(), Error>
+    ) -> Result<(), Error> {
+        for (i, slot) in self.mmap.chunks_exact(SLOT_SIZE).enumerate() {
+            if slot.iter().any(|b| *b != 0) {
+                f(NodeId(i as i64), self.read(i * SLOT_SIZE))?;
+            }
+        }
+        self.overflow.for_each_entry(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_node_store() {
+        let path = std::env::temp_dir().join(format!("sulu-nodes-{}.bin", std::process::id()));
+        let mut store = FileNodeStore::create(&path).unwrap();
+        store.insert(NodeId(7), -338_600_000, 1_512_100_000, 3).unwrap();
+        // null island is a valid location
+        store.insert(NodeId(8), 0, 0, 1).unwrap();
+        // beyond the end of the file, which grows to fit it
+        store.insert(NodeId(5_000_000), 1, 2, 1).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), (1 << 23) * SLOT_SIZE as u64);
+        store.insert(NodeId(-1), 3, 4, 2).unwrap();
+        assert_eq!(store.get(NodeId(7)), Some((-338_600_000, 1_512_100_000)));
+        assert_eq!(store.version(NodeId(7)), Some(3));
+        assert_eq!(store.version(NodeId(-1)), Some(2));
+        assert_eq!(store.get(NodeId(8)), Some((0, 0)));
+        assert_eq!(store.get(NodeId(5_000_000)), Some((1, 2)));
+        assert_eq!(store.get(NodeId(-1)), Some((3, 4)));
+        assert_eq!(store.get(NodeId(9)), None);
+        // the counts are kept apart from the location
+        store.set_entry(NodeId(9), NodeEntry { ways: 2, splits: 1, ..NodeEntry::default() }).unwrap();
+        assert_eq!(store.get(NodeId(9)), None);
+        store.insert(NodeId(9), 5, 6, 1).unwrap();
+        assert_eq!(store.entry(NodeId(9)).ways, 2);
+        store.remove(NodeId(7)).unwrap();
+        assert!(!store.contains(NodeId(7)));
+        let mut ids = vec![];
+        store.for_each_entry(&mut |id, _| { ids.push(id.0); Ok(()) }).unwrap();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![-1, 8, 9, 5_000_000]);
+        // an id too large for any file is an error
+        assert!(store.insert(NodeId(i64::MAX), 1, 2, 1).is_err());
+        drop(store);
+        std::fs::remove_file(path).unwrap();
+    }
+}
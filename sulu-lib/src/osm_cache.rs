@@ -1,7 +1,9 @@
 use std::{
     collections::{
         HashMap,
-        BTreeMap
+        HashSet,
+        BTreeMap,
+        BTreeSet,
     },
     io::{
         Read,
@@ -12,10 +14,36 @@ use osmpbfreader::{
     OsmPbfReader,
     OsmId,
     OsmObj,
+    NodeId,
+    WayId,
+    Node,
+    Way,
+    Tags,
+    groups,
+    primitive_block_from_blob,
+    osmformat::{
+        PrimitiveBlock,
+        PrimitiveGroup,
+    },
 };
 use serde::{Serialize, Deserialize};
 use crate::{
-    graph_config::GraphConfig,
+    graph_config::{
+        GraphConfig,
+        SplitAt,
+    },
+    osm_info::OsmInfo,
+    node_store::{
+        NodeStore,
+        MemoryNodeStore,
+    },
+    progress::{
+        Phase,
+        Progress,
+        Observer,
+        NoProgress,
+        report,
+    },
     error::Error,
 };
 
@@ -23,14 +51,14 @@ pub fn count_nodes(
     objs: &BTreeMap<OsmId, OsmObj>
 ) -> Result<HashMap<OsmId, usize>, Error> {
     let mut map = HashMap::new();
-    for (_, obj) in objs {
+    for obj in objs.values() {
         if obj.is_way() {
             let way = obj.way().unwrap();
-            for nid in &way.nodes {
+            for nid in passed_nodes(&way.nodes) {
                 let nid: OsmId = (*nid).into();
                 match map.get_mut(&nid) {
                     Some(c) => *c += 1,
-                    None => {map.insert(nid, 1); ()}
+                    None => { map.insert(nid, 1); }
                 }
             }
         }
@@ -38,50 +66,502 @@ pub fn count_nodes(
     Ok(map)
 }
 
+/// The nodes a way passes through, for counting. A node is passed each
+/// time the way goes through it, but a node repeated straight after itself
+/// is only passed once.
+fn passed_nodes(nodes: &[NodeId]) -> impl Iterator<Item=&NodeId> {
+    nodes.iter()
+        .enumerate()
+        .filter(move |(i, nid)| *i == 0 || nodes[i - 1] != **nid)
+        .map(|(_, nid)| nid)
+}
+
+/// Iterate over the nodes of a primitive group along with their versions.
+/// Nodes written without an info block get the pbf default version of -1.
+fn nodes_with_versions<'a>(
+    group: &'a PrimitiveGroup,
+    block: &'a PrimitiveBlock
+) -> impl Iterator<Item=(Node, i32)> + 'a {
+    let simple = groups::simple_nodes(group, block)
+        .zip(group.get_nodes())
+        .map(|(n, raw)| (n, raw.get_info().get_version()));
+    let dense_versions = group.get_dense().get_denseinfo().get_version();
+    let dense = groups::dense_nodes(group, block)
+        .enumerate()
+        .map(move |(i, n)| (n, dense_versions.get(i).copied().unwrap_or(-1)));
+    simple.chain(dense)
+}
+
+/// Read every block of a pbf from the start, reporting progress after each.
+/// `f` returns the number of ways it selected from the block.
+fn for_each_block<R, F>(
+    pbf: &mut OsmPbfReader<R>,
+    phase: Phase,
+    observer: &mut dyn Observer,
+    mut f: F
+) -> Result<(), Error>
+where
+    R: Read + Seek,
+    F: FnMut(&PrimitiveBlock) -> Result<usize, Error>
+{
+    let mut progress = Progress::new(phase);
+    pbf.rewind().map_err(Error::PbfError)?;
+    for blob in pbf.blobs() {
+        let blob = blob.map_err(Error::PbfError)?;
+        progress.bytes_read += (blob.get_raw().len()
+            + blob.get_zlib_data().len()
+            + blob.get_lzma_data().len()) as u64;
+        let block = primitive_block_from_blob(&blob).map_err(Error::PbfError)?;
+        progress.ways += f(&block)?;
+        progress.blobs += 1;
+        report(observer, &progress)?;
+    }
+    Ok(())
+}
+
+/// The encoding of an input file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    /// `.osm.pbf`
+    Pbf,
+    /// `.osm` XML, optionally gzip or bzip2 compressed
+    Xml
+}
+
+impl InputFormat {
+    /// Guess the format from the file extension, defaulting to pbf
+    pub fn from_path<P: AsRef<std::path::Path>>(path: P) -> Self {
+        let name = path.as_ref()
+            .file_name()
+            .map(|n| n.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let name = name.trim_end_matches(".gz").trim_end_matches(".bz2");
+        if name.ends_with(".osm") || name.ends_with(".xml") {
+            InputFormat::Xml
+        } else {
+            InputFormat::Pbf
+        }
+    }
+}
+
+impl std::str::FromStr for InputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pbf" => Ok(InputFormat::Pbf),
+            "xml" | "osm" => Ok(InputFormat::Xml),
+            _ => Err(Error::ConversionError(format!("Unknown input format: {}", s)))
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct OSMCache {
+    /// Not kept in snapshots, `load_snapshot` is given the graph config
+    #[serde(skip)]
     pub graph_config: GraphConfig,
+    /// The cached ways and relations, and the nodes that have tags
     pub osm_cache: BTreeMap<OsmId, OsmObj>,
-    pub node_count: HashMap<OsmId, usize>
+    /// The location of every cached node, and the number of ways passing
+    /// through it. Snapshots write the nodes after the rest of the cache,
+    /// see `save_snapshot`.
+    #[serde(skip, default="default_node_store")]
+    pub nodes: Box<dyn NodeStore>,
+    /// The version of each cached way and relation. The versions of nodes
+    /// are kept with their locations, see `version`.
+    pub versions: HashMap<OsmId, i32>,
+    /// The version, timestamp, changeset and user of each cached way
+    pub way_info: HashMap<WayId, OsmInfo>,
+    /// The ids of ways outside the network that still split its edges, see
+    /// `GraphConfig::split_at`. Their nodes aren't kept, only counted in
+    /// the node store as the ways are read.
+    pub splitting_ways: BTreeSet<WayId>
+}
+
+fn default_node_store() -> Box<dyn NodeStore> {
+    Box::new(MemoryNodeStore::default())
 }
 
 impl OSMCache {
     pub fn new(gc: GraphConfig) -> Self {
+        OSMCache::with_node_store(gc, default_node_store())
+    }
+
+    /// Make an empty cache that keeps node locations in `nodes`, e.g. a
+    /// `FileNodeStore` for networks whose nodes don't fit in memory
+    pub fn with_node_store(gc: GraphConfig, nodes: Box<dyn NodeStore>) -> Self {
         OSMCache {
             graph_config: gc,
             osm_cache: BTreeMap::new(),
-            node_count: HashMap::new()
+            nodes,
+            versions: HashMap::new(),
+            way_info: HashMap::new(),
+            splitting_ways: BTreeSet::new()
         }
     }
 
+    /// Load a file, choosing the reader from the file extension
     pub fn load_from_path<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<(), Error> {
+        let format = InputFormat::from_path(&path);
+        self.load_from_path_with_format(path, format)
+    }
+
+    pub fn load_from_path_with_format<P: AsRef<std::path::Path>>(
+        &mut self,
+        path: P,
+        format: InputFormat
+    ) -> Result<(), Error> {
+        self.load_from_path_with_observer(path, format, &mut NoProgress)
+    }
+
+    /// Load a file, reporting progress as it's read
+    pub fn load_from_path_with_observer<P: AsRef<std::path::Path>>(
+        &mut self,
+        path: P,
+        format: InputFormat,
+        observer: &mut dyn Observer
+    ) -> Result<(), Error> {
         let f = std::fs::File::open(path)
             .map_err(Error::IoError)?;
-        let mut pbf = OsmPbfReader::new(f);
-        self.load_pbf(&mut pbf)
+        match format {
+            InputFormat::Pbf => {
+                let mut pbf = OsmPbfReader::new(f);
+                self.load_pbf_with_observer(&mut pbf, observer)
+            },
+            InputFormat::Xml => self.load_xml_with_observer(f, observer)
+        }
     }
 
-    pub fn load_pbf<R>(&mut self, pbf: &mut OsmPbfReader<R>) -> Result<(), Error> 
-    where 
+    /// Load several (possibly overlapping) files into the cache,
+    /// e.g. adjacent tiles or regional extracts
+    pub fn load_from_paths<I, P>(&mut self, paths: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item=P>,
+        P: AsRef<std::path::Path>
+    {
+        for path in paths {
+            self.load_from_path(path)?;
+        }
+        Ok(())
+    }
+
+    /// Load the selected ways, route relations and areas, and the nodes the
+    /// ways reference, from a pbf.
+    ///
+    /// This can be called more than once. Objects already in the cache are
+    /// only replaced by a newer version, and nodes are counted over every
+    /// cached way so that networks from separate inputs connect.
+    pub fn load_pbf<R>(&mut self, pbf: &mut OsmPbfReader<R>) -> Result<(), Error>
+    where
         R: Read + Seek
     {
-        let mut cache = pbf.get_objs_and_deps(
-            |o| {
-                match o {
-                    OsmObj::Way(w) => self.graph_config.is_match(&w.tags),
-                    _ => false
+        self.load_pbf_with_observer(pbf, &mut NoProgress)
+    }
+
+    /// Load a pbf as with `load_pbf`, reporting progress after each blob
+    pub fn load_pbf_with_observer<R>(
+        &mut self,
+        pbf: &mut OsmPbfReader<R>,
+        observer: &mut dyn Observer
+    ) -> Result<(), Error>
+    where
+        R: Read + Seek
+    {
+        // first pass - select the ways and relations
+        for_each_block(pbf, Phase::Ways, observer, |block| {
+            let mut selected = 0;
+            for group in block.get_primitivegroup() {
+                for (way, raw) in groups::ways(group, block).zip(group.get_ways()) {
+                    if self.graph_config.is_way_match(&way.tags) {
+                        self.insert_with_info(OsmObj::Way(way), OsmInfo::from_pbf(raw.get_info(), block))?;
+                        selected += 1;
+                    }
+                }
+                for (rel, raw) in groups::relations(group, block).zip(group.get_relations()) {
+                    if self.graph_config.is_relation_match(&rel.tags) {
+                        self.insert(OsmObj::Relation(rel), raw.get_info().get_version())?;
+                    }
                 }
             }
-        ).map_err(Error::PbfError)?;
-        let node_count = count_nodes(&cache)
-            .map_err(|_| Error::NodeCountError)?;
-        self.osm_cache.append(&mut cache);
-        for (k, v) in node_count.into_iter() {
-            match self.node_count.get_mut(&k) {
-                Some(ev) => { *ev = (*ev).max(v); },
-                None => { self.node_count.insert(k, v); }
+            Ok(selected)
+        })?;
+
+        // second pass - the outlines of multipolygon areas, which are usually
+        // untagged ways, and the ways outside the network that split it
+        let required_ways = self.required_ways();
+        let splitting = !matches!(self.graph_config.split_at, SplitAt::Selected);
+        if !required_ways.is_empty() || splitting {
+            for_each_block(pbf, Phase::MemberWays, observer, |block| {
+                let mut selected = 0;
+                for group in block.get_primitivegroup() {
+                    for (way, raw) in groups::ways(group, block).zip(group.get_ways()) {
+                        if required_ways.contains(&way.id) {
+                            self.insert_with_info(OsmObj::Way(way), OsmInfo::from_pbf(raw.get_info(), block))?;
+                            selected += 1;
+                        } else if splitting {
+                            self.insert_splitting_way(&way)?;
+                        }
+                    }
+                }
+                Ok(selected)
+            })?;
+        }
+
+        // last pass - collect the nodes of every cached way, including
+        // ways from earlier inputs that were missing nodes
+        for_each_block(pbf, Phase::Nodes, observer, |block| {
+            for group in block.get_primitivegroup() {
+                for (node, version) in nodes_with_versions(group, block) {
+                    if self.requires_node(node.id) {
+                        self.insert(OsmObj::Node(node), version)?;
+                    }
+                }
             }
+            Ok(0)
+        })
+    }
+
+    /// The ids of the member ways of cached multipolygons that aren't cached yet
+    pub fn required_ways(&self) -> HashSet<WayId> {
+        self.osm_cache.values()
+            .filter_map(|o| o.relation())
+            .filter(|r| r.tags.contains("type", "multipolygon"))
+            .flat_map(|r| r.refs.iter().filter_map(|m| m.member.way()))
+            .filter(|w| !self.osm_cache.contains_key(&OsmId::Way(*w)))
+            .collect()
+    }
+
+    /// Whether a cached way references a node
+    pub fn requires_node(&self, id: NodeId) -> bool {
+        self.nodes.entry(id).ways > 0
+    }
+
+    /// The number of times cached ways pass through a node, plus the number
+    /// of times splitting ways do if it's on a cached way. Edges are split
+    /// where this is more than one.
+    pub fn node_count(&self, id: NodeId) -> usize {
+        let entry = self.nodes.entry(id);
+        if entry.ways > 0 {
+            entry.ways as usize + entry.splits as usize
+        } else {
+            0
+        }
+    }
+
+    /// Add (or take away) the passes of a way through its nodes, to the way
+    /// counts of a cached way or the split counts of a splitting way
+    fn count_way(&mut self, way: &Way, splitting: bool, add: bool) -> Result<(), Error> {
+        for nid in passed_nodes(&way.nodes) {
+            let mut entry = self.nodes.entry(*nid);
+            let count = if splitting { &mut entry.splits } else { &mut entry.ways };
+            *count = if add { count.saturating_add(1) } else { count.saturating_sub(1) };
+            self.nodes.set_entry(*nid, entry)?;
         }
         Ok(())
     }
+
+    /// Insert an object, keeping whichever of the cached and new objects has
+    /// the higher version. Returns true if the object was inserted.
+    pub fn insert(&mut self, obj: OsmObj, version: i32) -> Result<bool, Error> {
+        let id = obj.id();
+        if let Some(existing) = self.version(&id) {
+            if existing >= version {
+                return Ok(false)
+            }
+        }
+        match obj {
+            OsmObj::Node(node) => {
+                self.nodes.insert(node.id, node.decimicro_lat, node.decimicro_lon, version)?;
+                if node.tags.is_empty() {
+                    self.osm_cache.remove(&id);
+                } else {
+                    self.osm_cache.insert(id, OsmObj::Node(node));
+                }
+            },
+            obj => {
+                self.versions.insert(id, version);
+                if let OsmObj::Way(way) = &obj {
+                    self.way_info.remove(&way.id);
+                    // a way in the network no longer splits it from outside
+                    if self.splitting_ways.remove(&way.id) {
+                        self.count_way(way, true, false)?;
+                    }
+                    self.count_way(way, false, true)?;
+                }
+                if let Some(OsmObj::Way(old)) = self.osm_cache.insert(id, obj) {
+                    self.count_way(&old, false, false)?;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// The version of a cached object
+    pub fn version(&self, id: &OsmId) -> Option<i32> {
+        match id {
+            OsmId::Node(nid) => self.nodes.version(*nid),
+            _ => self.versions.get(id).copied()
+        }
+    }
+
+    /// Remove an object from the cache, returning it if it was cached
+    pub fn remove(&mut self, id: &OsmId) -> Result<Option<OsmObj>, Error> {
+        self.versions.remove(id);
+        match id {
+            OsmId::Node(nid) => {
+                let node = self.node(*nid);
+                self.nodes.remove(*nid)?;
+                self.osm_cache.remove(id);
+                Ok(node.map(OsmObj::Node))
+            },
+            OsmId::Way(wid) => {
+                self.way_info.remove(wid);
+                let old = self.osm_cache.remove(id);
+                if let Some(OsmObj::Way(way)) = &old {
+                    self.count_way(way, false, false)?;
+                }
+                Ok(old)
+            },
+            OsmId::Relation(_) => Ok(self.osm_cache.remove(id))
+        }
+    }
+
+    pub fn contains_node(&self, id: NodeId) -> bool {
+        self.nodes.contains(id)
+    }
+
+    /// The longitude and latitude of a cached node
+    pub fn node_coords(&self, id: NodeId) -> Option<(f64, f64)> {
+        self.nodes.get(id)
+            .map(|(lat, lon)| (lon as f64 * 1e-7, lat as f64 * 1e-7))
+    }
+
+    /// A cached node, with its tags if it has any
+    pub fn node(&self, id: NodeId) -> Option<Node> {
+        let (decimicro_lat, decimicro_lon) = self.nodes.get(id)?;
+        let tags = match self.osm_cache.get(&OsmId::Node(id)) {
+            Some(OsmObj::Node(node)) => node.tags.clone(),
+            _ => Tags::new()
+        };
+        Some(Node {
+            id,
+            tags,
+            decimicro_lat,
+            decimicro_lon
+        })
+    }
+
+    /// Insert an object as with `insert`, keeping the info of ways
+    pub fn insert_with_info(&mut self, obj: OsmObj, info: OsmInfo) -> Result<bool, Error> {
+        let id = obj.id();
+        if !self.insert(obj, info.version)? {
+            return Ok(false)
+        }
+        if let OsmId::Way(wid) = id {
+            self.way_info.insert(wid, info);
+        }
+        Ok(true)
+    }
+
+    /// Count the nodes of a way outside the network, if the way splits the
+    /// network. Only the nodes on cached ways split it, see `node_count`,
+    /// so ways from one input can split the ways of another. Each way is
+    /// only counted once.
+    pub fn insert_splitting_way(&mut self, way: &Way) -> Result<(), Error> {
+        if self.osm_cache.contains_key(&OsmId::Way(way.id))
+            || !self.graph_config.is_split_match(&way.tags)
+            || !self.splitting_ways.insert(way.id) {
+            return Ok(())
+        }
+        self.count_way(way, true, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph_config::{GraphConfig, SplitAt};
+
+    fn way(id: i64, nodes: &[i64]) -> OsmObj {
+        OsmObj::Way(Way {
+            id: WayId(id),
+            tags: Tags::new(),
+            nodes: nodes.iter().map(|n| NodeId(*n)).collect()
+        })
+    }
+
+    fn empty_cache() -> OSMCache {
+        OSMCache::new(GraphConfig { name: "test".to_string(), ..Default::default() })
+    }
+
+    #[test]
+    fn test_merge_counts() {
+        // way 1 comes from one input and way 2 from another, sharing node 2
+        let mut cache = empty_cache();
+        cache.insert(way(1, &[1, 2]), 1).unwrap();
+        assert_eq!(cache.node_count(NodeId(2)), 1);
+
+        cache.insert(way(2, &[2, 3]), 1).unwrap();
+        // the same way appearing in a second input is not counted twice
+        cache.insert(way(1, &[1, 2]), 1).unwrap();
+        assert_eq!(cache.node_count(NodeId(1)), 1);
+        assert_eq!(cache.node_count(NodeId(2)), 2);
+        assert_eq!(cache.node_count(NodeId(3)), 1);
+    }
+
+    #[test]
+    fn test_keeps_newest_version() {
+        let mut cache = empty_cache();
+        assert!(cache.insert(way(1, &[1, 2]), 2).unwrap());
+        assert!(!cache.insert(way(1, &[1, 3]), 1).unwrap());
+        assert!(!cache.insert(way(1, &[1, 3]), 2).unwrap());
+        assert_eq!(cache.osm_cache[&WayId(1).into()].way().unwrap().nodes, vec![NodeId(1), NodeId(2)]);
+        assert!(cache.insert(way(1, &[1, 3]), 3).unwrap());
+        assert_eq!(cache.node_count(NodeId(2)), 0);
+        assert_eq!(cache.node_count(NodeId(3)), 1);
+    }
+
+    #[test]
+    fn test_split_at_all_ways() {
+        use crate::edge_list::{basic_roads_config, edge_list_from_xml_with_config};
+        let mut gc = basic_roads_config();
+        gc.split_at = SplitAt::AllWays;
+        let (cache, edge_list) = edge_list_from_xml_with_config(gc, include_str!("../../examples/basic_roads.osm"));
+
+        // the footway isn't in the network but still splits both roads
+        assert!(!cache.osm_cache.contains_key(&WayId(102).into()));
+        assert!(cache.splitting_ways.contains(&WayId(102)));
+        assert_eq!(cache.nodes.entry(NodeId(6)).splits, 1);
+        assert_eq!(cache.nodes.entry(NodeId(2)).splits, 1);
+        let mut edges: Vec<_> = edge_list.edges.iter()
+            .map(|e| (e.way_osmid.0, e.start_node_id.0, e.end_node_id.0))
+            .collect();
+        edges.sort();
+        assert_eq!(edges, vec![(100, 1, 2), (100, 2, 3), (100, 3, 4), (101, 3, 6), (101, 5, 3), (101, 6, 7)]);
+    }
+
+    #[test]
+    fn test_split_at_across_inputs() {
+        // the footway is in one input and the road it crosses in another
+        let mut gc = crate::edge_list::basic_roads_config();
+        gc.split_at = SplitAt::AllWays;
+        let mut cache = OSMCache::new(gc);
+        let footway = r#"<osm>
+  <way id="102"><nd ref="6"/><nd ref="2"/><tag k="highway" v="footway"/></way>
+</osm>"#;
+        cache.load_xml(std::io::Cursor::new(footway)).unwrap();
+        // the same way in another input isn't counted twice
+        cache.load_xml(std::io::Cursor::new(footway)).unwrap();
+        assert_eq!(cache.nodes.entry(NodeId(2)).splits, 1);
+        cache.load_xml(std::io::Cursor::new(r#"<osm>
+  <node id="1" lat="0.0" lon="0.000"/>
+  <node id="2" lat="0.0" lon="0.001"/>
+  <node id="3" lat="0.0" lon="0.002"/>
+  <way id="100"><nd ref="1"/><nd ref="2"/><nd ref="3"/><tag k="highway" v="primary"/></way>
+</osm>"#)).unwrap();
+        assert_eq!(cache.node_count(NodeId(2)), 2);
+        // node 6 isn't in the network
+        assert_eq!(cache.node_count(NodeId(6)), 0);
+    }
 }
@@ -0,0 +1,283 @@
+//! Applying OsmChange (`.osc`) diffs to an `OSMCache` and `EdgeList`
+
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+        BTreeSet,
+    },
+    io::Read,
+};
+use osmpbfreader::{
+    OsmId,
+    OsmObj,
+    NodeId,
+    WayId,
+};
+use crate::{
+    osm_cache::OSMCache,
+    osm_xml::{
+        ChangeAction,
+        decompress,
+        read_changes,
+    },
+    edge_list::{
+        EdgeList,
+        EdgeChanges,
+    },
+    error::Error,
+};
+
+impl OSMCache {
+    /// Apply an OsmChange document to the cache, returning the ways whose
+    /// edges need to be rebuilt.
+    ///
+    /// Changed ways and relations are checked against the graph config again,
+    /// nodes are counted again, and edges are split again where the count of
+    /// a node on a changed way crosses one. Changes with a version no
+    /// newer than the cached object are ignored so a diff can be reapplied.
+    /// Only the ids of ways outside the network that split it are cached, so
+    /// those ways keep splitting it at the nodes they were first read with
+    /// until they join the network or the cache is rebuilt.
+    /// Gzip and bzip2 compressed input is decompressed transparently.
+    pub fn apply_change<R: Read>(&mut self, reader: R) -> Result<BTreeSet<WayId>, Error> {
+        let reader = decompress(reader)?;
+        let mut affected_ways: BTreeSet<WayId> = BTreeSet::new();
+        // nodes that moved, were deleted, or became (or stopped being) a junction
+        let mut touched_nodes: HashSet<NodeId> = HashSet::new();
+        // nodes that aren't cached yet, in case a changed way needs them
+        let mut pending_nodes: HashMap<NodeId, (OsmObj, i32)> = HashMap::new();
+        // the counts of nodes before the change
+        let mut old_counts: HashMap<NodeId, usize> = HashMap::new();
+        // the outlines of multipolygon areas are kept even if they are untagged
+        let area_members: HashSet<WayId> = self.osm_cache.values()
+            .filter_map(|o| o.relation())
+            .filter(|r| r.tags.contains("type", "multipolygon"))
+            .flat_map(|r| r.refs.iter().filter_map(|m| m.member.way()))
+            .collect();
+
+        read_changes(reader, |action, obj, info| {
+            let id = obj.id();
+            let version = info.version;
+            match (action, &obj) {
+                (ChangeAction::Delete, OsmObj::Node(node)) => {
+                    pending_nodes.remove(&node.id);
+                    if self.is_newer(&id, version) && self.remove(&id)?.is_some() {
+                        touched_nodes.insert(node.id);
+                    }
+                },
+                (_, OsmObj::Node(node)) => {
+                    if self.contains_node(node.id) {
+                        if self.insert(obj.clone(), version)? {
+                            touched_nodes.insert(node.id);
+                        }
+                    } else {
+                        pending_nodes.insert(node.id, (obj, version));
+                    }
+                },
+                (_, OsmObj::Way(way)) => {
+                    if !self.is_newer(&id, version) {
+                        return Ok(())
+                    }
+                    let keep = action != ChangeAction::Delete && (
+                        self.graph_config.is_way_match(&way.tags) || area_members.contains(&way.id));
+                    let old_nodes = self.osm_cache.get(&id)
+                        .and_then(|o| o.way())
+                        .map(|w| w.nodes.clone())
+                        .unwrap_or_default();
+                    for nid in old_nodes.iter().chain(way.nodes.iter()) {
+                        self.record_count(*nid, &mut old_counts);
+                    }
+                    let old = self.remove(&id)?;
+                    if keep {
+                        self.insert_with_info(obj.clone(), info)?;
+                    } else if action != ChangeAction::Delete {
+                        // ways outside the network can still split it
+                        self.insert_splitting_way(way)?;
+                    }
+                    if keep || old.is_some() {
+                        affected_ways.insert(way.id);
+                    }
+                },
+                (_, OsmObj::Relation(rel)) => {
+                    if !self.is_newer(&id, version) {
+                        return Ok(())
+                    }
+                    // the edges of both old and new members change their routes
+                    if let Some(OsmObj::Relation(old_rel)) = self.remove(&id)? {
+                        affected_ways.extend(old_rel.refs.iter().filter_map(|r| r.member.way()));
+                    }
+                    if action != ChangeAction::Delete && self.graph_config.is_relation_match(&rel.tags) {
+                        affected_ways.extend(rel.refs.iter().filter_map(|r| r.member.way()));
+                        self.insert(obj.clone(), version)?;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+
+        // changing whether a node joins several ways changes where edges split
+        for (nid, old_count) in old_counts.into_iter() {
+            let new_count = self.node_count(nid);
+            if (old_count > 1) != (new_count > 1) {
+                touched_nodes.insert(nid);
+            }
+            if new_count == 0 {
+                self.remove(&nid.into())?;
+            }
+        }
+
+        // changed ways may reference nodes that only appear in the diff
+        for (nid, (node, version)) in pending_nodes {
+            if self.requires_node(nid) {
+                self.insert(node, version)?;
+                touched_nodes.insert(nid);
+            }
+        }
+
+        for obj in self.osm_cache.values() {
+            if let Some(way) = obj.way() {
+                if way.nodes.iter().any(|n| touched_nodes.contains(n)) {
+                    affected_ways.insert(way.id);
+                }
+            }
+        }
+        Ok(affected_ways)
+    }
+
+    /// Whether `version` is newer than the cached version of an object.
+    /// Objects that aren't cached are always older.
+    fn is_newer(&self, id: &OsmId, version: i32) -> bool {
+        self.version(id).map(|v| version > v).unwrap_or(true)
+    }
+
+    /// Note the count of a node before the change, the first time it's touched
+    fn record_count(&self, nid: NodeId, old_counts: &mut HashMap<NodeId, usize>) {
+        old_counts.entry(nid).or_insert_with(|| self.node_count(nid));
+    }
+}
+
+/// Apply an OsmChange document to a cache and the edge list built from it,
+/// rebuilding only the edges of affected ways
+pub fn apply_change<R: Read>(
+    osm_cache: &mut OSMCache,
+    edge_list: &mut EdgeList<f64>,
+    reader: R
+) -> Result<EdgeChanges, Error> {
+    let affected_ways = osm_cache.apply_change(reader)?;
+    edge_list.rebuild_ways(osm_cache, &affected_ways)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edge_list::{
+        EdgeKey,
+        edge_list_from_xml,
+    };
+
+    const BASIC_ROADS_OSM: &str = include_str!("../../examples/basic_roads.osm");
+
+    fn key(way: i64, start: i64, end: i64) -> EdgeKey {
+        EdgeKey {
+            way_osmid: WayId(way),
+            start_node_id: NodeId(start),
+            end_node_id: NodeId(end)
+        }
+    }
+
+    fn setup() -> (OSMCache, EdgeList<f64>) {
+        edge_list_from_xml(BASIC_ROADS_OSM)
+    }
+
+    fn sorted(mut changes: EdgeChanges) -> EdgeChanges {
+        changes.added.sort();
+        changes.removed.sort();
+        changes.modified.sort();
+        changes
+    }
+
+    #[test]
+    fn test_apply_change() {
+        let (mut cache, mut edge_list) = setup();
+        assert_eq!(edge_list.edges.len(), 4);
+
+        // the footway between nodes 6 and 2 becomes a road and node 5 moves
+        let osc = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osmChange version="0.6">
+  <modify>
+    <way id="102" version="2">
+      <nd ref="6"/>
+      <nd ref="2"/>
+      <tag k="highway" v="residential"/>
+    </way>
+    <node id="5" version="2" lat="-33.8680000" lon="151.2020000"/>
+  </modify>
+</osmChange>"#;
+        let changes = sorted(apply_change(&mut cache, &mut edge_list, osc.as_bytes()).unwrap());
+        assert_eq!(changes.added, vec![
+            key(100, 1, 2), key(100, 2, 3), key(101, 3, 6), key(101, 6, 7), key(102, 6, 2)]);
+        assert_eq!(changes.removed, vec![key(100, 1, 3), key(101, 3, 7)]);
+        assert_eq!(changes.modified, vec![key(101, 5, 3)]);
+        assert_eq!(edge_list.edges.len(), 7);
+        assert_eq!(cache.node_count(NodeId(2)), 2);
+        assert_eq!(cache.node_count(NodeId(6)), 2);
+
+        // reapplying the same diff changes nothing
+        let changes = apply_change(&mut cache, &mut edge_list, osc.as_bytes()).unwrap();
+        assert!(changes.added.is_empty() && changes.removed.is_empty() && changes.modified.is_empty());
+
+        // deleting the new road and King Street leaves George Street as one edge
+        let osc = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osmChange version="0.6">
+  <delete>
+    <way id="101" version="2"/>
+    <way id="102" version="3"/>
+  </delete>
+</osmChange>"#;
+        let changes = sorted(apply_change(&mut cache, &mut edge_list, osc.as_bytes()).unwrap());
+        assert_eq!(changes.added, vec![key(100, 1, 4)]);
+        assert_eq!(changes.removed, vec![
+            key(100, 1, 2), key(100, 2, 3), key(100, 3, 4),
+            key(101, 3, 6), key(101, 5, 3), key(101, 6, 7), key(102, 6, 2)]);
+        assert!(changes.modified.is_empty());
+        assert_eq!(edge_list.edges.len(), 1);
+        assert_eq!(cache.node_count(NodeId(5)), 0);
+        assert!(!cache.contains_node(NodeId(5)));
+        assert_eq!(cache.node_count(NodeId(3)), 1);
+        assert!(changes.incomplete_ways.is_empty());
+    }
+
+    #[test]
+    fn test_tag_change() {
+        let (mut cache, mut edge_list) = setup();
+
+        // George Street becomes one way, and nothing else changes
+        if let Some(OsmObj::Way(way)) = cache.osm_cache.get_mut(&WayId(100).into()) {
+            way.tags.insert("oneway".into(), "yes".into());
+        }
+        let changes = sorted(edge_list.rebuild_ways(&cache, &vec![WayId(100)].into_iter().collect()).unwrap());
+        assert!(changes.added.is_empty() && changes.removed.is_empty());
+        assert_eq!(changes.modified, vec![key(100, 1, 3), key(100, 3, 4)]);
+    }
+
+    #[test]
+    fn test_incomplete_way() {
+        let (mut cache, mut edge_list) = setup();
+
+        // a way that now matches references a node that was never cached
+        let osc = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osmChange version="0.6">
+  <modify>
+    <way id="103" version="2">
+      <nd ref="4"/>
+      <nd ref="8"/>
+      <tag k="highway" v="residential"/>
+    </way>
+  </modify>
+</osmChange>"#;
+        let changes = apply_change(&mut cache, &mut edge_list, osc.as_bytes()).unwrap();
+        assert_eq!(changes.incomplete_ways, vec![WayId(103)]);
+        assert!(edge_list.edges.iter().all(|e| e.way_osmid != WayId(103)));
+    }
+}
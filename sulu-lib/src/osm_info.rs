@@ -0,0 +1,129 @@
+//! The metadata OSM keeps for each version of an object
+
+use osmpbfreader::osmformat::{
+    Info,
+    PrimitiveBlock,
+};
+use serde::{Serialize, Deserialize};
+use crate::error::Error;
+
+/// The version of an object and the edit that created it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OsmInfo {
+    /// -1 if the version isn't recorded
+    pub version: i32,
+    /// Seconds since the unix epoch
+    pub timestamp: Option<i64>,
+    pub changeset: Option<i64>,
+    pub uid: Option<i32>,
+    pub user: Option<String>
+}
+
+impl OsmInfo {
+    /// Info with only a version
+    pub fn with_version(version: i32) -> Self {
+        OsmInfo {
+            version,
+            timestamp: None,
+            changeset: None,
+            uid: None,
+            user: None
+        }
+    }
+
+    /// Read the info block of a pbf way or relation
+    pub fn from_pbf(info: &Info, block: &PrimitiveBlock) -> Self {
+        let user = if info.has_user_sid() {
+            block.get_stringtable().get_s()
+                .get(info.get_user_sid() as usize)
+                .map(|s| String::from_utf8_lossy(s).into_owned())
+        } else {
+            None
+        };
+        OsmInfo {
+            version: info.get_version(),
+            timestamp: if info.has_timestamp() {
+                Some(info.get_timestamp() * block.get_date_granularity() as i64 / 1000)
+            } else {
+                None
+            },
+            changeset: if info.has_changeset() { Some(info.get_changeset()) } else { None },
+            uid: if info.has_uid() { Some(info.get_uid()) } else { None },
+            user
+        }
+    }
+
+    /// The timestamp as an ISO 8601 string, e.g. `2020-03-01T12:00:00Z`
+    pub fn timestamp_string(&self) -> Option<String> {
+        self.timestamp.map(format_timestamp)
+    }
+}
+
+// days since the unix epoch of a date in the proleptic gregorian calendar
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (if m <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, m, d)
+}
+
+/// Parse a UTC date (`2020-03-01`) or timestamp (`2020-03-01T12:00:00Z`)
+/// into seconds since the unix epoch
+pub fn parse_timestamp(s: &str) -> Result<i64, Error> {
+    let invalid = || Error::ConversionError(format!("Invalid timestamp: {}", s));
+    let (date, time) = match s.split_once('T') {
+        Some((date, time)) => (date, Some(time.trim_end_matches('Z'))),
+        None => (s, None)
+    };
+    let parts = |s: &str, sep: char| s.split(sep)
+        .map(|p| p.parse::<i64>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>();
+    let (y, m, d) = match parts(date, '-')?.as_slice() {
+        [y, m, d] if (1..=12).contains(m) && (1..=31).contains(d) => (*y, *m, *d),
+        _ => return Err(invalid())
+    };
+    let seconds = match time {
+        Some(time) => match parts(time, ':')?.as_slice() {
+            [h, min, sec] if *h < 24 && *min < 60 && *sec <= 60 => h * 3600 + min * 60 + sec,
+            _ => return Err(invalid())
+        },
+        None => 0
+    };
+    Ok(days_from_civil(y, m, d) * 86400 + seconds)
+}
+
+/// Format seconds since the unix epoch as an ISO 8601 UTC timestamp
+pub fn format_timestamp(t: i64) -> String {
+    let (y, m, d) = civil_from_days(t.div_euclid(86400));
+    let s = t.rem_euclid(86400);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", y, m, d, s / 3600, s % 3600 / 60, s % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(parse_timestamp("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_timestamp("2020-03-01T12:30:05Z").unwrap(), 1583065805);
+        assert_eq!(format_timestamp(1583065805), "2020-03-01T12:30:05Z");
+        assert_eq!(format_timestamp(-86400), "1969-12-31T00:00:00Z");
+        assert!(parse_timestamp("2020-13-01").is_err());
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+}
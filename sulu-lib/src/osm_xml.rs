@@ -0,0 +1,515 @@
+//! Reading OSM XML (`.osm`, `.osm.gz`, `.osm.bz2`) and OsmChange (`.osc`) documents
+
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    io::{
+        Read,
+        BufRead,
+        BufReader,
+        Seek,
+        SeekFrom,
+    },
+};
+use flate2::read::MultiGzDecoder;
+use bzip2::read::MultiBzDecoder;
+use quick_xml::{
+    Reader,
+    events::{
+        Event,
+        BytesStart,
+    },
+};
+use osmpbfreader::{
+    OsmObj,
+    OsmId,
+    Node,
+    NodeId,
+    Way,
+    WayId,
+    Relation,
+    RelationId,
+    Ref,
+    Tags,
+};
+use crate::{
+    osm_cache::OSMCache,
+    edge_list::is_synthetic_node_id,
+    graph_config::SplitAt,
+    progress::{
+        Phase,
+        Progress,
+        Observer,
+        NoProgress,
+        report,
+    },
+    osm_info::{
+        OsmInfo,
+        parse_timestamp,
+    },
+    error::Error,
+};
+
+/// Wrap a reader so that gzip and bzip2 compressed data is decompressed,
+/// detected from the leading magic bytes
+pub fn decompress<'a, R: Read + 'a>(reader: R) -> Result<Box<dyn BufRead + 'a>, Error> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()
+        .map_err(Error::IoError)?;
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else if magic.starts_with(b"BZh") {
+        Ok(Box::new(BufReader::new(MultiBzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+fn attributes(e: &BytesStart) -> Result<HashMap<Vec<u8>, String>, Error> {
+    let mut attrs = HashMap::new();
+    for attr in e.attributes() {
+        let attr = attr.map_err(|e| Error::XmlError(e.into()))?;
+        let value = attr.unescape_value()
+            .map_err(Error::XmlError)?;
+        attrs.insert(attr.key.as_ref().to_vec(), value.into_owned());
+    }
+    Ok(attrs)
+}
+
+fn parse_attr<T: std::str::FromStr>(
+    attrs: &HashMap<Vec<u8>, String>,
+    key: &str
+) -> Result<T, Error> {
+    attrs.get(key.as_bytes())
+        .ok_or_else(|| Error::MissingInfo(format!("Missing attribute: {}", key)))?
+        .parse()
+        .map_err(|_| Error::ConversionError(format!("Invalid value for attribute: {}", key)))
+}
+
+/// Parse a node id, rejecting the ids reserved for synthetic nodes (see
+/// `SYNTHETIC_NODE_IDS`) so they can't collide with the input's
+fn parse_node_id(attrs: &HashMap<Vec<u8>, String>, key: &str) -> Result<NodeId, Error> {
+    let id = NodeId(parse_attr(attrs, key)?);
+    if is_synthetic_node_id(id) {
+        return Err(Error::ConversionError(format!(
+            "Node id {} is in the range sulu reserves for synthetic nodes", id.0)))
+    }
+    Ok(id)
+}
+
+fn parse_info(attrs: &HashMap<Vec<u8>, String>) -> Result<OsmInfo, Error> {
+    let optional = |key: &str| attrs.contains_key(key.as_bytes());
+    Ok(OsmInfo {
+        version: if optional("version") { parse_attr(attrs, "version")? } else { -1 },
+        timestamp: match attrs.get("timestamp".as_bytes()) {
+            Some(t) => Some(parse_timestamp(t)?),
+            None => None
+        },
+        changeset: if optional("changeset") { Some(parse_attr(attrs, "changeset")?) } else { None },
+        uid: if optional("uid") { Some(parse_attr(attrs, "uid")?) } else { None },
+        user: attrs.get("user".as_bytes()).cloned()
+    })
+}
+
+fn parse_degrees(attrs: &HashMap<Vec<u8>, String>, key: &str) -> Result<i32, Error> {
+    let degrees: f64 = parse_attr(attrs, key)?;
+    Ok((degrees * 1e7).round() as i32)
+}
+
+/// The sections of an OsmChange document
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeAction {
+    Create,
+    Modify,
+    Delete
+}
+
+/// Read the nodes, ways and relations of an OSM XML document, calling `f` with each
+/// object and its info (a version of -1 if the version isn't recorded)
+pub fn read_objects<R, F>(reader: R, mut f: F) -> Result<(), Error>
+where
+    R: BufRead,
+    F: FnMut(OsmObj, OsmInfo) -> Result<(), Error>
+{
+    read_elements(reader, |_, obj, info| f(obj, info))
+}
+
+/// Read the nodes, ways and relations of an OsmChange (`.osc`) document, calling `f`
+/// with the action, object and info of each change
+pub fn read_changes<R, F>(reader: R, mut f: F) -> Result<(), Error>
+where
+    R: BufRead,
+    F: FnMut(ChangeAction, OsmObj, OsmInfo) -> Result<(), Error>
+{
+    read_elements(reader, |action, obj, info| match action {
+        Some(action) => f(action, obj, info),
+        None => Err(Error::MissingInfo(format!(
+            "Change outside of a create, modify or delete section: {:?}", obj.id())))
+    })
+}
+
+fn read_elements<R, F>(reader: R, mut f: F) -> Result<(), Error>
+where
+    R: BufRead,
+    F: FnMut(Option<ChangeAction>, OsmObj, OsmInfo) -> Result<(), Error>
+{
+    let mut reader = Reader::from_reader(reader);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    // the OsmChange section we are in, if any
+    let mut action: Option<ChangeAction> = None;
+    // the node, way or relation currently being read, and its info
+    let mut current: Option<(OsmObj, OsmInfo)> = None;
+    loop {
+        let event = reader.read_event_into(&mut buf)
+            .map_err(Error::XmlError)?;
+        let (e, is_empty) = match &event {
+            Event::Start(e) => (e, false),
+            Event::Empty(e) => (e, true),
+            Event::End(e) => {
+                match e.name().as_ref() {
+                    b"node" | b"way" | b"relation" => {
+                        if let Some((obj, info)) = current.take() {
+                            f(action, obj, info)?;
+                        }
+                    },
+                    b"create" | b"modify" | b"delete" => action = None,
+                    _ => ()
+                }
+                buf.clear();
+                continue
+            },
+            Event::Eof => break,
+            _ => {
+                buf.clear();
+                continue
+            }
+        };
+        match e.name().as_ref() {
+            b"create" => action = Some(ChangeAction::Create),
+            b"modify" => action = Some(ChangeAction::Modify),
+            b"delete" => action = Some(ChangeAction::Delete),
+            b"node" => {
+                let attrs = attributes(e)?;
+                // deleted nodes don't need a position
+                let (lat, lon) = match action {
+                    Some(ChangeAction::Delete) => (
+                        parse_degrees(&attrs, "lat").unwrap_or(0),
+                        parse_degrees(&attrs, "lon").unwrap_or(0)
+                    ),
+                    _ => (parse_degrees(&attrs, "lat")?, parse_degrees(&attrs, "lon")?)
+                };
+                let node = Node {
+                    id: parse_node_id(&attrs, "id")?,
+                    tags: Tags::new(),
+                    decimicro_lat: lat,
+                    decimicro_lon: lon
+                };
+                current = Some((OsmObj::Node(node), parse_info(&attrs)?));
+            },
+            b"way" => {
+                let attrs = attributes(e)?;
+                let way = Way {
+                    id: WayId(parse_attr(&attrs, "id")?),
+                    tags: Tags::new(),
+                    nodes: vec![]
+                };
+                current = Some((OsmObj::Way(way), parse_info(&attrs)?));
+            },
+            b"relation" => {
+                let attrs = attributes(e)?;
+                let rel = Relation {
+                    id: RelationId(parse_attr(&attrs, "id")?),
+                    tags: Tags::new(),
+                    refs: vec![]
+                };
+                current = Some((OsmObj::Relation(rel), parse_info(&attrs)?));
+            },
+            b"member" => {
+                if let Some((OsmObj::Relation(rel), _)) = &mut current {
+                    let attrs = attributes(e)?;
+                    let id: i64 = parse_attr(&attrs, "ref")?;
+                    let member_type: String = parse_attr(&attrs, "type")?;
+                    let member = match member_type.as_str() {
+                        "node" => OsmId::Node(parse_node_id(&attrs, "ref")?),
+                        "way" => OsmId::Way(WayId(id)),
+                        "relation" => OsmId::Relation(RelationId(id)),
+                        _ => return Err(Error::ConversionError(
+                            format!("Unknown member type: {}", member_type)))
+                    };
+                    let role: String = parse_attr(&attrs, "role").unwrap_or_default();
+                    rel.refs.push(Ref { member, role: role.into() });
+                }
+            },
+            b"nd" => {
+                if let Some((OsmObj::Way(way), _)) = &mut current {
+                    let attrs = attributes(e)?;
+                    way.nodes.push(parse_node_id(&attrs, "ref")?);
+                }
+            },
+            b"tag" => {
+                let tags = match &mut current {
+                    Some((OsmObj::Node(node), _)) => Some(&mut node.tags),
+                    Some((OsmObj::Way(way), _)) => Some(&mut way.tags),
+                    Some((OsmObj::Relation(rel), _)) => Some(&mut rel.tags),
+                    None => None
+                };
+                if let Some(tags) = tags {
+                    let attrs = attributes(e)?;
+                    let key: String = parse_attr(&attrs, "k")?;
+                    let value: String = parse_attr(&attrs, "v")?;
+                    tags.insert(key.into(), value.into());
+                }
+            },
+            _ => ()
+        }
+        // self closing nodes, ways and relations have no children
+        if is_empty {
+            match e.name().as_ref() {
+                b"node" | b"way" | b"relation" => {
+                    if let Some((obj, info)) = current.take() {
+                        f(action, obj, info)?;
+                    }
+                },
+                b"create" | b"modify" | b"delete" => action = None,
+                _ => ()
+            }
+        }
+        buf.clear();
+    }
+    Ok(())
+}
+
+/// Progress is reported after this many objects
+const REPORT_EVERY: usize = 10_000;
+
+/// Counts the bytes read from the (possibly compressed) input, for progress
+struct CountingReader<'a, R> {
+    inner: &'a mut R,
+    count: &'a Cell<u64>
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Read every object of an OSM XML document from the start, reporting
+/// progress every `REPORT_EVERY` objects and at the end. `f` returns
+/// whether it selected a way.
+fn for_each_object<R, F>(
+    reader: &mut R,
+    phase: Phase,
+    observer: &mut dyn Observer,
+    mut f: F
+) -> Result<(), Error>
+where
+    R: Read + Seek,
+    F: FnMut(OsmObj, OsmInfo) -> Result<bool, Error>
+{
+    let mut progress = Progress::new(phase);
+    reader.seek(SeekFrom::Start(0))
+        .map_err(Error::IoError)?;
+    let bytes_read = Cell::new(0);
+    let counting = CountingReader {
+        inner: reader,
+        count: &bytes_read
+    };
+    let mut objects = 0;
+    read_objects(decompress(counting)?, |obj, info| {
+        if f(obj, info)? {
+            progress.ways += 1;
+        }
+        progress.bytes_read = bytes_read.get();
+        objects += 1;
+        if objects % REPORT_EVERY == 0 {
+            report(observer, &progress)?;
+        }
+        Ok(())
+    })?;
+    progress.bytes_read = bytes_read.get();
+    report(observer, &progress)
+}
+
+impl OSMCache {
+    /// Load the selected ways, route relations and areas, and the nodes the
+    /// ways reference, from OSM XML, as `load_pbf` does for a pbf.
+    /// Gzip and bzip2 compressed input is decompressed transparently.
+    ///
+    /// The input is read up to three times, like a pbf, so that only the
+    /// selected objects are kept in memory.
+    pub fn load_xml<R: Read + Seek>(&mut self, reader: R) -> Result<(), Error> {
+        self.load_xml_with_observer(reader, &mut NoProgress)
+    }
+
+    /// Load OSM XML as with `load_xml`, reporting progress every 10,000
+    /// objects. XML has no blobs, so only the bytes and ways are counted.
+    pub fn load_xml_with_observer<R: Read + Seek>(
+        &mut self,
+        mut reader: R,
+        observer: &mut dyn Observer
+    ) -> Result<(), Error> {
+        // first pass - select the ways and relations
+        for_each_object(&mut reader, Phase::Ways, observer, |obj, info| {
+            match &obj {
+                OsmObj::Way(way) if self.graph_config.is_way_match(&way.tags) => {
+                    self.insert_with_info(obj, info)?;
+                    return Ok(true)
+                },
+                OsmObj::Relation(rel) if self.graph_config.is_relation_match(&rel.tags) => {
+                    self.insert(obj, info.version)?;
+                },
+                _ => ()
+            }
+            Ok(false)
+        })?;
+
+        // second pass - the outlines of multipolygon areas, and the ways
+        // outside the network that split it
+        let required_ways = self.required_ways();
+        let splitting = !matches!(self.graph_config.split_at, SplitAt::Selected);
+        if !required_ways.is_empty() || splitting {
+            for_each_object(&mut reader, Phase::MemberWays, observer, |obj, info| {
+                if let OsmObj::Way(way) = &obj {
+                    if required_ways.contains(&way.id) {
+                        self.insert_with_info(obj, info)?;
+                        return Ok(true)
+                    } else if splitting {
+                        self.insert_splitting_way(way)?;
+                    }
+                }
+                Ok(false)
+            })?;
+        }
+
+        // last pass - the nodes of every cached way
+        for_each_object(&mut reader, Phase::Nodes, observer, |obj, info| {
+            if let OsmObj::Node(node) = &obj {
+                if self.requires_node(node.id) {
+                    self.insert(obj, info.version)?;
+                }
+            }
+            Ok(false)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{
+        Cursor,
+        Write,
+    };
+    use osmpbfreader::OsmId;
+    use crate::graph_config::GraphConfig;
+
+    const XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6" generator="JOSM">
+  <node id="1" version="2" lat="-33.8600000" lon="151.2100000"/>
+  <node id="2" version="1" lat="-33.8610000" lon="151.2110000">
+    <tag k="highway" v="traffic_signals"/>
+  </node>
+  <node id="3" lat="-33.8620000" lon="151.2120000"/>
+  <node id="4" lat="-33.8630000" lon="151.2130000"/>
+  <way id="10" version="3" timestamp="2020-03-01T12:30:05Z" changeset="81234" uid="42" user="mapper">
+    <nd ref="1"/>
+    <nd ref="2"/>
+    <nd ref="3"/>
+    <tag k="highway" v="primary"/>
+    <tag k="name" v="George &amp; King"/>
+  </way>
+  <way id="11" version="1">
+    <nd ref="3"/>
+    <nd ref="4"/>
+    <tag k="building" v="yes"/>
+  </way>
+</osm>"#;
+
+    fn graph_config() -> GraphConfig {
+        serde_json::from_str(r#"{
+            "name": "roads",
+            "options": [
+                {"name": "road", "requires": [{"key": "highway", "kind": "all"}], "excludes": []}
+            ]
+        }"#).unwrap()
+    }
+
+    fn check(cache: &OSMCache) {
+        let way = cache.osm_cache[&OsmId::Way(WayId(10))].way().unwrap();
+        assert_eq!(way.nodes, vec![NodeId(1), NodeId(2), NodeId(3)]);
+        assert!(way.tags.contains("name", "George & King"));
+        assert_eq!(cache.versions[&OsmId::Way(WayId(10))], 3);
+        let info = &cache.way_info[&WayId(10)];
+        assert_eq!(info.timestamp, Some(1583065805));
+        assert_eq!(info.changeset, Some(81234));
+        assert_eq!(info.uid, Some(42));
+        assert_eq!(info.user.as_deref(), Some("mapper"));
+        assert!(!cache.osm_cache.contains_key(&OsmId::Way(WayId(11))));
+        assert!(!cache.contains_node(NodeId(4)));
+
+        let node = cache.node(NodeId(2)).unwrap();
+        assert_eq!(node.decimicro_lat, -338_610_000);
+        assert_eq!(node.decimicro_lon, 1_512_110_000);
+        assert!(node.tags.contains("highway", "traffic_signals"));
+        assert_eq!(cache.version(&OsmId::Node(NodeId(3))), Some(-1));
+        assert_eq!(cache.node_count(NodeId(3)), 1);
+    }
+
+    #[test]
+    fn test_load_xml() {
+        let mut cache = OSMCache::new(graph_config());
+        cache.load_xml(Cursor::new(XML)).unwrap();
+        check(&cache);
+    }
+
+    #[test]
+    fn test_load_xml_progress() {
+        let mut cache = OSMCache::new(graph_config());
+        let mut reports = vec![];
+        cache.load_xml_with_observer(Cursor::new(XML), &mut |p: &Progress| {
+            reports.push((p.phase, p.ways, p.bytes_read));
+            true
+        }).unwrap();
+        check(&cache);
+        // no multipolygons or splitting ways, so no member ways pass
+        let len = XML.len() as u64;
+        assert_eq!(reports, vec![(Phase::Ways, 1, len), (Phase::Nodes, 0, len)]);
+
+        let mut cache = OSMCache::new(graph_config());
+        let result = cache.load_xml_with_observer(Cursor::new(XML), &mut |_: &Progress| false);
+        assert!(matches!(result, Err(Error::Cancelled)));
+    }
+
+    #[test]
+    fn test_negative_ids() {
+        // new objects in JOSM have small negative ids
+        let josm = XML.replace("id=\"1\"", "id=\"-1\"").replace("ref=\"1\"", "ref=\"-1\"");
+        let mut cache = OSMCache::new(graph_config());
+        cache.load_xml(Cursor::new(josm)).unwrap();
+        assert!(cache.contains_node(NodeId(-1)));
+
+        // but ids in the synthetic range would collide with split nodes
+        let reserved = XML.replace("ref=\"1\"", "ref=\"-4611686018427387904\"");
+        let mut cache = OSMCache::new(graph_config());
+        assert!(matches!(cache.load_xml(Cursor::new(reserved)), Err(Error::ConversionError(_))));
+    }
+
+    #[test]
+    fn test_load_compressed_xml() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(XML.as_bytes()).unwrap();
+        let mut cache = OSMCache::new(graph_config());
+        cache.load_xml(Cursor::new(gz.finish().unwrap())).unwrap();
+        check(&cache);
+
+        let mut bz = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+        bz.write_all(XML.as_bytes()).unwrap();
+        let mut cache = OSMCache::new(graph_config());
+        cache.load_xml(Cursor::new(bz.finish().unwrap())).unwrap();
+        check(&cache);
+    }
+}
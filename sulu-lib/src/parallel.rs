@@ -0,0 +1,220 @@
+//! Duplicate and parallel edges
+//!
+//! Overlapping ways give edges with the same geometry, and two ways joining
+//! the same pair of nodes give parallel edges. Many graph libraries expect
+//! a simple graph, so these can be reported, duplicates dropped, and
+//! parallel edges split at a synthetic node.
+
+use std::collections::BTreeMap;
+use serde::Serialize;
+use osmpbfreader::NodeId;
+use crate::edge_list::{
+    Edge,
+    EdgeKey,
+    EdgeList,
+};
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ParallelEdgesReport {
+    /// Groups of edges with exactly the same geometry, drawn either way,
+    /// that can be travelled the same ways and have the same graph config
+    /// option
+    pub duplicates: Vec<Vec<EdgeKey>>,
+    /// Groups of edges joining the same pair of nodes, with more than one
+    /// geometry between them
+    pub parallel: Vec<Vec<EdgeKey>>
+}
+
+impl ParallelEdgesReport {
+    pub fn is_empty(&self) -> bool {
+        self.duplicates.is_empty() && self.parallel.is_empty()
+    }
+}
+
+impl std::fmt::Display for ParallelEdgesReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} groups of duplicate edges and {} groups of parallel edges",
+               self.duplicates.len(), self.parallel.len())
+    }
+}
+
+/// The coordinates of an edge as bits, in whichever direction sorts first,
+/// with the edge's direction and graph config option, so an edge and its
+/// reverse compare equal but opposite one way streets don't
+fn duplicate_key(edge: &Edge<f64>) -> (Vec<(u64, u64)>, String, String) {
+    let forward: Vec<(u64, u64)> = edge.geometry.0.iter()
+        .map(|c| (c.x.to_bits(), c.y.to_bits()))
+        .collect();
+    let mut backward = forward.clone();
+    backward.reverse();
+    let option = edge.graph_config_option.name.clone();
+    if backward < forward {
+        (backward, edge.direction.reversed().to_string(), option)
+    } else {
+        (forward, edge.direction.to_string(), option)
+    }
+}
+
+/// The indices of the edges between each pair of nodes, in edge list order
+fn node_pairs(edges: &[Edge<f64>]) -> BTreeMap<(NodeId, NodeId), Vec<usize>> {
+    let mut pairs: BTreeMap<(NodeId, NodeId), Vec<usize>> = BTreeMap::new();
+    for (i, edge) in edges.iter().enumerate() {
+        let pair = if edge.start_node_id <= edge.end_node_id {
+            (edge.start_node_id, edge.end_node_id)
+        } else {
+            (edge.end_node_id, edge.start_node_id)
+        };
+        pairs.entry(pair).or_default().push(i);
+    }
+    pairs
+}
+
+/// The indices of the edges between each pair of nodes, grouped by
+/// geometry, direction and graph config option
+fn duplicate_groups(edges: &[Edge<f64>]) -> Vec<Vec<Vec<usize>>> {
+    node_pairs(edges).into_values()
+        .map(|indices| {
+            let mut geometries: BTreeMap<_, Vec<usize>> = BTreeMap::new();
+            for i in indices {
+                geometries.entry(duplicate_key(&edges[i])).or_default().push(i);
+            }
+            let mut groups: Vec<Vec<usize>> = geometries.into_values().collect();
+            groups.sort();
+            groups
+        })
+        .collect()
+}
+
+impl EdgeList<f64> {
+    /// Find the edges that duplicate another edge, and the pairs of nodes
+    /// joined by more than one edge that aren't duplicates
+    pub fn parallel_edges(&self) -> ParallelEdgesReport {
+        let mut report = ParallelEdgesReport::default();
+        let keys = |indices: &[usize]| indices.iter().map(|i| self.edges[*i].key()).collect();
+        for groups in duplicate_groups(&self.edges) {
+            for group in groups.iter().filter(|g| g.len() > 1) {
+                report.duplicates.push(keys(group));
+            }
+            if groups.len() > 1 {
+                let mut indices: Vec<usize> = groups.into_iter().flatten().collect();
+                indices.sort();
+                report.parallel.push(keys(&indices));
+            }
+        }
+        report
+    }
+
+    /// Drop every edge that duplicates an earlier edge, see
+    /// `ParallelEdgesReport::duplicates`. The kept edge gets the route
+    /// memberships of the dropped ones.
+    /// Returns the number of edges dropped.
+    pub fn drop_duplicate_edges(&mut self) -> usize {
+        let mut drop = vec![false; self.edges.len()];
+        for group in duplicate_groups(&self.edges).into_iter().flatten() {
+            for i in group[1..].iter() {
+                drop[*i] = true;
+                let routes = self.edges[*i].routes.clone();
+                let kept = &mut self.edges[group[0]];
+                for route in routes {
+                    if !kept.routes.contains(&route) {
+                        kept.routes.push(route);
+                    }
+                }
+            }
+        }
+        let dropped = drop.iter().filter(|d| **d).count();
+        let mut drop = drop.into_iter();
+        self.edges.retain(|_| !drop.next().unwrap_or(false));
+        if dropped > 0 {
+            self.invalidate_ids();
+        }
+        dropped
+    }
+
+    /// Split every edge but the first between each pair of nodes at its
+    /// middle, at a synthetic node (see `synthetic_node_id`), so that no two
+    /// edges join the same nodes. Returns the number of edges split.
+    pub fn split_parallel_edges(&mut self) -> usize {
+        let mut split = vec![false; self.edges.len()];
+        for indices in node_pairs(&self.edges).into_values() {
+            for i in indices[1..].iter() {
+                split[*i] = true;
+            }
+        }
+        let mut count = 0;
+        let edges = std::mem::take(&mut self.edges);
+        for (edge, split) in edges.into_iter().zip(split) {
+            match edge.split_at(edge.length_m / 2.0).filter(|_| split) {
+                Some((first, second)) => {
+                    self.edges.push(first);
+                    self.edges.push(second);
+                    count += 1;
+                },
+                None => self.edges.push(edge)
+            }
+        }
+        if count > 0 {
+            self.invalidate_ids();
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use osmpbfreader::WayId;
+    use crate::edge_list::edge_list_from_xml;
+
+    #[test]
+    fn test_parallel_edges() {
+        // way 11 is a copy of way 10 drawn the other way, and way 12 takes
+        // a detour between the same nodes
+        let xml = r#"<osm>
+  <node id="1" lat="0.0" lon="0.000"/>
+  <node id="2" lat="0.0" lon="0.001"/>
+  <node id="3" lat="0.0005" lon="0.0005"/>
+  <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+  <way id="11"><nd ref="2"/><nd ref="1"/><tag k="highway" v="primary"/></way>
+  <way id="12"><nd ref="1"/><nd ref="3"/><nd ref="2"/><tag k="highway" v="residential"/></way>
+</osm>"#;
+        let (_, mut edge_list) = edge_list_from_xml(xml);
+
+        let report = edge_list.parallel_edges();
+        assert_eq!(report.duplicates.len(), 1);
+        assert_eq!(report.duplicates[0].iter().map(|k| k.way_osmid.0).collect::<Vec<_>>(), vec![10, 11]);
+        assert_eq!(report.parallel.len(), 1);
+        assert_eq!(report.parallel[0].len(), 3);
+
+        assert_eq!(edge_list.drop_duplicate_edges(), 1);
+        assert_eq!(edge_list.edges.len(), 2);
+        let length = edge_list.edges[1].length_m;
+        assert_eq!(edge_list.split_parallel_edges(), 1);
+        assert_eq!(edge_list.edges.len(), 3);
+        assert!(edge_list.parallel_edges().is_empty());
+
+        // the detour is split at its middle node, 3
+        let (first, second) = (&edge_list.edges[1], &edge_list.edges[2]);
+        assert_eq!(first.end_node_id, crate::edge_list::synthetic_node_id(WayId(12), length / 2.0));
+        assert_eq!(first.end_node_id, second.start_node_id);
+        let middle = first.geometry.0.last().unwrap();
+        assert!((middle.x - 0.0005).abs() < 1e-9 && (middle.y - 0.0005).abs() < 1e-9);
+        assert!((first.length_m + second.length_m - length).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_opposite_one_way_streets() {
+        // the same street mapped as a one way way in each direction
+        let xml = r#"<osm>
+  <node id="1" lat="0.0" lon="0.000"/>
+  <node id="2" lat="0.0" lon="0.001"/>
+  <way id="10"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/><tag k="oneway" v="yes"/></way>
+  <way id="11"><nd ref="2"/><nd ref="1"/><tag k="highway" v="primary"/><tag k="oneway" v="yes"/></way>
+</osm>"#;
+        let (_, mut edge_list) = edge_list_from_xml(xml);
+        let report = edge_list.parallel_edges();
+        assert!(report.duplicates.is_empty());
+        assert_eq!(report.parallel.len(), 1);
+        assert_eq!(edge_list.drop_duplicate_edges(), 0);
+        assert_eq!(edge_list.edges.len(), 2);
+    }
+}
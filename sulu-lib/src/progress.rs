@@ -0,0 +1,112 @@
+//! Progress reporting and cancellation for long running builds
+
+use crate::error::Error;
+
+/// The stage of a build
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Selecting ways and relations from the input
+    Ways,
+    /// Reading multipolygon outlines and the ways that split the network
+    MemberWays,
+    /// Reading the nodes of the selected ways
+    Nodes,
+    /// Converting ways to edges
+    Edges
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Phase::Ways => write!(f, "Selecting ways"),
+            Phase::MemberWays => write!(f, "Reading member ways"),
+            Phase::Nodes => write!(f, "Reading nodes"),
+            Phase::Edges => write!(f, "Building edges")
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub phase: Phase,
+    /// The number of pbf blobs read in this phase, always 0 for XML
+    pub blobs: usize,
+    /// The number of bytes of the input read in this phase
+    pub bytes_read: u64,
+    /// The number of ways selected so far, or converted to edges in the
+    /// `Edges` phase
+    pub ways: usize,
+    /// The number of ways to convert in the `Edges` phase
+    pub total_ways: Option<usize>
+}
+
+impl Progress {
+    pub fn new(phase: Phase) -> Self {
+        Progress {
+            phase,
+            blobs: 0,
+            bytes_read: 0,
+            ways: 0,
+            total_ways: None
+        }
+    }
+}
+
+/// Receives progress updates during a build. Returning false from
+/// `progress` cancels the build, which then fails with `Error::Cancelled`.
+pub trait Observer {
+    fn progress(&mut self, progress: &Progress) -> bool;
+}
+
+/// An observer that ignores progress and never cancels
+pub struct NoProgress;
+
+impl Observer for NoProgress {
+    fn progress(&mut self, _progress: &Progress) -> bool {
+        true
+    }
+}
+
+impl<F: FnMut(&Progress) -> bool> Observer for F {
+    fn progress(&mut self, progress: &Progress) -> bool {
+        self(progress)
+    }
+}
+
+/// Report progress, turning a request to stop into an error
+pub(crate) fn report(observer: &mut dyn Observer, progress: &Progress) -> Result<(), Error> {
+    if observer.progress(progress) {
+        Ok(())
+    } else {
+        Err(Error::Cancelled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        osm_cache::OSMCache,
+        edge_list::{
+            EdgeList,
+            basic_roads_config,
+        },
+    };
+
+    #[test]
+    fn test_cancel_edges() {
+        let mut cache = OSMCache::new(basic_roads_config());
+        cache.load_xml(std::io::Cursor::new(include_str!("../../examples/basic_roads.osm"))).unwrap();
+
+        let mut reports = vec![];
+        let edge_list = EdgeList::from_cache_with_observer(&cache, &mut |p: &Progress| {
+            reports.push((p.phase, p.ways, p.total_ways));
+            true
+        }).unwrap();
+        assert_eq!(edge_list.edges.len(), 4);
+        assert_eq!(reports, vec![(Phase::Edges, 0, Some(2)), (Phase::Edges, 2, Some(2))]);
+
+        let result = EdgeList::from_cache_with_observer(&cache, &mut |_: &Progress| false);
+        assert!(matches!(result, Err(Error::Cancelled)));
+    }
+}
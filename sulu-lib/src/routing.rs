@@ -0,0 +1,477 @@
+//! Shortest paths over an edge list
+//!
+//! A quick check that a network routes: a `Graph` of the edges in the
+//! directions they can be travelled, with the cost of each from their length
+//! or any other attribute, searched with Dijkstra's algorithm or A*. Routes
+//! can run between nodes, or between points snapped to the nearest edge,
+//! starting and ending part way along an edge.
+
+use std::{
+    cmp::Ordering,
+    collections::{
+        BinaryHeap,
+        HashMap,
+    },
+};
+use geo::{
+    Coordinate,
+    LineString,
+    Point,
+    algorithm::{
+        geodesic_distance::GeodesicDistance,
+        haversine_distance::HaversineDistance,
+    },
+};
+use osmpbfreader::NodeId;
+use crate::{
+    edge_list::{
+        Edge,
+        EdgeList,
+    },
+    error::Error,
+};
+
+const METRES_PER_DEGREE: f64 = 6_371_008.8 * std::f64::consts::PI / 180.0;
+/// Haversine distances can be a little over geodesic lengths, so the A*
+/// heuristic is scaled down to stay under the true cost
+const HEURISTIC_SCALE: f64 = 0.99;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Algorithm {
+    Dijkstra,
+    /// Dijkstra's algorithm guided by the straight line distance to the end
+    #[default]
+    AStar
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dijkstra" => Ok(Algorithm::Dijkstra),
+            "astar" => Ok(Algorithm::AStar),
+            _ => Err(Error::ConversionError(format!("Unknown routing algorithm: {}", s)))
+        }
+    }
+}
+
+/// The nearest point on an edge to a longitude and latitude
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Snap {
+    /// The index of the edge in `EdgeList::edges`
+    pub edge: usize,
+    /// How far along the edge the point is, from 0 at its start node to 1
+    /// at its end node
+    pub fraction: f64,
+    pub point: Coordinate<f64>,
+    /// From the longitude and latitude to the point
+    pub distance_m: f64
+}
+
+/// Travelling along all or part of an edge
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    pub edge: usize,
+    pub forward: bool,
+    /// The part of the edge travelled, as fractions of the way from its
+    /// start node to its end node
+    pub from_fraction: f64,
+    pub to_fraction: f64
+}
+
+impl Step {
+    fn whole(edge: usize, forward: bool) -> Self {
+        let (from_fraction, to_fraction) = if forward { (0.0, 1.0) } else { (1.0, 0.0) };
+        Step { edge, forward, from_fraction, to_fraction }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub cost: f64,
+    pub steps: Vec<Step>
+}
+
+/// The distance along a geometry to each of its points
+fn cumulative_lengths(coords: &[Coordinate<f64>]) -> Vec<f64> {
+    let mut lengths = Vec::with_capacity(coords.len());
+    let mut total = 0.0;
+    for (i, coord) in coords.iter().enumerate() {
+        if i > 0 {
+            total += Point::from(coords[i - 1]).geodesic_distance(&Point::from(*coord));
+        }
+        lengths.push(total);
+    }
+    lengths
+}
+
+/// The point a fraction of the way along a geometry
+fn point_at(coords: &[Coordinate<f64>], lengths: &[f64], fraction: f64) -> Coordinate<f64> {
+    let distance = lengths.last().copied().unwrap_or(0.0) * fraction;
+    for i in 1..coords.len() {
+        if lengths[i] >= distance {
+            let segment = lengths[i] - lengths[i - 1];
+            let t = if segment > 0.0 { (distance - lengths[i - 1]) / segment } else { 0.0 };
+            let (a, b) = (coords[i - 1], coords[i]);
+            return Coordinate { x: a.x + (b.x - a.x) * t, y: a.y + (b.y - a.y) * t }
+        }
+    }
+    coords.last().copied().unwrap_or(Coordinate { x: 0.0, y: 0.0 })
+}
+
+impl Route {
+    /// The length of the route, from the `length_m` of its edges
+    pub fn length_m(&self, edge_list: &EdgeList<f64>) -> f64 {
+        self.steps.iter()
+            .map(|s| edge_list.edges[s.edge].length_m * (s.to_fraction - s.from_fraction).abs())
+            .sum()
+    }
+
+    /// The geometry of the route, cutting the edges it starts and ends part
+    /// way along
+    pub fn geometry(&self, edge_list: &EdgeList<f64>) -> LineString<f64> {
+        let mut route: Vec<Coordinate<f64>> = vec![];
+        for step in self.steps.iter() {
+            let coords = &edge_list.edges[step.edge].geometry.0;
+            let lengths = cumulative_lengths(coords);
+            let total = lengths.last().copied().unwrap_or(0.0);
+            let (low, high) = if step.forward {
+                (step.from_fraction, step.to_fraction)
+            } else {
+                (step.to_fraction, step.from_fraction)
+            };
+            let mut piece = vec![point_at(coords, &lengths, low)];
+            piece.extend(coords.iter().zip(lengths.iter())
+                .filter(|(_, l)| **l > low * total && **l < high * total)
+                .map(|(c, _)| *c));
+            piece.push(point_at(coords, &lengths, high));
+            if !step.forward {
+                piece.reverse();
+            }
+            for coord in piece {
+                if route.last() != Some(&coord) {
+                    route.push(coord);
+                }
+            }
+        }
+        route.into()
+    }
+}
+
+impl EdgeList<f64> {
+    /// Snap a longitude and latitude to the nearest point on any edge, by
+    /// checking every segment
+    pub fn snap(&self, lon: f64, lat: f64) -> Option<Snap> {
+        // metres east and north of the point
+        let x_scale = METRES_PER_DEGREE * lat.to_radians().cos();
+        let local = |c: &Coordinate<f64>| ((c.x - lon) * x_scale, (c.y - lat) * METRES_PER_DEGREE);
+        let mut nearest: Option<(f64, usize, usize, f64)> = None;
+        for (i, edge) in self.edges.iter().enumerate() {
+            for (j, segment) in edge.geometry.0.windows(2).enumerate() {
+                let ((ax, ay), (bx, by)) = (local(&segment[0]), local(&segment[1]));
+                let (dx, dy) = (bx - ax, by - ay);
+                let length_squared = dx * dx + dy * dy;
+                let t = if length_squared > 0.0 {
+                    (-(ax * dx + ay * dy) / length_squared).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                let distance = (ax + t * dx).hypot(ay + t * dy);
+                if nearest.is_none_or(|(d, _, _, _)| distance < d) {
+                    nearest = Some((distance, i, j, t));
+                }
+            }
+        }
+        let (distance_m, edge, segment, t) = nearest?;
+        let coords = &self.edges[edge].geometry.0;
+        let lengths = cumulative_lengths(coords);
+        let total = lengths.last().copied().unwrap_or(0.0);
+        let along = lengths[segment] + t * (lengths[segment + 1] - lengths[segment]);
+        let (a, b) = (coords[segment], coords[segment + 1]);
+        Some(Snap {
+            edge,
+            fraction: if total > 0.0 { along / total } else { 0.0 },
+            point: Coordinate { x: a.x + (b.x - a.x) * t, y: a.y + (b.y - a.y) * t },
+            distance_m
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Arc {
+    to: usize,
+    edge: usize,
+    forward: bool,
+    cost: f64
+}
+
+/// A node in the search queue, ordered so the lowest estimate is popped
+/// first
+struct Queued {
+    estimate: f64,
+    cost: f64,
+    node: usize
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.total_cmp(&self.estimate).then(other.node.cmp(&self.node))
+    }
+}
+
+/// The adjacency of an edge list's nodes, with the cost of travelling each
+/// edge in each direction
+pub struct Graph {
+    node_indices: HashMap<NodeId, usize>,
+    coords: Vec<Coordinate<f64>>,
+    arcs: Vec<Vec<Arc>>,
+    /// The start and end node of each edge
+    ends: Vec<(usize, usize)>,
+    /// The cost of each edge forward and backward, `None` where it can't be
+    /// travelled that way
+    costs: Vec<(Option<f64>, Option<f64>)>,
+    /// The lowest cost per metre of any edge, which keeps the A* heuristic
+    /// under the true cost
+    min_cost_per_m: f64
+}
+
+impl Graph {
+    /// Build a graph with `cost(index, edge, forward)` for each direction
+    /// an edge can be travelled in. Costs must not be negative; `None`
+    /// leaves the edge out in that direction.
+    pub fn new<F: Fn(usize, &Edge<f64>, bool) -> Option<f64>>(edge_list: &EdgeList<f64>, cost: F) -> Self {
+        let mut graph = Graph {
+            node_indices: HashMap::new(),
+            coords: vec![],
+            arcs: vec![],
+            ends: Vec::with_capacity(edge_list.edges.len()),
+            costs: Vec::with_capacity(edge_list.edges.len()),
+            min_cost_per_m: f64::INFINITY
+        };
+        for (i, edge) in edge_list.edges.iter().enumerate() {
+            let start = graph.node(edge.start_node_id, edge.geometry.0.first());
+            let end = graph.node(edge.end_node_id, edge.geometry.0.last());
+            let valid = |c: Option<f64>| c.filter(|c| c.is_finite() && *c >= 0.0);
+            let forward = if edge.direction.forward() { valid(cost(i, edge, true)) } else { None };
+            let backward = if edge.direction.backward() { valid(cost(i, edge, false)) } else { None };
+            for (c, from, to, is_forward) in [(forward, start, end, true), (backward, end, start, false)].iter() {
+                if let Some(c) = c {
+                    graph.arcs[*from].push(Arc { to: *to, edge: i, forward: *is_forward, cost: *c });
+                    if edge.length_m > 0.0 {
+                        graph.min_cost_per_m = graph.min_cost_per_m.min(c / edge.length_m);
+                    }
+                }
+            }
+            graph.ends.push((start, end));
+            graph.costs.push((forward, backward));
+        }
+        if !graph.min_cost_per_m.is_finite() {
+            graph.min_cost_per_m = 0.0;
+        }
+        graph
+    }
+
+    /// A graph with the length of each edge as its cost
+    pub fn by_length(edge_list: &EdgeList<f64>) -> Self {
+        Graph::new(edge_list, |_, edge, _| Some(edge.length_m))
+    }
+
+    fn node(&mut self, node_id: NodeId, coord: Option<&Coordinate<f64>>) -> usize {
+        if let Some(i) = self.node_indices.get(&node_id) {
+            return *i
+        }
+        let i = self.coords.len();
+        self.node_indices.insert(node_id, i);
+        self.coords.push(coord.copied().unwrap_or(Coordinate { x: 0.0, y: 0.0 }));
+        self.arcs.push(vec![]);
+        i
+    }
+
+    /// The cheapest route from `sources` (nodes, the cost of reaching them
+    /// and how) to `targets` (nodes, the cost of finishing from them and
+    /// how), guided towards `goal` for A*
+    fn search(
+        &self,
+        sources: &HashMap<usize, (f64, Option<Step>)>,
+        targets: &HashMap<usize, (f64, Option<Step>)>,
+        goal: Option<Coordinate<f64>>
+    ) -> Option<Route> {
+        let heuristic = |node: usize| match goal {
+            Some(goal) => Point::from(self.coords[node]).haversine_distance(&Point::from(goal))
+                * self.min_cost_per_m * HEURISTIC_SCALE,
+            None => 0.0
+        };
+        let mut costs = vec![f64::INFINITY; self.coords.len()];
+        let mut previous: Vec<Option<Arc>> = vec![None; self.coords.len()];
+        let mut from_node: Vec<usize> = (0..self.coords.len()).collect();
+        let mut queue = BinaryHeap::new();
+        for (node, (cost, _)) in sources.iter() {
+            costs[*node] = *cost;
+            queue.push(Queued { estimate: cost + heuristic(*node), cost: *cost, node: *node });
+        }
+        let mut best: Option<(f64, usize)> = None;
+        while let Some(Queued { estimate, cost, node }) = queue.pop() {
+            if best.is_some_and(|(b, _)| estimate >= b) {
+                break
+            }
+            if cost > costs[node] {
+                continue
+            }
+            if let Some((finish, _)) = targets.get(&node) {
+                if best.is_none_or(|(b, _)| cost + finish < b) {
+                    best = Some((cost + finish, node));
+                }
+            }
+            for arc in self.arcs[node].iter() {
+                let next = cost + arc.cost;
+                if next < costs[arc.to] {
+                    costs[arc.to] = next;
+                    previous[arc.to] = Some(*arc);
+                    from_node[arc.to] = node;
+                    queue.push(Queued { estimate: next + heuristic(arc.to), cost: next, node: arc.to });
+                }
+            }
+        }
+
+        let (cost, end) = best?;
+        let mut steps: Vec<Step> = targets[&end].1.into_iter().collect();
+        let mut node = end;
+        while let Some(arc) = previous[node] {
+            steps.push(Step::whole(arc.edge, arc.forward));
+            node = from_node[node];
+        }
+        steps.extend(sources[&node].1);
+        steps.reverse();
+        Some(Route { cost, steps })
+    }
+
+    fn algorithm_goal(&self, algorithm: Algorithm, goal: Coordinate<f64>) -> Option<Coordinate<f64>> {
+        match algorithm {
+            Algorithm::AStar => Some(goal),
+            Algorithm::Dijkstra => None
+        }
+    }
+
+    /// The cheapest route between two nodes
+    pub fn shortest_path(&self, from: NodeId, to: NodeId, algorithm: Algorithm) -> Option<Route> {
+        let (from, to) = (*self.node_indices.get(&from)?, *self.node_indices.get(&to)?);
+        let sources = vec![(from, (0.0, None))].into_iter().collect();
+        let targets = vec![(to, (0.0, None))].into_iter().collect();
+        self.search(&sources, &targets, self.algorithm_goal(algorithm, self.coords[to]))
+    }
+
+    /// The cheapest route between two snapped points, which starts and
+    /// ends part way along their edges
+    pub fn route(&self, from: &Snap, to: &Snap, algorithm: Algorithm) -> Option<Route> {
+        let keep_cheapest = |map: &mut HashMap<usize, (f64, Option<Step>)>, node: usize, cost: f64, step: Step| {
+            if map.get(&node).is_none_or(|(c, _)| cost < *c) {
+                map.insert(node, (cost, Some(step)));
+            }
+        };
+        let ((start, end), (forward, backward)) = (self.ends[from.edge], self.costs[from.edge]);
+        let mut sources = HashMap::new();
+        if let Some(c) = forward {
+            keep_cheapest(&mut sources, end, c * (1.0 - from.fraction),
+                          Step { edge: from.edge, forward: true, from_fraction: from.fraction, to_fraction: 1.0 });
+        }
+        if let Some(c) = backward {
+            keep_cheapest(&mut sources, start, c * from.fraction,
+                          Step { edge: from.edge, forward: false, from_fraction: from.fraction, to_fraction: 0.0 });
+        }
+        let ((start, end), (forward, backward)) = (self.ends[to.edge], self.costs[to.edge]);
+        let mut targets = HashMap::new();
+        if let Some(c) = forward {
+            keep_cheapest(&mut targets, start, c * to.fraction,
+                          Step { edge: to.edge, forward: true, from_fraction: 0.0, to_fraction: to.fraction });
+        }
+        if let Some(c) = backward {
+            keep_cheapest(&mut targets, end, c * (1.0 - to.fraction),
+                          Step { edge: to.edge, forward: false, from_fraction: 1.0, to_fraction: to.fraction });
+        }
+
+        let mut route = self.search(&sources, &targets, self.algorithm_goal(algorithm, to.point));
+        // along the one edge, without leaving it
+        if from.edge == to.edge {
+            let forward = to.fraction >= from.fraction;
+            let cost = if forward { self.costs[to.edge].0 } else { self.costs[to.edge].1 };
+            if let Some(c) = cost {
+                let cost = c * (to.fraction - from.fraction).abs();
+                if route.as_ref().is_none_or(|r| cost <= r.cost) {
+                    let step = Step { edge: to.edge, forward, from_fraction: from.fraction, to_fraction: to.fraction };
+                    route = Some(Route { cost, steps: vec![step] });
+                }
+            }
+        }
+        route.map(|mut r| {
+            r.steps.retain(|s| s.from_fraction != s.to_fraction);
+            r
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edge_list::edge_list_from_xml;
+
+    #[test]
+    fn test_route() {
+        // a square of two-way streets, split into two edges at nodes 1 and
+        // 3, with a one way short cut across it from 1 to 3
+        let xml = r#"<osm>
+  <node id="1" lat="0.0" lon="0.0"/>
+  <node id="2" lat="0.0" lon="0.001"/>
+  <node id="3" lat="0.001" lon="0.001"/>
+  <node id="4" lat="0.001" lon="0.0"/>
+  <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="1"/><tag k="highway" v="primary"/></way>
+  <way id="11"><nd ref="1"/><nd ref="3"/><tag k="highway" v="primary"/><tag k="oneway" v="yes"/></way>
+</osm>"#;
+        let (_, edge_list) = edge_list_from_xml(xml);
+        let graph = Graph::by_length(&edge_list);
+        let side = edge_list.edges[0].length_m / 2.0;
+
+        for algorithm in [Algorithm::Dijkstra, Algorithm::AStar].iter() {
+            let there = graph.shortest_path(NodeId(1), NodeId(3), *algorithm).unwrap();
+            assert_eq!(there.steps.len(), 1);
+            assert_eq!(edge_list.edges[there.steps[0].edge].way_osmid.0, 11);
+            // the long way round coming back
+            let back = graph.shortest_path(NodeId(3), NodeId(1), *algorithm).unwrap();
+            assert_eq!(back.steps.len(), 1);
+            assert!((back.cost - 2.0 * side).abs() < 0.01, "{:?}", back);
+        }
+
+        // from a quarter of the way along 1-2, back through 1 and 4 to half
+        // way along 3-4
+        let from = edge_list.snap(0.00025, -0.0001).unwrap();
+        assert!((from.fraction - 0.125).abs() < 0.01 && (from.distance_m - 11.1).abs() < 0.1, "{:?}", from);
+        let to = edge_list.snap(0.0005, 0.0011).unwrap();
+        let route = graph.route(&from, &to, Algorithm::AStar).unwrap();
+        assert!((route.cost - 1.75 * side).abs() < 0.1, "{:?}", route);
+        assert!((route.length_m(&edge_list) - route.cost).abs() < 1e-6);
+        let geometry = route.geometry(&edge_list);
+        assert_eq!(geometry.0.len(), 4);
+        let close = |a: Coordinate<f64>, b: Coordinate<f64>| (a.x - b.x).abs() < 1e-9 && (a.y - b.y).abs() < 1e-9;
+        assert!(close(geometry.0[0], from.point) && close(geometry.0[3], to.point), "{:?}", geometry);
+
+        // along the one edge, 0.00055° of longitude on the equator
+        let near = edge_list.snap(0.0008, 0.0).unwrap();
+        let route = graph.route(&from, &near, Algorithm::Dijkstra).unwrap();
+        assert_eq!(route.steps.len(), 1);
+        assert!((route.cost - 61.2).abs() < 0.1, "{:?}", route);
+    }
+}
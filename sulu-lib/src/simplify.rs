@@ -0,0 +1,205 @@
+//! Simplifying edge geometries with a tolerance in metres
+//!
+//! Edge geometries keep every OSM node, which makes large outputs slow to
+//! render. Simplification drops points that are within the tolerance of
+//! the line without them. Each geometry is simplified in a local
+//! equirectangular projection around its first point, so the tolerance is
+//! in metres wherever the edge is.
+//!
+//! Dropping points can make a winding edge cross itself, so both methods
+//! check for that, see `SimplifyMethod`. Edges are simplified one at a
+//! time, so a simplified edge can still cross a neighbouring edge.
+
+use geo::{
+    Coordinate,
+    Line,
+    LineString,
+    algorithm::{
+        intersects::Intersects,
+        simplify::SimplifyIdx,
+        simplifyvw::SimplifyVWPreserve,
+    },
+};
+use crate::{
+    edge_list::EdgeList,
+    error::Error,
+};
+
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SimplifyMethod {
+    /// Drop points closer than the tolerance to the simplified line. If
+    /// that makes the line cross itself the tolerance is halved until it
+    /// doesn't, see `MAX_HALVINGS`.
+    #[default]
+    DouglasPeucker,
+    /// Drop points whose triangle with their neighbours has an area under
+    /// the tolerance squared, unless dropping them makes the line cross
+    /// itself
+    Visvalingam
+}
+
+/// How many times Douglas-Peucker simplification halves the tolerance to
+/// stop a line crossing itself, before the line is left as it is
+const MAX_HALVINGS: usize = 8;
+
+impl std::str::FromStr for SimplifyMethod {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "douglas-peucker" => Ok(SimplifyMethod::DouglasPeucker),
+            "visvalingam" => Ok(SimplifyMethod::Visvalingam),
+            _ => Err(Error::ConversionError(format!("Unknown simplification method: {}", s)))
+        }
+    }
+}
+
+/// The points of a geometry in metres from its first point
+fn local_metres(geometry: &LineString<f64>) -> LineString<f64> {
+    let origin = match geometry.0.first() {
+        Some(origin) => *origin,
+        None => return LineString(vec![])
+    };
+    let scale = EARTH_RADIUS_M * std::f64::consts::PI / 180.0;
+    let x_scale = scale * origin.y.to_radians().cos();
+    geometry.0.iter()
+        .map(|c| Coordinate { x: (c.x - origin.x) * x_scale, y: (c.y - origin.y) * scale })
+        .collect()
+}
+
+/// Whether any two segments of a line that don't follow one another
+/// cross or touch. The first and last segments of a closed line follow one
+/// another.
+fn self_intersects(line: &LineString<f64>) -> bool {
+    let segments: Vec<Line<f64>> = line.lines().collect();
+    let closed = line.0.len() > 3 && line.0.first() == line.0.last();
+    segments.iter().enumerate().any(|(i, a)| {
+        segments.iter().enumerate().skip(i + 2).any(|(j, b)| {
+            !(closed && i == 0 && j == segments.len() - 1) && a.intersects(b)
+        })
+    })
+}
+
+/// The points of a line at `indices`
+fn subset(line: &LineString<f64>, indices: &[usize]) -> LineString<f64> {
+    indices.iter().map(|i| line.0[*i]).collect()
+}
+
+/// Douglas-Peucker simplification that doesn't make a line cross itself.
+/// A line that already crosses itself is simplified as usual.
+fn douglas_peucker(line: &LineString<f64>, tolerance: f64) -> Vec<usize> {
+    let crossed = self_intersects(line);
+    let mut tolerance = tolerance;
+    for _ in 0..=MAX_HALVINGS {
+        let keep = line.simplify_idx(&tolerance);
+        if crossed || !self_intersects(&subset(line, &keep)) {
+            return keep
+        }
+        tolerance /= 2.0;
+    }
+    (0..line.0.len()).collect()
+}
+
+/// Visvalingam-Whyatt simplification that doesn't make a line cross itself
+fn visvalingam(line: &LineString<f64>, tolerance: f64) -> Vec<usize> {
+    // the simplified line's points are copied from the line, in order
+    let simplified = line.simplifyvw_preserve(&(tolerance * tolerance));
+    let mut keep = Vec::with_capacity(simplified.0.len());
+    let mut next = 0;
+    for c in simplified.0.iter() {
+        match line.0[next..].iter().position(|p| p == c) {
+            Some(i) => {
+                keep.push(next + i);
+                next += i + 1;
+            },
+            None => return (0..line.0.len()).collect()
+        }
+    }
+    keep
+}
+
+impl EdgeList<f64> {
+    /// Simplify the geometry of every edge, keeping its end points.
+    /// `length_m` is left as the length of the original geometry, so
+    /// simplify after anything that recomputes lengths from geometry
+    /// (`split_long_edges`, `densify`), and before `sample_elevation`.
+    pub fn simplify(&mut self, method: SimplifyMethod, tolerance_m: f64) {
+        if tolerance_m <= 0.0 {
+            return
+        }
+        for edge in self.edges.iter_mut() {
+            if edge.geometry.0.len() < 3 {
+                continue
+            }
+            let local = local_metres(&edge.geometry);
+            let keep = match method {
+                SimplifyMethod::DouglasPeucker => douglas_peucker(&local, tolerance_m),
+                SimplifyMethod::Visvalingam => visvalingam(&local, tolerance_m)
+            };
+            if keep.len() < edge.geometry.0.len() {
+                edge.geometry = keep.into_iter().map(|i| edge.geometry.0[i]).collect();
+                edge.elevation = None;
+                edge.measures = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use crate::edge_list::edge_list_from_xml;
+
+    #[test]
+    fn test_simplify() {
+        // a road along the equator with a 2 m kink in the middle, and a
+        // bend of about 55 m at the end
+        let xml = r#"<osm>
+  <node id="1" lat="0.0" lon="0.000"/>
+  <node id="2" lat="0.000018" lon="0.001"/>
+  <node id="3" lat="0.0" lon="0.002"/>
+  <node id="4" lat="0.0005" lon="0.0025"/>
+  <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><tag k="highway" v="primary"/></way>
+</osm>"#;
+        let (cache, original) = edge_list_from_xml(xml);
+        let length = original.edges[0].length_m;
+
+        // the kink's triangle is about 220 m², over 5 m squared
+        for (method, tolerance) in [(SimplifyMethod::DouglasPeucker, 5.0), (SimplifyMethod::Visvalingam, 20.0)] {
+            let mut edge_list = EdgeList::try_from(&cache).unwrap();
+            edge_list.simplify(method, tolerance);
+            let edge = &edge_list.edges[0];
+            let expected: Vec<Coordinate<f64>> = [0, 2, 3].iter().map(|i| original.edges[0].geometry.0[*i]).collect();
+            assert_eq!(edge.geometry.0, expected, "{:?}", method);
+            assert_eq!(edge.length_m, length);
+        }
+    }
+
+    #[test]
+    fn test_simplify_without_crossing() {
+        // a road that doubles back on itself, where dropping node 4 would
+        // make the line from node 3 to node 5 cross the first segment
+        let xml = r#"<osm>
+  <node id="1" lat="0.0" lon="0.0"/>
+  <node id="2" lat="-0.00015" lon="0.00065"/>
+  <node id="3" lat="-0.0002" lon="0.00035"/>
+  <node id="4" lat="-0.00015" lon="0.0007"/>
+  <node id="5" lat="0.0" lon="0.001"/>
+  <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><nd ref="4"/><nd ref="5"/><tag k="highway" v="primary"/></way>
+</osm>"#;
+        let (cache, original) = edge_list_from_xml(xml);
+        let local = local_metres(&original.edges[0].geometry);
+        assert!(!self_intersects(&local));
+        assert!(self_intersects(&subset(&local, &local.simplify_idx(&13.0))));
+
+        for method in vec![SimplifyMethod::DouglasPeucker, SimplifyMethod::Visvalingam].into_iter() {
+            let mut edge_list = EdgeList::try_from(&cache).unwrap();
+            edge_list.simplify(method, 13.0);
+            let simplified = local_metres(&edge_list.edges[0].geometry);
+            assert!(!self_intersects(&simplified), "{:?}", method);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Saving and reloading the filtered contents of an `OSMCache`
+//!
+//! A snapshot is the bytes `SULU`, a bincode encoded `SnapshotHeader`, the
+//! bincode encoded `OSMCache` and then its node locations, as a count
+//! followed by `(id, lat, lon, version)` tuples. The header records the snapshot
+//! format version, a hash of the parts of the graph config that decide what
+//! is cached and a hash of the inputs, so a stale snapshot is never mistaken
+//! for a fresh one.
+
+use std::{
+    io::{
+        Read,
+        Write,
+        BufReader,
+        BufWriter,
+    },
+    path::Path,
+    time::UNIX_EPOCH,
+};
+use bincode::Options;
+use serde::{Serialize, Deserialize};
+use osmpbfreader::NodeId;
+use crate::{
+    graph_config::{
+        GraphConfig,
+        GraphConfigOption,
+        SplitAt,
+    },
+    osm_cache::OSMCache,
+    node_store::{
+        NodeStore,
+        NodeEntry,
+        MemoryNodeStore,
+    },
+    error::Error,
+};
+
+/// Incremented whenever the layout of `OSMCache`, or what it caches, changes
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 8;
+const SNAPSHOT_MAGIC: &[u8; 4] = b"SULU";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotHeader {
+    pub format_version: u32,
+    pub graph_config_hash: u64,
+    pub source_hash: u64
+}
+
+/// 64 bit FNV-1a, used because the std hashers are not stable between releases
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |h, b| (h ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3))
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// The parts of a graph config that decide what is cached. The rest only
+/// changes how the cache is turned into edges.
+#[derive(Serialize)]
+struct CacheFilter<'a> {
+    options: &'a [GraphConfigOption],
+    routes: &'a [GraphConfigOption],
+    areas: &'a [GraphConfigOption],
+    split_at: &'a SplitAt,
+    turn_restrictions: bool
+}
+
+/// A hash of the filtering parts of a graph config that is stable between runs
+pub fn graph_config_hash(gc: &GraphConfig) -> Result<u64, Error> {
+    let filter = CacheFilter {
+        options: &gc.options,
+        routes: &gc.routes,
+        areas: &gc.areas,
+        split_at: &gc.split_at,
+        turn_restrictions: gc.turn_restrictions
+    };
+    let bytes = serde_json::to_vec(&filter)
+        .map_err(|e| Error::ConversionError(e.to_string()))?;
+    Ok(fnv1a(FNV_OFFSET, &bytes))
+}
+
+/// A hash of the path, size and modification time of each input file
+pub fn source_hash<I, P>(paths: I) -> Result<u64, Error>
+where
+    I: IntoIterator<Item=P>,
+    P: AsRef<Path>
+{
+    let mut hash = FNV_OFFSET;
+    for path in paths {
+        let path = path.as_ref();
+        let metadata = std::fs::metadata(path)
+            .map_err(Error::IoError)?;
+        let modified = metadata.modified()
+            .map_err(Error::IoError)?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        hash = fnv1a(hash, path.to_string_lossy().as_bytes());
+        hash = fnv1a(hash, &metadata.len().to_le_bytes());
+        hash = fnv1a(hash, &modified.to_le_bytes());
+    }
+    Ok(hash)
+}
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+impl OSMCache {
+    /// Write a snapshot of the cache, recording the hash of its inputs
+    pub fn save_snapshot<W: Write>(&self, mut writer: W, source_hash: u64) -> Result<(), Error> {
+        let header = SnapshotHeader {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            graph_config_hash: graph_config_hash(&self.graph_config)?,
+            source_hash
+        };
+        writer.write_all(SNAPSHOT_MAGIC)
+            .map_err(Error::IoError)?;
+        bincode_options().serialize_into(&mut writer, &header)
+            .map_err(|e| Error::SnapshotError(e.to_string()))?;
+        bincode_options().serialize_into(&mut writer, self)
+            .map_err(|e| Error::SnapshotError(e.to_string()))?;
+        // the nodes are streamed from the store, ending with `None`
+        self.nodes.for_each_entry(&mut |nid, entry| {
+            bincode_options().serialize_into(&mut writer, &Some((nid.0, entry)))
+                .map_err(|e| Error::SnapshotError(e.to_string()))
+        })?;
+        bincode_options().serialize_into(&mut writer, &None::<(i64, NodeEntry)>)
+            .map_err(|e| Error::SnapshotError(e.to_string()))?;
+        writer.flush().map_err(Error::IoError)
+    }
+
+    /// Read a snapshot, failing with `Error::StaleSnapshot` if it was written
+    /// by another format version, for a graph config that filters differently
+    /// or from other inputs. The loaded cache takes `gc` for its graph config.
+    pub fn load_snapshot<R: Read>(
+        reader: R,
+        gc: &GraphConfig,
+        source_hash: u64
+    ) -> Result<Self, Error> {
+        Self::load_snapshot_with_node_store(reader, gc, source_hash, Box::new(MemoryNodeStore::default()))
+    }
+
+    /// Read a snapshot as with `load_snapshot`, putting the node locations in `nodes`
+    pub fn load_snapshot_with_node_store<R: Read>(
+        mut reader: R,
+        gc: &GraphConfig,
+        source_hash: u64,
+        nodes: Box<dyn NodeStore>
+    ) -> Result<Self, Error> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)
+            .map_err(Error::IoError)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(Error::SnapshotError("Not a sulu snapshot".to_string()))
+        }
+        let header: SnapshotHeader = bincode_options().deserialize_from(&mut reader)
+            .map_err(|e| Error::SnapshotError(e.to_string()))?;
+        if header.format_version != SNAPSHOT_FORMAT_VERSION {
+            return Err(Error::StaleSnapshot(format!(
+                "format version {} is not {}", header.format_version, SNAPSHOT_FORMAT_VERSION)))
+        }
+        if header.graph_config_hash != graph_config_hash(gc)? {
+            return Err(Error::StaleSnapshot("the graph config has changed".to_string()))
+        }
+        if header.source_hash != source_hash {
+            return Err(Error::StaleSnapshot("the input files have changed".to_string()))
+        }
+        let mut cache: OSMCache = bincode_options().deserialize_from(&mut reader)
+            .map_err(|e| Error::SnapshotError(e.to_string()))?;
+        cache.graph_config = gc.clone();
+        cache.nodes = nodes;
+        loop {
+            let node: Option<(i64, NodeEntry)> = bincode_options().deserialize_from(&mut reader)
+                .map_err(|e| Error::SnapshotError(e.to_string()))?;
+            match node {
+                Some((nid, entry)) => cache.nodes.set_entry(NodeId(nid), entry)?,
+                None => break
+            }
+        }
+        Ok(cache)
+    }
+
+    pub fn save_snapshot_to_path<P: AsRef<Path>>(&self, path: P, source_hash: u64) -> Result<(), Error> {
+        let f = std::fs::File::create(path)
+            .map_err(Error::IoError)?;
+        self.save_snapshot(BufWriter::new(f), source_hash)
+    }
+
+    pub fn load_snapshot_from_path<P: AsRef<Path>>(
+        path: P,
+        gc: &GraphConfig,
+        source_hash: u64
+    ) -> Result<Self, Error> {
+        let f = std::fs::File::open(path)
+            .map_err(Error::IoError)?;
+        Self::load_snapshot(BufReader::new(f), gc, source_hash)
+    }
+
+    pub fn load_snapshot_from_path_with_node_store<P: AsRef<Path>>(
+        path: P,
+        gc: &GraphConfig,
+        source_hash: u64,
+        nodes: Box<dyn NodeStore>
+    ) -> Result<Self, Error> {
+        let f = std::fs::File::open(path)
+            .map_err(Error::IoError)?;
+        Self::load_snapshot_with_node_store(BufReader::new(f), gc, source_hash, nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use osmpbfreader::{OsmObj, Node, Way, WayId, Tags};
+    use crate::graph_config::{GeometryMeasure, MergeChains, TravelTime};
+
+    fn graph_config(name: &str) -> GraphConfig {
+        GraphConfig {
+            name: name.to_string(),
+            options: vec![GraphConfigOption {
+                name: "any".to_string(),
+                requires: vec![],
+                excludes: vec![]
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let gc = graph_config("roads");
+        let mut cache = OSMCache::new(gc.clone());
+        cache.insert(OsmObj::Way(Way {
+            id: WayId(1),
+            tags: vec![("highway".into(), "primary".into())].into_iter().collect(),
+            nodes: vec![NodeId(1), NodeId(2)]
+        }), 3).unwrap();
+        cache.insert(OsmObj::Node(Node {
+            id: NodeId(1),
+            tags: Tags::new(),
+            decimicro_lat: -338_600_000,
+            decimicro_lon: 1_512_100_000
+        }), 1).unwrap();
+
+        let mut buf = Vec::new();
+        cache.save_snapshot(&mut buf, 42).unwrap();
+
+        let loaded = OSMCache::load_snapshot(&buf[..], &gc, 42).unwrap();
+        assert_eq!(loaded.osm_cache, cache.osm_cache);
+        assert_eq!(loaded.node_count(NodeId(1)), 1);
+        // node 2 is counted though it wasn't loaded
+        assert_eq!(loaded.node_count(NodeId(2)), 1);
+        assert_eq!(loaded.versions, cache.versions);
+        assert_eq!(loaded.nodes.get(NodeId(1)), Some((-338_600_000, 1_512_100_000)));
+        assert_eq!(loaded.version(&NodeId(1).into()), Some(1));
+        assert!(!loaded.contains_node(NodeId(2)));
+
+        assert!(matches!(
+            OSMCache::load_snapshot(&buf[..], &gc, 43),
+            Err(Error::StaleSnapshot(_))));
+        let mut paths_gc = graph_config("paths");
+        paths_gc.options[0].requires = vec![serde_json::from_str(r#"{"key": "highway", "kind": {"exact": "path"}}"#).unwrap()];
+        assert!(matches!(
+            OSMCache::load_snapshot(&buf[..], &paths_gc, 42),
+            Err(Error::StaleSnapshot(_))));
+        let mut restrictions_gc = gc.clone();
+        restrictions_gc.turn_restrictions = true;
+        assert!(matches!(
+            OSMCache::load_snapshot(&buf[..], &restrictions_gc, 42),
+            Err(Error::StaleSnapshot(_))));
+        assert!(matches!(
+            OSMCache::load_snapshot(&b"nope"[..], &gc, 42),
+            Err(Error::SnapshotError(_))));
+
+        // output settings don't change what is cached
+        let mut output_gc = gc.clone();
+        output_gc.merge_chains = Some(MergeChains::default());
+        output_gc.travel_time = Some(TravelTime::default());
+        output_gc.geometry_measures = vec![GeometryMeasure::Sinuosity];
+        let loaded = OSMCache::load_snapshot(&buf[..], &output_gc, 42).unwrap();
+        assert_eq!(loaded.graph_config.geometry_measures, output_gc.geometry_measures);
+    }
+}
@@ -0,0 +1,256 @@
+//! Travel times of edges, from the `travel-time` section of the graph config
+//!
+//! The speed of an edge is its way's `maxspeed` (for cars), the speed for
+//! its graph config option, or the default speed, in that order. It is then
+//! multiplied by the factors for the way's tags and for going up or down
+//! an `incline`. Penalties are added for the traffic signals and crossings
+//! along the edge, counting every node but the one it is entered from, so
+//! that a node between two edges is only passed once. Cars follow `oneway`,
+//! bikes follow `oneway:bicycle` where it's given, and walking ignores both.
+
+use osmpbfreader::{
+    NodeId,
+    OsmId,
+    Tags,
+};
+use crate::{
+    edge_list::Direction,
+    graph_config::{
+        TravelMode,
+        TravelTime,
+    },
+    osm_cache::OSMCache,
+};
+
+const KMH_PER_MPH: f64 = 1.609_344;
+const KMH_PER_KNOT: f64 = 1.852;
+
+/// The time to travel an edge in each direction, `None` where it can't be
+/// travelled that way
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TravelTimes {
+    /// From the start node to the end node
+    pub forward_s: Option<f64>,
+    /// From the end node to the start node
+    pub backward_s: Option<f64>
+}
+
+impl TravelTimes {
+    /// The travel times of the edge travelled the other way
+    pub fn reversed(self) -> Self {
+        TravelTimes { forward_s: self.backward_s, backward_s: self.forward_s }
+    }
+
+    /// The travel times of a fraction of the edge, for when it is split
+    pub fn scaled(self, fraction: f64) -> Self {
+        TravelTimes {
+            forward_s: self.forward_s.map(|t| t * fraction),
+            backward_s: self.backward_s.map(|t| t * fraction)
+        }
+    }
+
+    /// The travel times of this edge followed by `next`
+    pub fn then(self, next: TravelTimes) -> Self {
+        let add = |a: Option<f64>, b: Option<f64>| Some(a? + b?);
+        TravelTimes {
+            forward_s: add(self.forward_s, next.forward_s),
+            backward_s: add(self.backward_s, next.backward_s)
+        }
+    }
+}
+
+/// A `maxspeed` value in km/h, e.g. `50`, `30 mph` or `10 knots`. Implicit
+/// limits such as `AU:urban`, and `none` or `walk`, give `None`.
+pub fn parse_maxspeed(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let (number, scale) = if let Some(number) = value.strip_suffix("mph") {
+        (number, KMH_PER_MPH)
+    } else if let Some(number) = value.strip_suffix("knots") {
+        (number, KMH_PER_KNOT)
+    } else if let Some(number) = value.strip_suffix("km/h") {
+        (number, 1.0)
+    } else {
+        (value, 1.0)
+    };
+    number.trim().parse::<f64>().ok()
+        .filter(|speed| *speed > 0.0)
+        .map(|speed| speed * scale)
+}
+
+/// The sign of an `incline` tag: positive if the way goes up, negative if
+/// it goes down, zero if flat or unknown
+fn incline_sign(value: &str) -> f64 {
+    match value.trim() {
+        "up" => 1.0,
+        "down" => -1.0,
+        v => v.trim_end_matches(['%', '°'])
+            .trim()
+            .parse::<f64>()
+            .map(|n| if n == 0.0 { 0.0 } else { n.signum() })
+            .unwrap_or(0.0)
+    }
+}
+
+impl TravelMode {
+    /// The directions a way with `tags` can be travelled in this mode
+    pub fn direction(&self, tags: &Tags) -> Direction {
+        match self {
+            TravelMode::Car => Direction::from_tags(tags),
+            TravelMode::Bike => Direction::from_oneway_tag(tags, "oneway:bicycle")
+                .unwrap_or_else(|| Direction::from_tags(tags)),
+            TravelMode::Walk => Direction::Both
+        }
+    }
+}
+
+impl TravelTime {
+    /// The speed in km/h along and against the way
+    fn speeds_kmh(&self, option_name: &str, tags: &Tags) -> (f64, f64) {
+        let base = self.option_speeds_kmh.get(option_name).copied()
+            .or(self.default_speed_kmh)
+            .unwrap_or_else(|| self.mode.default_speed_kmh());
+        let maxspeed = |key: &str| match self.mode {
+            TravelMode::Car => tags.get(key).and_then(|v| parse_maxspeed(v)),
+            _ => None
+        };
+        let limit = maxspeed("maxspeed");
+        let mut forward = maxspeed("maxspeed:forward").or(limit).unwrap_or(base);
+        let mut backward = maxspeed("maxspeed:backward").or(limit).unwrap_or(base);
+
+        let factor: f64 = self.factors.iter()
+            .filter(|f| tags.contains(f.key.as_str(), f.value.as_str()))
+            .map(|f| f.factor)
+            .product();
+        forward *= factor;
+        backward *= factor;
+        if let Some(incline) = &self.incline {
+            let sign = tags.get("incline").map(|v| incline_sign(v)).unwrap_or(0.0);
+            if sign > 0.0 {
+                forward *= incline.uphill;
+                backward *= incline.downhill;
+            } else if sign < 0.0 {
+                forward *= incline.downhill;
+                backward *= incline.uphill;
+            }
+        }
+        (forward, backward)
+    }
+
+    /// The penalty for the signals and crossings at `nodes`
+    fn penalty_s(&self, nodes: &[NodeId], osm_cache: &OSMCache) -> f64 {
+        nodes.iter()
+            .filter_map(|nid| osm_cache.osm_cache.get(&OsmId::Node(*nid)))
+            .map(|obj| {
+                let tags = obj.tags();
+                if tags.contains("highway", "traffic_signals") {
+                    self.signal_penalty_s
+                } else if tags.contains("highway", "crossing") {
+                    self.crossing_penalty_s
+                } else {
+                    0.0
+                }
+            })
+            .sum()
+    }
+
+    /// The travel times of an edge through `nodes`, on a way with `tags`
+    pub(crate) fn travel_times(
+        &self,
+        option_name: &str,
+        tags: &Tags,
+        length_m: f64,
+        nodes: &[NodeId],
+        osm_cache: &OSMCache
+    ) -> TravelTimes {
+        let (forward_kmh, backward_kmh) = self.speeds_kmh(option_name, tags);
+        let direction = self.mode.direction(tags);
+        let time = |allowed: bool, speed_kmh: f64, passed: &[NodeId]| {
+            if allowed && speed_kmh > 0.0 {
+                Some(length_m / (speed_kmh / 3.6) + self.penalty_s(passed, osm_cache))
+            } else {
+                None
+            }
+        };
+        let last = nodes.len().saturating_sub(1);
+        TravelTimes {
+            forward_s: time(direction.forward(), forward_kmh, nodes.get(1..).unwrap_or_default()),
+            backward_s: time(direction.backward(), backward_kmh, &nodes[..last])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edge_list::{
+        basic_roads_config,
+        edge_list_from_xml_with_config,
+    };
+
+    #[test]
+    fn test_parse_maxspeed() {
+        assert_eq!(parse_maxspeed("60"), Some(60.0));
+        assert_eq!(parse_maxspeed("50 km/h"), Some(50.0));
+        assert!((parse_maxspeed("30 mph").unwrap() - 48.28).abs() < 0.01);
+        assert_eq!(parse_maxspeed("AU:urban"), None);
+        assert_eq!(parse_maxspeed("none"), None);
+    }
+
+    #[test]
+    fn test_travel_times() {
+        let mut gc = basic_roads_config();
+        gc.travel_time = serde_json::from_str(r#"{
+            "option-speeds-kmh": {"main-road": 40},
+            "factors": [{"key": "surface", "value": "gravel", "factor": 0.5}],
+            "signal-penalty-s": 20
+        }"#).unwrap();
+        let xml = include_str!("../../examples/basic_roads.osm")
+            .replace(r#"<tag k="name" v="George Street"/>"#,
+                     r#"<tag k="name" v="George Street"/><tag k="maxspeed" v="60"/><tag k="oneway" v="yes"/>"#)
+            .replace(r#"<tag k="name" v="King Street"/>"#,
+                     r#"<tag k="name" v="King Street"/><tag k="surface" v="gravel"/>"#);
+        let (_, edge_list) = edge_list_from_xml_with_config(gc, &xml);
+
+        // George Street from node 1 to the signals at node 3, and on from them
+        let george: Vec<&TravelTimes> = edge_list.edges.iter()
+            .filter(|e| e.way_osmid.0 == 100)
+            .map(|e| e.travel_time.as_ref().unwrap())
+            .collect();
+        let expected = edge_list.edges[0].length_m / (60.0 / 3.6) + 20.0;
+        assert!((george[0].forward_s.unwrap() - expected).abs() < 1e-9);
+        assert_eq!(george[0].backward_s, None);
+        let expected = edge_list.edges[1].length_m / (60.0 / 3.6);
+        assert!((george[1].forward_s.unwrap() - expected).abs() < 1e-9);
+
+        // King Street is gravel, at half the option's speed
+        let king = edge_list.edges.iter().find(|e| e.way_osmid.0 == 101).unwrap();
+        let times = king.travel_time.unwrap();
+        assert!((times.backward_s.unwrap() - king.length_m / (20.0 / 3.6)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_mode_direction() {
+        let tags = |pairs: &[(&str, &str)]| -> Tags {
+            pairs.iter().map(|(k, v)| (k.to_string().into(), v.to_string().into())).collect()
+        };
+        let oneway = tags(&[("oneway", "yes")]);
+        let contraflow = tags(&[("oneway", "yes"), ("oneway:bicycle", "no")]);
+        assert_eq!(TravelMode::Car.direction(&oneway), Direction::Forward);
+        assert_eq!(TravelMode::Car.direction(&contraflow), Direction::Forward);
+        assert_eq!(TravelMode::Bike.direction(&oneway), Direction::Forward);
+        assert_eq!(TravelMode::Bike.direction(&contraflow), Direction::Both);
+        assert_eq!(TravelMode::Bike.direction(&tags(&[("oneway:bicycle", "-1")])), Direction::Backward);
+        assert_eq!(TravelMode::Walk.direction(&oneway), Direction::Both);
+
+        // walking both ways along a one way street
+        let mut gc = basic_roads_config();
+        gc.travel_time = serde_json::from_str(r#"{"mode": "walk"}"#).unwrap();
+        let xml = include_str!("../../examples/basic_roads.osm")
+            .replace(r#"<tag k="name" v="George Street"/>"#,
+                     r#"<tag k="name" v="George Street"/><tag k="oneway" v="yes"/>"#);
+        let (_, edge_list) = edge_list_from_xml_with_config(gc, &xml);
+        let george = edge_list.edges.iter().find(|e| e.way_osmid.0 == 100).unwrap();
+        assert_eq!(george.direction, Direction::Both);
+        assert!(george.travel_time.unwrap().backward_s.is_some());
+    }
+}
@@ -0,0 +1,357 @@
+//! The line graph of movements between edges, for turn costs
+//!
+//! A movement is travelling along one edge into a node and out along
+//! another (or back along the same one). There is a movement for every pair
+//! of edges that can be travelled that way given their `direction`, with the
+//! angle turned, whether that is left, right, straight on or a U-turn, and
+//! the `type=restriction` relation that applies to it, if any. Only
+//! restrictions with a via node and a plain `restriction` tag are used.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+};
+use geo::Coordinate;
+use geographiclib_rs::{
+    Geodesic,
+    InverseGeodesic,
+};
+use osmpbfreader::{
+    NodeId,
+    RelationId,
+    WayId,
+};
+use crate::{
+    edge_list::{
+        Edge,
+        EdgeList,
+    },
+    error::Error,
+    osm_cache::OSMCache,
+};
+
+/// Turns of up to this many degrees either way are straight on
+const STRAIGHT_DEG: f64 = 30.0;
+/// Turns of more than this many degrees either way are U-turns
+const U_TURN_DEG: f64 = 160.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Turn {
+    Straight,
+    Left,
+    Right,
+    UTurn
+}
+
+impl Turn {
+    /// Classify a turn angle, positive to the right
+    pub fn from_angle(angle_deg: f64) -> Self {
+        if angle_deg.abs() <= STRAIGHT_DEG {
+            Turn::Straight
+        } else if angle_deg.abs() > U_TURN_DEG {
+            Turn::UTurn
+        } else if angle_deg > 0.0 {
+            Turn::Right
+        } else {
+            Turn::Left
+        }
+    }
+}
+
+impl std::fmt::Display for Turn {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Turn::Straight => "straight",
+            Turn::Left => "left",
+            Turn::Right => "right",
+            Turn::UTurn => "u-turn"
+        })
+    }
+}
+
+/// A turn restriction relation, e.g. `no_left_turn` or `only_straight_on`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TurnRestriction {
+    pub relation_osmid: RelationId,
+    pub restriction: String
+}
+
+/// Travelling along one edge into a node and out along another. Edges are
+/// indices into `EdgeList::edges`, each with whether it is travelled from
+/// its start node to its end node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movement {
+    pub from_edge: usize,
+    pub from_forward: bool,
+    pub to_edge: usize,
+    pub to_forward: bool,
+    pub via_node_id: NodeId,
+    /// From the bearing the first edge arrives on to the bearing the second
+    /// leaves on, -180 to 180 with right turns positive
+    pub angle_deg: f64,
+    pub turn: Turn,
+    pub restriction: Option<TurnRestriction>,
+    /// Whether the restriction forbids the movement
+    pub prohibited: bool
+}
+
+/// The bearings of the geodesic from one point to another as it leaves
+/// `from` and as it arrives at `to`, 0 to 360 clockwise from north
+pub(crate) fn azimuths(from: Coordinate<f64>, to: Coordinate<f64>) -> (f64, f64) {
+    let (leaving, arriving, _): (f64, f64, f64) = Geodesic::wgs84().inverse(from.y, from.x, to.y, to.x);
+    (leaving.rem_euclid(360.0), arriving.rem_euclid(360.0))
+}
+
+/// The first point, and the next point that isn't the same
+fn first_segment<'a, I: Iterator<Item=&'a Coordinate<f64>>>(mut coords: I) -> Option<(Coordinate<f64>, Coordinate<f64>)> {
+    let first = *coords.next()?;
+    coords.find(|c| **c != first).map(|c| (first, *c))
+}
+
+impl Edge<f64> {
+    /// The bearing the edge leaves its start node on, from longitude and
+    /// latitude geometry
+    pub fn start_bearing(&self) -> Option<f64> {
+        first_segment(self.geometry.0.iter()).map(|(a, b)| azimuths(a, b).0)
+    }
+
+    /// The bearing the edge arrives at its end node on
+    pub fn end_bearing(&self) -> Option<f64> {
+        first_segment(self.geometry.0.iter().rev()).map(|(a, b)| azimuths(b, a).1)
+    }
+
+    /// The start and end bearings travelling the edge forward or backward
+    fn bearings(&self, forward: bool) -> Option<(f64, f64)> {
+        if forward {
+            Some((self.start_bearing()?, self.end_bearing()?))
+        } else {
+            let start = first_segment(self.geometry.0.iter().rev()).map(|(a, b)| azimuths(a, b).0)?;
+            let end = first_segment(self.geometry.0.iter()).map(|(a, b)| azimuths(b, a).1)?;
+            Some((start, end))
+        }
+    }
+
+    /// The way at the node the edge is entered from, travelling forward or
+    /// backward, which differs for merged edges
+    fn first_way(&self, forward: bool) -> WayId {
+        if forward {
+            self.way_osmid
+        } else {
+            *self.merged_way_osmids.last().unwrap_or(&self.way_osmid)
+        }
+    }
+}
+
+struct Restriction {
+    relation_osmid: RelationId,
+    restriction: String,
+    to: WayId
+}
+
+/// The restrictions by their from way and via node
+fn restrictions(osm_cache: &OSMCache) -> HashMap<(WayId, NodeId), Vec<Restriction>> {
+    let mut restrictions: HashMap<(WayId, NodeId), Vec<Restriction>> = HashMap::new();
+    for rel in osm_cache.osm_cache.values().filter_map(|o| o.relation()) {
+        if !rel.tags.contains("type", "restriction") {
+            continue
+        }
+        let restriction = match rel.tags.get("restriction") {
+            Some(restriction) => restriction.to_string(),
+            None => continue
+        };
+        let member = |role: &str| rel.refs.iter().find(|m| m.role == role).map(|m| m.member);
+        let (from, via, to) = match (
+            member("from").and_then(|m| m.way()),
+            member("via").and_then(|m| m.node()),
+            member("to").and_then(|m| m.way())
+        ) {
+            (Some(from), Some(via), Some(to)) => (from, via, to),
+            _ => continue
+        };
+        restrictions.entry((from, via)).or_default().push(Restriction { relation_osmid: rel.id, restriction, to });
+    }
+    restrictions
+}
+
+impl EdgeList<f64> {
+    /// Every movement between edges allowed by their directions, in order
+    /// of the edge arrived on. Bearings are measured from longitude and
+    /// latitude, so build this before `reproject`. Restrictions are only
+    /// applied if the cache was built with `GraphConfig::turn_restrictions`.
+    pub fn movements(&self, osm_cache: &OSMCache) -> Vec<Movement> {
+        let restrictions = restrictions(osm_cache);
+        let mut leaving: HashMap<NodeId, Vec<(usize, bool)>> = HashMap::new();
+        for (i, edge) in self.edges.iter().enumerate() {
+            if edge.direction.forward() {
+                leaving.entry(edge.start_node_id).or_default().push((i, true));
+            }
+            if edge.direction.backward() {
+                leaving.entry(edge.end_node_id).or_default().push((i, false));
+            }
+        }
+
+        let mut movements = vec![];
+        for (i, from) in self.edges.iter().enumerate() {
+            for &from_forward in [true, false].iter() {
+                let allowed = if from_forward { from.direction.forward() } else { from.direction.backward() };
+                let arrival = match from.bearings(from_forward) {
+                    Some((_, end)) if allowed => end,
+                    _ => continue
+                };
+                let via_node_id = if from_forward { from.end_node_id } else { from.start_node_id };
+                let from_way = from.first_way(!from_forward);
+                let applicable = restrictions.get(&(from_way, via_node_id));
+                for &(j, to_forward) in leaving.get(&via_node_id).into_iter().flatten() {
+                    let to = &self.edges[j];
+                    let departure = match to.bearings(to_forward) {
+                        Some((start, _)) => start,
+                        None => continue
+                    };
+                    let angle_deg = (departure - arrival + 180.0).rem_euclid(360.0) - 180.0;
+                    let turn = if i == j && from_forward != to_forward {
+                        Turn::UTurn
+                    } else {
+                        Turn::from_angle(angle_deg)
+                    };
+                    // a `no_` restriction forbids its to way, and an `only_`
+                    // restriction every other way
+                    let to_way = to.first_way(to_forward);
+                    let mut restriction = None;
+                    let mut prohibited = false;
+                    for r in applicable.into_iter().flatten() {
+                        let forbids = if r.restriction.starts_with("only_") { r.to != to_way } else { r.to == to_way };
+                        if forbids || r.to == to_way {
+                            restriction = Some(TurnRestriction {
+                                relation_osmid: r.relation_osmid,
+                                restriction: r.restriction.clone()
+                            });
+                            prohibited |= forbids;
+                        }
+                    }
+                    movements.push(Movement {
+                        from_edge: i,
+                        from_forward,
+                        to_edge: j,
+                        to_forward,
+                        via_node_id,
+                        angle_deg,
+                        turn,
+                        restriction,
+                        prohibited
+                    });
+                }
+            }
+        }
+        movements
+    }
+
+    /// Write movements as a CSV table. Edges are identified by their
+    /// `edge_id` if the edge list has been reindexed, otherwise by their
+    /// position in it.
+    pub fn write_movements<W: Write>(&self, movements: &[Movement], mut writer: W) -> Result<(), Error> {
+        let edge_id = |i: usize| self.edges[i].dense_ids.map(|d| d.edge_id).unwrap_or(i);
+        writeln!(writer, "from_edge_id,to_edge_id,from_way_osmid,to_way_osmid,from_node_id,via_node_id,to_node_id,\
+                          angle_deg,turn,restriction,restriction_osmid,prohibited")
+            .map_err(Error::IoError)?;
+        for m in movements {
+            let (from, to) = (&self.edges[m.from_edge], &self.edges[m.to_edge]);
+            let from_node_id = if m.from_forward { from.start_node_id } else { from.end_node_id };
+            let to_node_id = if m.to_forward { to.end_node_id } else { to.start_node_id };
+            writeln!(writer, "{},{},{},{},{},{},{},{:.1},{},{},{},{}",
+                     edge_id(m.from_edge),
+                     edge_id(m.to_edge),
+                     from.first_way(!m.from_forward).0,
+                     to.first_way(m.to_forward).0,
+                     from_node_id.0,
+                     m.via_node_id.0,
+                     to_node_id.0,
+                     m.angle_deg,
+                     m.turn,
+                     m.restriction.as_ref().map(|r| r.restriction.as_str()).unwrap_or(""),
+                     m.restriction.as_ref().map(|r| r.relation_osmid.0.to_string()).unwrap_or_default(),
+                     m.prohibited)
+                .map_err(Error::IoError)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::edge_list::{
+        basic_roads_config,
+        edge_list_from_xml,
+        edge_list_from_xml_with_config,
+    };
+
+    #[test]
+    fn test_movements() {
+        // a crossroads at node 1, with a one way street (12) leaving north
+        let xml = r#"<osm>
+  <node id="1" lat="0.0" lon="0.0"/>
+  <node id="2" lat="0.001" lon="0.0"/>
+  <node id="3" lat="0.0" lon="0.001"/>
+  <node id="4" lat="-0.001" lon="0.0"/>
+  <node id="5" lat="0.0" lon="-0.001"/>
+  <way id="10"><nd ref="4"/><nd ref="1"/><tag k="highway" v="primary"/></way>
+  <way id="11"><nd ref="5"/><nd ref="1"/><tag k="highway" v="primary"/></way>
+  <way id="12"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/><tag k="oneway" v="yes"/></way>
+  <way id="13"><nd ref="1"/><nd ref="3"/><tag k="highway" v="primary"/></way>
+  <relation id="20">
+    <member type="way" ref="10" role="from"/>
+    <member type="node" ref="1" role="via"/>
+    <member type="way" ref="11" role="to"/>
+    <tag k="type" v="restriction"/><tag k="restriction" v="no_left_turn"/>
+  </relation>
+  <relation id="21">
+    <member type="way" ref="13" role="from"/>
+    <member type="node" ref="1" role="via"/>
+    <member type="way" ref="12" role="to"/>
+    <tag k="type" v="restriction"/><tag k="restriction" v="only_right_turn"/>
+  </relation>
+</osm>"#;
+        // restrictions are only cached when asked for
+        let (cache, _) = edge_list_from_xml(xml);
+        assert!(!cache.osm_cache.contains_key(&RelationId(20).into()));
+
+        let mut gc = basic_roads_config();
+        gc.turn_restrictions = true;
+        let (cache, edge_list) = edge_list_from_xml_with_config(gc, xml);
+        let movements = edge_list.movements(&cache);
+        let way = |m: &Movement| (edge_list.edges[m.from_edge].way_osmid.0, edge_list.edges[m.to_edge].way_osmid.0);
+
+        // arriving at the crossroads from the south, west and east, leaving
+        // in every direction, and U-turns at the three dead ends
+        assert_eq!(movements.len(), 15);
+        let from_south: Vec<_> = movements.iter()
+            .filter(|m| m.via_node_id == NodeId(1) && way(m).0 == 10)
+            .map(|m| (way(m).1, m.turn, m.prohibited))
+            .collect();
+        assert_eq!(from_south, vec![
+            (10, Turn::UTurn, false),
+            (11, Turn::Left, true),
+            (12, Turn::Straight, false),
+            (13, Turn::Right, false)
+        ]);
+        let left = movements.iter().find(|m| way(m) == (10, 11)).unwrap();
+        assert!((left.angle_deg + 90.0).abs() < 0.01);
+        assert_eq!(left.restriction.as_ref().unwrap().restriction, "no_left_turn");
+
+        // from the east only the right turn is allowed
+        let from_east: Vec<_> = movements.iter()
+            .filter(|m| way(m).0 == 13 && m.via_node_id == NodeId(1))
+            .map(|m| (way(m).1, m.prohibited))
+            .collect();
+        assert_eq!(from_east, vec![(10, true), (11, true), (12, false), (13, true)]);
+        assert!(movements.iter()
+            .filter(|m| way(m).0 == 13)
+            .all(|m| m.restriction.is_some() == (m.via_node_id == NodeId(1))));
+
+        let mut table = vec![];
+        edge_list.write_movements(&movements, &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert_eq!(table.lines().count(), 16);
+        assert!(table.contains(",10,11,4,1,5,-90.0,left,no_left_turn,20,true"), "{}", table);
+    }
+}